@@ -0,0 +1,276 @@
+//========================//
+// Backward bisimulation //
+//========================//
+
+// In backward bisimulation, states are equivalent if their incoming transitions match:
+// two states are in the same block if their own nodes have the same type and tag,
+// and for every block B, the transitions into them from states in B carry the same labels and weights.
+// Like the backrefs in Coalg, the incoming transitions are computed once with a counting sort,
+// but here we also keep the type of the source node and the value of the transition.
+// Weights of transitions from the same block are combined with the monoid operation of the source node
+// (sum for Add, Int and Real, product for Mul, maximum for Max, minimum for Min, bitwise or for Or), while the labels of Tag transitions and the
+// transitions of Set and List nodes are combined as sets.
+//
+// A state can also be an argument of a List, which is either the node of the source state, or an element of its Set,
+// Tag or monoid node, like the transitions f(q1,...,qn) -> q of a tree automaton, which are Add[o]{List[f]{@q1,...,@qn}:w,...}.
+// Such a transition into qi also has a context: the tag of the List, the position i, and the blocks of the other arguments.
+// Transitions are then only combined if they have the same context, which gives the forward bisimulation of
+// weighted tree automata of Högberg, Maletti and May.
+//
+// The partition is refined with a worklist as in optalg: when a block splits, only the states whose incoming
+// transitions come from a state of a new block, or have an argument in a new block in their context, are recomputed.
+
+use std::cmp::{max, min};
+use std::hash::{Hash, Hasher};
+
+use crate::binrep::{CReader, RealKeys, Node, read_nodes, LIST_TYP, SET_TYP, ADD_TYP, MAX_TYP, OR_TYP, TAG_TYP, REAL_TYP, INT_TYP, MIN_TYP, MUL_TYP, CONST_TYP};
+use crate::coalg::{add_op, int_op, mul_op};
+use crate::hmap::new_hasher;
+use crate::refpart::RefinablePartition;
+use crate::renumber;
+
+#[cfg(test)]
+use crate::io::read_boa_txt;
+
+/// Marks an incoming transition whose target is not in a List
+const NO_LIST: u32 = u32::MAX;
+
+/// An incoming transition: the source state, the type of the source node, the weight or label,
+/// and if the target is an argument of a List, the index of the List and the position of the target in it
+#[derive(Clone, Copy)]
+pub struct InEdge {
+    pub source: u32,
+    pub typ: u8,
+    pub v: u64,
+    pub list: u32,
+    pub pos: u32,
+}
+
+pub struct Predecessors {
+    pub init: Vec<u64>, // (typ, tag) of the node of each state
+    pub edges: Vec<InEdge>, // buffer of incoming transitions
+    pub edges_locs: Vec<u32>, // the incoming transitions of state i are edges[edges_locs[i]..edges_locs[i+1]]
+    pub lists: Vec<(u32, u32, u32)>, // (tag, start, end) of each List, whose arguments are args[start..end]
+    pub args: Vec<u32>,
+    pub deps: Vec<u32>, // buffer of the states whose signature depends on the block of a state
+    pub deps_locs: Vec<u32>, // the dependents of state i are deps[deps_locs[i]..deps_locs[i+1]]
+}
+
+/// Counting sort of the values by key, returning the buffer and the start of the values of each key
+fn group_by_key<T: Copy>(pairs: &[(u32, T)], num_keys: usize, empty: T) -> (Vec<T>, Vec<u32>) {
+    let mut locs = vec![0u32; num_keys + 1];
+    for &(key, _) in pairs { locs[key as usize + 1] += 1 }
+    for i in 0..num_keys { locs[i + 1] += locs[i] }
+    let mut next = locs.clone();
+    let mut buffer = vec![empty; pairs.len()];
+    for &(key, v) in pairs {
+        buffer[next[key as usize] as usize] = v;
+        next[key as usize] += 1;
+    }
+    return (buffer, locs);
+}
+
+impl Predecessors {
+    /// Collect the incoming transitions, or fail if a node has a shape that backward mode doesn't support
+    pub fn new(data: &[u8], r: &CReader) -> Result<Predecessors, String> {
+        let nodes = read_nodes(data, r);
+        let num_states = nodes.len();
+        let mut out: Vec<(u32, InEdge)> = vec![]; // (target, incoming transition)
+        let mut lists = vec![];
+        let mut args = vec![];
+        let mut init = vec![];
+        for (source, node) in nodes.iter().enumerate() {
+            let err = || format!("Backward mode doesn't support the node of state @{}: it requires constants, Lists of states, \
+                or Set, Tag or monoid nodes whose elements are states or Lists of states.", source);
+            let source = source as u32;
+            let target = |node: &Node| match node {
+                Node::State(state) if (*state as usize) < num_states => Ok(*state),
+                Node::State(state) => Err(format!("Reference to state @{} out of range.", state)),
+                _ => Err(err()),
+            };
+            let mut element = |node: &Node, typ: u8, v: u64, out: &mut Vec<(u32, InEdge)>| -> Result<(), String> {
+                match node {
+                    Node::Coll(LIST_TYP, tag, children) => {
+                        let list = lists.len() as u32;
+                        let start = args.len() as u32;
+                        for (pos, child) in children.iter().enumerate() {
+                            let t = target(child)?;
+                            args.push(t);
+                            out.push((t, InEdge { source, typ, v, list, pos: pos as u32 }));
+                        }
+                        lists.push((*tag, start, args.len() as u32));
+                    },
+                    _ => out.push((target(node)?, InEdge { source, typ, v, list: NO_LIST, pos: 0 })),
+                }
+                Ok(())
+            };
+            let (typ, tag) = match node {
+                Node::Coll(LIST_TYP, tag, _) => {
+                    element(node, LIST_TYP, 0, &mut out)?;
+                    (LIST_TYP as u64, *tag as u64)
+                },
+                Node::Coll(SET_TYP, tag, children) => {
+                    for c in children { element(c, SET_TYP, 0, &mut out)? }
+                    (SET_TYP as u64, *tag as u64)
+                },
+                Node::Mon(typ @ (ADD_TYP | MAX_TYP | OR_TYP | TAG_TYP | REAL_TYP | INT_TYP | MIN_TYP | MUL_TYP), tag, children) => {
+                    for (c, v) in children { element(c, *typ, *v, &mut out)? }
+                    (*typ as u64, *tag as u64)
+                },
+                Node::Const(val) => (CONST_TYP as u64, *val), // no successors
+                _ => return Err(err()),
+            };
+            let mut h = new_hasher();
+            (typ, tag).hash(&mut h);
+            init.push(h.finish());
+        }
+
+        // The signature of the target depends on the block of the source, and of the other arguments of its List
+        let mut dep_pairs: Vec<(u32, u32)> = vec![];
+        for &(target, edge) in &out {
+            dep_pairs.push((edge.source, target));
+            if edge.list != NO_LIST {
+                let (_, start, end) = lists[edge.list as usize];
+                for (pos, &arg) in args[start as usize..end as usize].iter().enumerate() {
+                    if pos as u32 != edge.pos { dep_pairs.push((arg, target)) }
+                }
+            }
+        }
+        let (deps, deps_locs) = group_by_key(&dep_pairs, num_states, 0);
+        let (edges, edges_locs) = group_by_key(&out, num_states, InEdge { source: 0, typ: 0, v: 0, list: NO_LIST, pos: 0 });
+        Ok(Predecessors { init, edges, edges_locs, lists, args, deps, deps_locs })
+    }
+
+    pub fn incoming(self: &Self, state: usize) -> &[InEdge] {
+        &self.edges[self.edges_locs[state] as usize..self.edges_locs[state + 1] as usize]
+    }
+
+    pub fn dependents(self: &Self, state: usize) -> &[u32] {
+        &self.deps[self.deps_locs[state] as usize..self.deps_locs[state + 1] as usize]
+    }
+
+    pub fn num_states(self: &Self) -> usize {
+        self.init.len()
+    }
+
+    /// Hash of the context of a transition into a List: its tag, the position, and the blocks of the other arguments
+    fn context(self: &Self, edge: &InEdge, ids: &[u32]) -> u64 {
+        if edge.list == NO_LIST { return 0 }
+        let (tag, start, end) = self.lists[edge.list as usize];
+        let mut hasher = new_hasher();
+        (tag, edge.pos).hash(&mut hasher);
+        for (pos, &arg) in self.args[start as usize..end as usize].iter().enumerate() {
+            if pos as u32 != edge.pos { ids[arg as usize].hash(&mut hasher) }
+        }
+        // 0 is reserved for transitions without a context
+        return hasher.finish() | 1;
+    }
+}
+
+/// Signature of a state: its own (typ, tag) and its incoming transitions combined per source block and context
+fn signature(preds: &Predecessors, ids: &[u32], state: usize, repr: &mut Vec<(u32, u8, u64, u64)>, reals: &RealKeys) -> u64 {
+    repr.clear();
+    for edge in preds.incoming(state) {
+        let block = ids[edge.source as usize];
+        let ctx = preds.context(edge, ids);
+        match edge.typ {
+            // labels are part of the key, so Tag transitions are combined as a set
+            SET_TYP | LIST_TYP => repr.push((block, edge.typ, ctx, 0)),
+            _ => repr.push((block, edge.typ, ctx, edge.v)),
+        }
+    }
+    repr.sort_unstable();
+    let mut hasher = new_hasher();
+    preds.init[state].hash(&mut hasher);
+    let mut i = 0;
+    while i < repr.len() {
+        let (block, typ, ctx, v) = repr[i];
+        let mut vtot = v;
+        i += 1;
+        while i < repr.len() && repr[i].0 == block && repr[i].1 == typ && repr[i].2 == ctx && (typ != TAG_TYP || repr[i].3 == v) {
+            vtot = match typ {
+                ADD_TYP => add_op(vtot, repr[i].3),
+                INT_TYP => int_op(vtot, repr[i].3),
+                MUL_TYP => mul_op(vtot, repr[i].3),
+                MAX_TYP => max(vtot, repr[i].3),
+                MIN_TYP => min(vtot, repr[i].3),
+                OR_TYP => vtot | repr[i].3,
+                REAL_TYP => (f64::from_bits(vtot) + f64::from_bits(repr[i].3)).to_bits(),
+                _ => vtot,
+            };
+            i += 1;
+        }
+        if typ == REAL_TYP {
+            (block, typ, ctx, reals.key(f64::from_bits(vtot))).hash(&mut hasher);
+        } else {
+            (block, typ, ctx, vtot).hash(&mut hasher);
+        }
+    }
+    return hasher.finish();
+}
+
+/// Refine the partition of the states by their incoming transitions until it is stable.
+/// Returns the partition as block numbers 0..n, and the number of iterations.
+pub fn refine_backward(preds: &Predecessors, reals: &RealKeys) -> (Vec<u32>, usize) {
+    let mut partition = RefinablePartition::new(preds.num_states() as u32);
+    let mut repr = vec![];
+    let mut iters = 0;
+    while let Some(block_id) = partition.worklist.pop_back() {
+        let states = partition.refiners(block_id);
+        let signatures: Vec<u64> = states.iter().map(|&state| signature(preds, &partition.state2block, state as usize, &mut repr, reals)).collect();
+        let new_blocks = partition.refine(block_id, &renumber(&signatures));
+        for block in new_blocks {
+            let (start, _, end) = partition.partition[block as usize];
+            let states = partition.buffer[start as usize..end as usize].to_vec();
+            for state in states {
+                for &dep in preds.dependents(state as usize) {
+                    partition.mark_dirty(dep);
+                }
+            }
+        }
+        iters += 1;
+    }
+    return (renumber(&partition.state2block), iters);
+}
+
+/// Compute the backward bisimulation partition, as block numbers 0..n
+pub fn partref_backward(data: &[u8], r: &CReader) -> Result<Vec<u32>, String> {
+    let preds = Predecessors::new(data, r)?;
+    return Ok(refine_backward(&preds, &r.reals).0);
+}
+
+#[test]
+fn test_partref_backward() {
+    // 0: Tag[0]{@2:0}
+    // 1: Tag[0]{@3:0}
+    // 2: Tag[0]{}
+    // 3: Tag[0]{}
+    // 4: Add[0]{@5:1,@5:1,@6:2,@7:1}
+    // 5: Add[0]{}
+    // 6: Add[0]{}
+    // 7: Add[0]{}
+    let (data,r) = read_boa_txt("tests/backward_test.boa.txt");
+    assert_eq!(partref_backward(&data, &r).unwrap(), vec![0,0,1,1,2,3,3,4]);
+}
+
+#[test]
+fn test_partref_backward_lists() {
+    use crate::binrep::{CWriter, Node};
+    // @1 is the first argument of two transitions of weight 1 from @0 and @3 whose other arguments are equivalent,
+    // while @2 is the first and the second argument of one transition each, and @4 only the second of one
+    let nodes = ["Add[0]{List[0]{@1,@2}:1,List[0]{@2,@1}:1}", "Add[1]{}", "Add[1]{}", "Add[0]{List[0]{@1,@4}:1}", "Add[1]{}", "List[1]{@5,@5}"];
+    let mut w = CWriter::new();
+    for node in nodes { Node::from_ascii(node.as_bytes()).write(&mut w) }
+    let (data, r) = w.finish();
+    assert_eq!(partref_backward(&data, &r).unwrap(), vec![0,1,2,0,3,4]);
+}
+
+#[test]
+fn test_partref_backward_unsupported() {
+    use crate::binrep::{CWriter, Node};
+    let mut w = CWriter::new();
+    Node::from_ascii("List[0]{List[0]{@0}}".as_bytes()).write(&mut w);
+    let (data, r) = w.finish();
+    let err = partref_backward(&data, &r).unwrap_err();
+    assert!(err.contains("state @0"), "{}", err);
+}
@@ -1,3 +1,6 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+
 use hmap::HMap;
 
 //=======================//
@@ -10,15 +13,24 @@ use hmap::HMap;
 // If the last two bits of the first byte are 11, it is a header.
 // A header's first byte is its typ (indicating whether it is a list/set/add/or/max node).
 // A header's second byte is its tag (just some additional data to distinguish states, e.g. different constructors of algebraic data type with the same length).
+// Tags that don't fit in a byte have the tag byte set to 255, and the actual tag follows the header as a value.
 // A header's third and fourth byte are the len of the collection.
 // For lists/sets, we then encode sequence of len states.
-// For add/or/max, we then encode a sequence of len (state,value).
+// For add/or/max/tag/real, we then encode a sequence of len (state,value).
+// The values of real nodes are the bits of an f64, so they share the value dictionary with the integer values.
+// For rational nodes, we encode a sequence of len (state,numerator,denominator), with the fraction in lowest terms.
+// A constant N is a header of typ CONST followed by the value N.
+// For user-defined node types (see nodetype.rs), each of the len elements is followed by arity() values.
 // Values are encoded as follows: if the last bit of the first byte is 0, then it is dictionary compressed (so we have a value dictionary with 128 entries).
 // If the last bit of the first byte is 1, then the remaining bits encode the 63 bit value.
+// The 63 bit value with all bits set is reserved as an escape: it is followed by 8 more bytes holding the full 64 bit value.
+// This way values at or above 2^63 - 1 (e.g. large weights, or zigzag encoded integers of large magnitude) are stored without loss.
 
 use datasize::DataSize;
 
 use crate::{hmap, parsing};
+use crate::parsing::Names;
+use crate::nodetype::{node_type, MAX_ARITY};
 
 /// Compression tag
 fn is_compressed32(w: u32) -> bool {
@@ -46,6 +58,8 @@ fn get_noncompressed64(w: u64) -> u64 {
 fn put_noncompressed64(w: u64) -> u64 {
     (w << 1) | 1
 }
+/// Non-compressed 63 bit value announcing that the full 64 bit value follows
+const ESCAPE64: u64 = u64::MAX >> 1;
 
 fn put_compressed8(w: u8) -> u8 {
     w << 1
@@ -76,12 +90,40 @@ pub fn encode_header(typ: u8, tag: u8, len: u16) -> u32 {
     ((typ as u32) << 24) | ((tag as u32) << 16) | (len as u32)
 }
 
+/// The number of elements of a node must fit in the 16 bits of the header
+pub fn checked_len(len: usize) -> u16 {
+    u16::try_from(len).unwrap_or_else(|_| panic!("Node has {} elements, at most {} are supported.", len, u16::MAX))
+}
+
 pub const LIST_TYP: u8 = 0;
 pub const SET_TYP: u8 = 1;
 pub const ADD_TYP: u8 = 2;
 pub const MAX_TYP: u8 = 3;
 pub const OR_TYP: u8 = 4;
 pub const TAG_TYP: u8 = 5;
+pub const REAL_TYP: u8 = 6; // values are the bits of a non-negative f64
+pub const RAT_TYP: u8 = 7; // each element is followed by two values, the numerator and denominator of a rational weight
+pub const INT_TYP: u8 = 8; // values are zigzag encoded i64
+pub const MIN_TYP: u8 = 9;
+pub const MUL_TYP: u8 = 10;
+pub const TAG_ESCAPE: u8 = 255; // tag byte of a header whose tag follows as a value
+pub const CONST_TYP: u8 = 11; // leaf constant: a header with tag 0 and len 0, followed by a single value
+pub const REF_TYP: u8 = 12; // reference to a shared node: a header with tag 0 and len 0, followed by the index of the shared node
+
+// Semantics of the monoids when combining the values of elements that end up in the same block:
+// Add: sum of u64, panics on overflow
+// Int: sum of i64, panics on overflow
+// Mul: product of u64, panics on overflow
+// Max/Min: maximum/minimum of u64
+// Or: bitwise or of u64
+
+/// Map i64 to u64 so that values of small magnitude stay small: 0,-1,1,-2,2,... map to 0,1,2,3,4,...
+pub fn zigzag(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
+pub fn unzigzag(v: u64) -> i64 {
+    ((v >> 1) as i64) ^ -((v & 1) as i64)
+}
 
 #[test]
 fn test_binary_representation() {
@@ -98,6 +140,10 @@ fn test_binary_representation() {
         )))),
         (127, u8::MAX, u16::MAX)
     );
+    for v in [0, 1, -1, 12345, -12345, i64::MAX, i64::MIN] {
+        assert_eq!(unzigzag(zigzag(v)), v);
+    }
+    assert_eq!(zigzag(-1), 1);
     assert_eq!(
         decode_header(get_header(get_noncompressed32(put_noncompressed32(
             put_header(encode_header(63, u8::MAX, u16::MAX))
@@ -110,15 +156,143 @@ fn test_binary_representation() {
 // Dictionary compressed readers & writers //
 //=========================================//
 
-#[derive(DataSize)]
+/// Symbolic names that are kept alongside a coalgebra. They are used by the text format, but don't affect the semantics.
+#[derive(DataSize, Clone, Default, PartialEq, Debug)]
+pub struct Meta {
+    pub tag_names: BTreeMap<u32, String>,
+    pub state_names: Vec<Option<String>>, // indexed by state, empty if no state has a name
+    pub label_names: BTreeMap<u64, String>, // names of the labels of Tag nodes, which are their values
+    pub initial: Option<u32>, // initial state if it isn't 0, which is kept by the binary format but not the text format
+    // The following are kept for probabilistic models that are imported from PRISM or Storm, so that they
+    // can be exported again. Like the initial state, the binary format keeps them but the text format doesn't.
+    pub model_type: Option<String>, // like DTMC, CTMC or MDP
+    pub reward_models: Vec<String>, // names of the reward models
+    pub observations: BTreeMap<u32, Observation>, // what the tags stand for
+}
+
+/// What a tag of an imported probabilistic model stands for. Rewards are given per reward model,
+/// and are empty if they are all 0.
+#[derive(DataSize, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Observation {
+    /// The labels and rewards of a state, and the rewards of its only action if it is a state of a DTMC or CTMC
+    State { labels: Vec<String>, rewards: Vec<String>, action_rewards: Vec<String> },
+    /// An action of an MDP and its rewards
+    Action { name: Option<String>, rewards: Vec<String> },
+}
+
+impl Meta {
+    pub fn is_empty(self: &Self) -> bool {
+        return self.tag_names.is_empty() && self.state_names.is_empty() && self.label_names.is_empty() && self.initial.is_none()
+            && self.model_type.is_none() && self.reward_models.is_empty() && self.observations.is_empty();
+    }
+
+    pub fn state_name(self: &Self, state: u32) -> Option<&str> {
+        return self.state_names.get(state as usize).and_then(|name| name.as_deref());
+    }
+
+    /// A reference to the state as in the text format, @name or @i
+    pub fn state_ref(self: &Self, state: u32) -> String {
+        let mut w = vec![b'@'];
+        match self.state_name(state) {
+            Some(name) => parsing::write_name(name, &mut w),
+            None => w.extend(lexical::to_string(state).as_bytes()),
+        }
+        return String::from_utf8(w).unwrap();
+    }
+
+    /// Find a state given as a number, a name, or a reference @name or @i
+    pub fn find_state(self: &Self, s: &str) -> Option<u32> {
+        let s = s.strip_prefix('@').unwrap_or(s);
+        if let Ok(state) = s.parse::<u32>() { return Some(state) }
+        return self.state_names.iter().position(|name| name.as_deref() == Some(s)).map(|state| state as u32);
+    }
+}
+
+/// Real weights are summed per block, and sums that are equal up to a relative error of REAL_EPSILON,
+/// like 0.1+0.2 and 0.3, get the same signature
+pub const REAL_EPSILON: f64 = 1e-9;
+
+/// Whether two real weights are equal up to a relative error of REAL_EPSILON
+pub fn real_eq(a: f64, b: f64) -> bool {
+    (a - b).abs() <= REAL_EPSILON * a.abs().max(b.abs())
+}
+
+/// Keys of the real weights seen so far, so that weights that are equal up to REAL_EPSILON get the same key.
+/// The key of a weight is the nearest earlier key within the tolerance, or the weight itself if there is none.
+/// Unlike rounding to multiples of a fixed epsilon, weights just below and above such a multiple get the same key,
+/// and large weights keep their relative precision.
+/// The keys are kept with the reader, so that signatures of different rounds of a refinement can be compared.
+#[derive(Clone, Default)]
+pub struct RealKeys {
+    keys: RefCell<BTreeSet<u64>>, // bits of non-negative f64s, which are ordered like the f64s
+}
+
+impl RealKeys {
+    pub fn key(self: &Self, v: f64) -> u64 {
+        let bits = (v + 0.0).to_bits();
+        let mut keys = self.keys.borrow_mut();
+        let below = keys.range(..=bits).next_back().copied();
+        let above = keys.range(bits..).next().copied();
+        let dist = |k: u64| (f64::from_bits(k) - v).abs();
+        let nearest = below.into_iter().chain(above)
+            .filter(|&k| real_eq(f64::from_bits(k), v))
+            .min_by(|&k1, &k2| dist(k1).total_cmp(&dist(k2)));
+        return match nearest {
+            Some(k) => k,
+            None => { keys.insert(bits); bits }
+        };
+    }
+}
+
+#[test]
+fn test_real_keys() {
+    let keys = RealKeys::default();
+    assert_eq!(keys.key(0.1 + 0.2), keys.key(0.3));
+    // large weights are neither saturated nor rounded together
+    assert_ne!(keys.key(1e10), keys.key(1e11));
+    assert_ne!(keys.key(1e19), keys.key(2e19));
+    assert_eq!(keys.key(1e19), keys.key(1e19 * (1.0 + 1e-12)));
+    // weights on both sides of what used to be a bucket boundary, 1.5e-9 with the old epsilon of 1e-9
+    let keys = RealKeys::default();
+    assert_eq!(keys.key(1.5e-9 + 1e-20), keys.key(1.5e-9 - 1e-20));
+    assert_eq!(keys.key(0.3 - 1e-12), keys.key(0.3 + 1e-12));
+    assert_ne!(keys.key(0.3), keys.key(0.3 + 1e-6));
+    assert_eq!(keys.key(0.0), keys.key(-0.0));
+}
+
+#[derive(DataSize, Clone)]
 pub struct CReader {
     pub headers: [u32; 128],
     pub values: [u64; 128],
+    pub meta: Meta,
+    pub shared: Vec<u8>, // shared nodes, which are stored once and referred to by REF_TYP nodes
+    pub shared_locs: Vec<usize>, // shared_locs[k] gives the index into shared where the k-th shared node starts
+    #[data_size(skip)]
+    pub reals: RealKeys,
 }
 
 impl CReader {
+    pub fn new() -> CReader {
+        CReader { headers: [0; 128], values: [0; 128], meta: Meta::default(), shared: vec![], shared_locs: vec![], reals: RealKeys::default() }
+    }
+
+    /// Location of the k-th shared node
+    pub fn shared_node(self: &Self, k: u64) -> *const u8 {
+        let loc = *self.shared_locs.get(k as usize).unwrap_or_else(|| panic!("Unknown shared node ${}.", k));
+        return self.shared[loc..].as_ptr();
+    }
+
+    /// Read the full tag of a header whose tag byte is tag, which may be followed by an escaped tag
+    pub unsafe fn read_tag_mut(self: &Self, tag: u8, data: &mut *const u8) -> u32 {
+        if tag == TAG_ESCAPE {
+            let tag = self.read_value_mut(data);
+            return u32::try_from(tag).expect("Escaped tag does not fit in 32 bits.");
+        }
+        return tag as u32;
+    }
+
     pub unsafe fn read_node(self: &Self, data: *const u8) -> (u32, *const u8) {
-        let x = *(data as *const u32);
+        let x = (data as *const u32).read_unaligned();
         if is_compressed32(x) {
             (self.headers[get_compressed32(x) as usize], data.add(1))
         } else {
@@ -127,11 +301,16 @@ impl CReader {
     }
 
     pub unsafe fn read_value(self: &Self, data: *const u8) -> (u64, *const u8) {
-        let x = *(data as *const u64);
+        let x = (data as *const u64).read_unaligned();
         if is_compressed64(x) {
             (self.values[get_compressed64(x) as usize], data.add(1))
         } else {
-            (get_noncompressed64(x), data.add(8))
+            let v = get_noncompressed64(x);
+            if v == ESCAPE64 {
+                ((data.add(8) as *const u64).read_unaligned(), data.add(16))
+            } else {
+                (v, data.add(8))
+            }
         }
     }
 
@@ -158,6 +337,9 @@ pub struct CWriter {
     pub headers: [u32; 128],
     pub values: [u64; 128],
     pub data: Vec<u8>,
+    pub meta: Meta,
+    pub shared: Vec<u8>,
+    pub shared_locs: Vec<usize>,
 }
 
 impl CWriter {
@@ -168,20 +350,43 @@ impl CWriter {
             headers: [0; 128],
             values: [0; 128],
             data: vec![],
+            meta: Meta::default(),
+            shared: vec![],
+            shared_locs: vec![],
         }
     }
 
     pub fn finish(mut self: Self) -> (Vec<u8>, CReader) {
         self.data.reserve(7); // make sure to not trigger undefined behaviour by reading u64 at the last byte
+        if !self.shared.is_empty() {
+            // padding rather than capacity, so that it survives cloning the reader
+            self.shared.extend([0; 7]);
+        }
         return (
             self.data,
             CReader {
                 headers: self.headers,
                 values: self.values,
+                meta: self.meta,
+                shared: self.shared,
+                shared_locs: self.shared_locs,
+                reals: RealKeys::default(),
             },
         );
     }
 
+    /// Write a shared node with f, which writes it with the same dictionaries as the states.
+    /// Returns the index of the shared node, which REF_TYP nodes refer to.
+    pub fn write_shared<F>(self: &mut Self, f: F) -> u32
+    where F: FnOnce(&mut CWriter) {
+        let k = u32::try_from(self.shared_locs.len()).expect("Too many shared nodes.");
+        std::mem::swap(&mut self.data, &mut self.shared);
+        self.shared_locs.push(self.data.len());
+        f(self);
+        std::mem::swap(&mut self.data, &mut self.shared);
+        return k;
+    }
+
     pub fn write_node(self: &mut Self, node: u32) {
         if self.headers_map.contains_key(&node) {
             self.data.push(self.headers_map[&node])
@@ -201,6 +406,16 @@ impl CWriter {
         }
     }
 
+    /// Write the header of a node, escaping the tag if it doesn't fit in a byte
+    pub fn write_header(self: &mut Self, typ: u8, tag: u32, len: u16) {
+        if tag < TAG_ESCAPE as u32 {
+            self.write_node(put_header(encode_header(typ, tag as u8, len)))
+        } else {
+            self.write_node(put_header(encode_header(typ, TAG_ESCAPE, len)));
+            self.write_value(tag as u64)
+        }
+    }
+
     pub fn write_node_noncompressed(self: &mut Self, node: u32) {
         self.data
             .extend(u32::to_ne_bytes(put_noncompressed32(node)))
@@ -215,6 +430,10 @@ impl CWriter {
                 self.values_map.insert(value, put_compressed8(i));
                 self.values[i as usize] = value;
                 self.data.push(put_compressed8(i));
+            } else if value >= ESCAPE64 {
+                self.data
+                    .extend(u64::to_ne_bytes(put_noncompressed64(ESCAPE64)));
+                self.data.extend(u64::to_ne_bytes(value))
             } else {
                 // panic!("Value dict full");
                 self.data
@@ -253,21 +472,64 @@ fn test_creader_cwriter() {
     }
 }
 
+#[test]
+#[should_panic(expected = "at most 65535")]
+fn test_too_many_elements() {
+    let node = Node::Coll(SET_TYP, 0, vec![Node::State(0); 1 << 16]);
+    node.write(&mut CWriter::new());
+}
+
+#[test]
+fn test_full_64bit_values() {
+    let mut w = CWriter::new();
+    // fill the dictionary, so that the following values are written non-compressed
+    for i in 0..128 {
+        w.write_value(i)
+    }
+    let big = [u64::MAX, u64::MAX - 1, 1 << 63, ESCAPE64, ESCAPE64 - 1, 12345];
+    for v in big {
+        w.write_value(v)
+    }
+    let (data, r) = w.finish();
+    assert_eq!(data.len(), 128 + 4 * 16 + 2 * 8);
+    let mut p = data.as_ptr();
+    unsafe {
+        for i in 0..128 {
+            assert_eq!(r.read_value_mut(&mut p), i)
+        }
+        for v in big {
+            assert_eq!(r.read_value_mut(&mut p), v)
+        }
+        assert!(CReader::is_at_end(&data, p));
+    }
+}
+
 //=================================//
 // Convert between text and binary //
 //=================================//
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Eq, Hash, PartialOrd, Ord, Debug, Clone)]
 pub enum Node {
     State(u32),
-    Coll(u8, u8, Vec<Node>),
-    Mon(u8, u8, Vec<(Node, u64)>),
+    Coll(u8, u32, Vec<Node>),
+    Mon(u8, u32, Vec<(Node, u64)>),
+    Rat(u32, Vec<(Node, u64, u64)>), // tag and (node, numerator, denominator)
+    Ext(u8, u32, Vec<(Node, Vec<u64>)>), // node of a user-defined type, each element with arity() values
+    Const(u64), // leaf constant, e.g. an output or observation
+    Shared(u32), // reference $k to the k-th shared node; map_states, map_tags and successors don't look into it
 }
 
 impl Node {
     pub fn from_ascii(inp: &[u8]) -> Self {
-        let (node, rest) = parsing::read_node(inp);
-        if rest.len() == 0 || rest == [b'\n'] {
+        let mut names = Names::new();
+        names.reserve_numeric(inp);
+        return Node::from_ascii_named(inp, &mut names);
+    }
+
+    /// Parse a node whose symbolic tags are looked up in, or added to, names
+    pub fn from_ascii_named(inp: &[u8], names: &mut Names) -> Self {
+        let (node, rest) = parsing::read_node(inp, names);
+        if parsing::skip_ws(rest).is_empty() {
             return node;
         } else {
             panic!("Did not parse everything on the line.")
@@ -275,47 +537,39 @@ impl Node {
     }
 
     pub fn to_ascii(self: &Self, w: &mut Vec<u8>) {
+        self.to_ascii_named(&Meta::default(), w)
+    }
+
+    /// Print the node, showing tags and states that have a name in meta by their name
+    pub fn to_ascii_named(self: &Self, meta: &Meta, w: &mut Vec<u8>) {
         match self {
             Node::State(state) => {
                 w.push(b'@');
-                w.extend(lexical::to_string(*state).as_bytes());
-            }
-            Node::Coll(typ, tag, nodes) => {
-                let typ_str = match *typ {
-                    LIST_TYP => "List[",
-                    SET_TYP => "Set[",
-                    _ => panic!("Bad typ."),
-                };
-                w.extend(typ_str.as_bytes());
-                w.extend(lexical::to_string(*tag).as_bytes());
-                w.extend([b']', b'{']);
-                for node in nodes {
-                    node.to_ascii(w);
-                    w.push(b',');
+                match meta.state_name(*state) {
+                    Some(name) => parsing::write_name(name, w),
+                    None => w.extend(lexical::to_string(*state).as_bytes()),
                 }
-                if nodes.len() > 0 {
-                    w.pop();
-                }
-                w.push(b'}');
             }
-            Node::Mon(typ, tag, nodes) => {
-                let typ_str = match *typ {
-                    ADD_TYP => "Add[",
-                    OR_TYP => "Or[",
-                    MAX_TYP => "Max[",
-                    TAG_TYP => "Tag[",
-                    _ => panic!("Bad typ."),
-                };
-                w.extend(typ_str.as_bytes());
-                w.extend(lexical::to_string(*tag).as_bytes());
-                w.extend([b']', b'{']);
-                for (node, val) in nodes {
-                    node.to_ascii(w);
-                    w.push(b':');
-                    w.extend(lexical::to_string(*val).as_bytes());
+            Node::Const(val) => w.extend(lexical::to_string(*val).as_bytes()),
+            Node::Shared(k) => {
+                w.push(b'$');
+                w.extend(lexical::to_string(*k).as_bytes());
+            }
+            _ => {
+                let (typ, tag, len) = self.header();
+                let t = node_type(typ).expect("Unknown typ.");
+                w.extend(t.name().as_bytes());
+                write_tag(tag, meta, w);
+                w.push(b'{');
+                self.for_each_elem(|node, values| {
+                    node.to_ascii_named(meta, w);
+                    if t.arity() > 0 {
+                        w.push(b':');
+                        t.print_values(values, meta, w);
+                    }
                     w.push(b',');
-                }
-                if nodes.len() > 0 {
+                });
+                if len > 0 {
                     w.pop();
                 }
                 w.push(b'}');
@@ -326,51 +580,209 @@ impl Node {
     pub fn write(self: &Self, w: &mut CWriter) {
         match self {
             Node::State(state) => w.write_node_noncompressed(put_state(*state)),
-            Node::Coll(typ, tag, nodes) => {
-                w.write_node(put_header(encode_header(*typ, *tag, nodes.len() as u16)));
-                for node in nodes {
-                    node.write(w)
-                }
+            Node::Const(val) => {
+                w.write_node(put_header(encode_header(CONST_TYP, 0, 0)));
+                w.write_value(*val)
             }
-            Node::Mon(typ, tag, nodes) => {
-                w.write_node(put_header(encode_header(*typ, *tag, nodes.len() as u16)));
-                for (node, val) in nodes {
+            Node::Shared(k) => {
+                w.write_node(put_header(encode_header(REF_TYP, 0, 0)));
+                w.write_value(*k as u64)
+            }
+            _ => {
+                let (typ, tag, len) = self.header();
+                let t = node_type(typ).expect("Unknown typ.");
+                w.write_header(typ, tag, checked_len(len));
+                self.for_each_elem(|node, values| {
                     node.write(w);
-                    w.write_value(*val)
+                    t.encode_values(values, w)
+                });
+            }
+        }
+    }
+
+    /// The type code, tag and number of elements of a node that is not a state, constant or shared node
+    pub fn header(self: &Self) -> (u8, u32, usize) {
+        match self {
+            Node::Coll(typ, tag, nodes) => (*typ, *tag, nodes.len()),
+            Node::Mon(typ, tag, nodes) => (*typ, *tag, nodes.len()),
+            Node::Rat(tag, nodes) => (RAT_TYP, *tag, nodes.len()),
+            Node::Ext(typ, tag, nodes) => (*typ, *tag, nodes.len()),
+            Node::State(_) | Node::Const(_) | Node::Shared(_) => panic!("Node has no header."),
+        }
+    }
+
+    /// Call f on each element of the node and its values, as they are written in the text and binary formats
+    pub fn for_each_elem<'a, F>(self: &'a Self, mut f: F)
+    where F: FnMut(&'a Node, &[u64]) {
+        match self {
+            Node::State(_) | Node::Const(_) | Node::Shared(_) => {}
+            Node::Coll(_, _, nodes) => {
+                for node in nodes { f(node, &[]) }
+            }
+            Node::Mon(_, _, nodes) => {
+                for (node, val) in nodes { f(node, &[*val]) }
+            }
+            Node::Rat(_, nodes) => {
+                for (node, num, den) in nodes { f(node, &[*num, *den]) }
+            }
+            Node::Ext(_, _, nodes) => {
+                for (node, values) in nodes { f(node, values) }
+            }
+        }
+    }
+
+    /// Replace every state reference @i in the node by @f(i)
+    pub fn map_states<F>(self: &mut Self, f: &F)
+    where F: Fn(u32) -> u32 {
+        match self {
+            Node::State(state) => *state = f(*state),
+            Node::Const(_) | Node::Shared(_) => {}
+            Node::Coll(_, _, nodes) => {
+                for node in nodes { node.map_states(f) }
+            }
+            Node::Mon(_, _, nodes) => {
+                for (node, _) in nodes { node.map_states(f) }
+            }
+            Node::Rat(_, nodes) => {
+                for (node, _, _) in nodes { node.map_states(f) }
+            }
+            Node::Ext(_, _, nodes) => {
+                for (node, _) in nodes { node.map_states(f) }
+            }
+        }
+    }
+
+    /// Replace every tag t in the node by f(t)
+    pub fn map_tags<F>(self: &mut Self, f: &mut F)
+    where F: FnMut(u32) -> u32 {
+        match self {
+            Node::State(_) | Node::Const(_) | Node::Shared(_) => {}
+            Node::Coll(_, tag, nodes) => {
+                *tag = f(*tag);
+                for node in nodes { node.map_tags(f) }
+            }
+            Node::Mon(_, tag, nodes) => {
+                *tag = f(*tag);
+                for (node, _) in nodes { node.map_tags(f) }
+            }
+            Node::Rat(tag, nodes) => {
+                *tag = f(*tag);
+                for (node, _, _) in nodes { node.map_tags(f) }
+            }
+            Node::Ext(_, tag, nodes) => {
+                *tag = f(*tag);
+                for (node, _) in nodes { node.map_tags(f) }
+            }
+        }
+    }
+
+    /// Replace every label l of a Tag node in the node by f(l)
+    pub fn map_labels<F>(self: &mut Self, f: &mut F)
+    where F: FnMut(u64) -> u64 {
+        match self {
+            Node::State(_) | Node::Const(_) | Node::Shared(_) => {}
+            Node::Coll(_, _, nodes) => {
+                for node in nodes { node.map_labels(f) }
+            }
+            Node::Mon(typ, _, nodes) => {
+                for (node, val) in nodes {
+                    node.map_labels(f);
+                    if *typ == TAG_TYP { *val = f(*val) }
                 }
             }
+            Node::Rat(_, nodes) => {
+                for (node, _, _) in nodes { node.map_labels(f) }
+            }
+            Node::Ext(_, _, nodes) => {
+                for (node, _) in nodes { node.map_labels(f) }
+            }
+        }
+    }
+
+    /// Collect the state references in the node, in the order in which they occur
+    pub fn successors(self: &Self, out: &mut Vec<u32>) {
+        match self {
+            Node::State(state) => out.push(*state),
+            Node::Const(_) | Node::Shared(_) => {}
+            Node::Coll(_, _, nodes) => {
+                for node in nodes { node.successors(out) }
+            }
+            Node::Mon(_, _, nodes) => {
+                for (node, _) in nodes { node.successors(out) }
+            }
+            Node::Rat(_, nodes) => {
+                for (node, _, _) in nodes { node.successors(out) }
+            }
+            Node::Ext(_, _, nodes) => {
+                for (node, _) in nodes { node.successors(out) }
+            }
         }
     }
 
+    /// Read a node, replacing references to shared nodes by the shared nodes themselves
     pub unsafe fn read(r: &CReader, p: &mut *const u8) -> Self {
+        Node::read_with(r, p, true)
+    }
+
+    /// Read a node, keeping references to shared nodes as Node::Shared
+    pub unsafe fn read_raw(r: &CReader, p: &mut *const u8) -> Self {
+        Node::read_with(r, p, false)
+    }
+
+    unsafe fn read_with(r: &CReader, p: &mut *const u8, inline: bool) -> Self {
         let w = r.read_node_mut(p);
         if is_state(w) {
             Node::State(get_state(w))
         } else {
             let (typ, tag, len) = decode_header(get_header(w));
+            let tag = r.read_tag_mut(tag, p);
             match typ {
-                LIST_TYP | SET_TYP => {
-                    let nodes = (0..len).map(|_| Node::read(r, p)).collect();
-                    Node::Coll(typ, tag, nodes)
-                }
-                ADD_TYP | OR_TYP | MAX_TYP | TAG_TYP => {
-                    let nodes = (0..len)
-                        .map(|_| {
-                            let node = Node::read(r, p);
-                            let val = r.read_value_mut(p);
-                            (node, val)
-                        })
-                        .collect();
-                    Node::Mon(typ, tag, nodes)
+                CONST_TYP => Node::Const(r.read_value_mut(p)),
+                REF_TYP => {
+                    let k = r.read_value_mut(p);
+                    if inline {
+                        Node::read_with(r, &mut r.shared_node(k), true)
+                    } else {
+                        Node::Shared(u32::try_from(k).expect("Shared node index does not fit in 32 bits."))
+                    }
                 }
                 _ => {
-                    panic!("Unknown typ.")
+                    let t = node_type(typ).expect("Unknown typ.");
+                    let mut elems = (0..len).map(|_| {
+                        let node = Node::read_with(r, p, inline);
+                        let mut values = [0; MAX_ARITY];
+                        t.decode_values(r, p, &mut values[..t.arity()]);
+                        (node, values)
+                    });
+                    t.make_node(typ, tag, &mut elems)
                 }
             }
         }
     }
 }
 
+/// Write a tag in brackets, by name if it has one
+pub fn write_tag(tag: u32, meta: &Meta, w: &mut Vec<u8>) {
+    w.push(b'[');
+    match meta.tag_names.get(&tag) {
+        Some(name) => parsing::write_name(name, w),
+        None => w.extend(lexical::to_string(tag).as_bytes()),
+    }
+    w.push(b']');
+}
+
+/// Decode all states of a coalgebra into nodes
+pub fn read_nodes(data: &[u8], r: &CReader) -> Vec<Node> {
+    let mut nodes = vec![];
+    unsafe {
+        let mut p = data.as_ptr();
+        while !CReader::is_at_end(data, p) {
+            nodes.push(Node::read(r, &mut p));
+        }
+    }
+    return nodes;
+}
+
 #[test]
 fn test_node_read_write() {
     // Test conversion from & to ascii
@@ -389,4 +801,103 @@ fn test_node_read_write() {
         let node2 = Node::read(&r, &mut data.as_ptr());
         assert_eq!(node, node2);
     }
+
+    // Test real weights
+    let node_str = "Real[3]{@1:0.25,List[0]{@2}:0.75,@3:1.0}";
+    let node = Node::from_ascii(node_str.as_bytes());
+    let mut out = vec![];
+    node.to_ascii(&mut out);
+    assert_eq!(String::from_utf8(out).unwrap(), node_str);
+    let mut w = CWriter::new();
+    node.write(&mut w);
+    let (data, r) = w.finish();
+    unsafe {
+        assert_eq!(node, Node::read(&r, &mut data.as_ptr()));
+    }
+
+    // Test rational weights
+    let node_str = "Q[3]{@1:1/3,List[0]{@2}:2/3,@3:1}";
+    let node = Node::from_ascii(node_str.as_bytes());
+    let mut out = vec![];
+    node.to_ascii(&mut out);
+    assert_eq!(String::from_utf8(out).unwrap(), node_str);
+    let mut w = CWriter::new();
+    node.write(&mut w);
+    let (data, r) = w.finish();
+    unsafe {
+        assert_eq!(node, Node::read(&r, &mut data.as_ptr()));
+    }
+
+    // Test signed, min and mul monoids
+    let node_str = "List[0]{Int[1]{@1:-5,@2:3},Min[2]{@1:5},Mul[3]{@3:7,@4:0}}";
+    let node = Node::from_ascii(node_str.as_bytes());
+    let mut out = vec![];
+    node.to_ascii(&mut out);
+    assert_eq!(String::from_utf8(out).unwrap(), node_str);
+    let mut w = CWriter::new();
+    node.write(&mut w);
+    let (data, r) = w.finish();
+    unsafe {
+        assert_eq!(node, Node::read(&r, &mut data.as_ptr()));
+    }
+
+    // Test constants
+    let node_str = "Tag[0]{List[1]{@1,7}:2,0:3,18446744073709551615:4}";
+    let node = Node::from_ascii(node_str.as_bytes());
+    let mut out = vec![];
+    node.to_ascii(&mut out);
+    assert_eq!(String::from_utf8(out).unwrap(), node_str);
+    let mut w = CWriter::new();
+    node.write(&mut w);
+    Node::from_ascii("42".as_bytes()).write(&mut w);
+    let (data, r) = w.finish();
+    assert_eq!(read_nodes(&data, &r), vec![node, Node::Const(42)]);
+
+    // Test tags that don't fit in a byte
+    let node_str = "List[255]{Add[70000]{@1:2},Set[254]{},Q[4294967295]{@0:1}}";
+    let node = Node::from_ascii(node_str.as_bytes());
+    let mut w = CWriter::new();
+    node.write(&mut w);
+    let (data, r) = w.finish();
+    assert_eq!(read_nodes(&data, &r), vec![node]);
+
+    // Test values that need all 64 bits
+    let node_str = "List[0]{Add[1]{@1:18446744073709551615,@2:9223372036854775808},Int[2]{@3:-9223372036854775808,@4:9223372036854775807}}";
+    let node = Node::from_ascii(node_str.as_bytes());
+    let mut w = CWriter::new();
+    for i in 0..128 {
+        w.write_value(i) // force non-compressed values
+    }
+    node.write(&mut w);
+    let (data, r) = w.finish();
+    unsafe {
+        let mut p = data.as_ptr();
+        for _ in 0..128 {
+            r.read_value_mut(&mut p);
+        }
+        let node2 = Node::read(&r, &mut p);
+        let mut out = vec![];
+        node2.to_ascii(&mut out);
+        assert_eq!(String::from_utf8(out).unwrap(), node_str);
+    }
+
+    // Test shared nodes, which are inlined when reading unless read_raw is used
+    let mut w = CWriter::new();
+    let shared = Node::from_ascii("Set[1]{@0,@2}".as_bytes());
+    assert_eq!(w.write_shared(|w| shared.write(w)), 0);
+    let node = Node::Coll(LIST_TYP, 0, vec![Node::Shared(0), Node::State(1), Node::Shared(0)]);
+    node.write(&mut w);
+    let (data, r) = w.finish();
+    unsafe {
+        assert_eq!(Node::read_raw(&r, &mut data.as_ptr()), node);
+        assert_eq!(Node::read(&r, &mut data.as_ptr()), Node::from_ascii("List[0]{Set[1]{@0,@2},@1,Set[1]{@0,@2}}".as_bytes()));
+    }
+    let mut out = vec![];
+    node.to_ascii(&mut out);
+    assert_eq!(String::from_utf8(out).unwrap(), "List[0]{$0,@1,$0}");
+
+    // Test shifting of state references
+    let mut node3 = Node::from_ascii("List[1]{@0,Add[2]{@3:4}}".as_bytes());
+    node3.map_states(&|s| s + 10);
+    assert_eq!(node3, Node::from_ascii("List[1]{@10,Add[2]{@13:4}}".as_bytes()));
 }
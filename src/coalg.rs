@@ -2,20 +2,19 @@
 // Partition refinement //
 //======================//
 
-use std::cmp::max;
 use std::hash::Hasher;
 use std::hash::Hash;
 use datasize::DataSize;
-use itertools::Itertools;
-use crate::hmap::new_hasher;
+use crate::hmap::{new_hasher, HMap};
+use crate::nodetype::{node_type, Elem, MAX_ARITY};
 
-use crate::{binrep::{self, CReader, get_state, is_state, decode_header, get_header, LIST_TYP, ADD_TYP, SET_TYP, MAX_TYP, OR_TYP, TAG_TYP}};
+use crate::{binrep::{self, CReader, Node, get_state, is_state, decode_header, get_header, CONST_TYP, REF_TYP, zigzag, unzigzag}};
 
 
 #[cfg(test)]
 use crate::io;
 
-fn ptrvec_datasize(v: &Vec<*const u8>) -> usize { v.len() * 8 }
+fn ptrvec_datasize(v: &[*const u8]) -> usize { v.len() * 8 }
 
 #[derive(DataSize)]
 pub struct Coalg {
@@ -32,26 +31,25 @@ impl Coalg {
     pub fn new(data: Vec<u8>, r: CReader) -> Coalg {
         // Iterate over one state starting at data[loc], calling f(i) on each state ref @i in the state.
         unsafe fn iter<F>(p: &mut *const u8, r: &CReader, f : &mut F)
-        where F : FnMut(u32) -> () {
+        where F : FnMut(u32) {
             let w = r.read_node_mut( p);
             if is_state(w) {
                 f(get_state(w));
             } else {
-                let (typ,_tag,len) = decode_header(get_header(w));
+                let (typ,tag,len) = decode_header(get_header(w));
+                r.read_tag_mut(tag, p);
                 match typ {
-                    LIST_TYP|SET_TYP => {
-                        for _ in 0..len {
-                            iter(p,r,f)
-                        }
+                    CONST_TYP => {
+                        r.read_value_mut( p);
                     },
-                    ADD_TYP|MAX_TYP|OR_TYP|TAG_TYP => {
-                        for _ in 0..len {
-                            iter(p,r,f);
-                            r.read_value_mut( p);
-                        }
+                    REF_TYP => {
+                        // the states referred to by the shared node are successors of every node that refers to it
+                        let k = r.read_value_mut( p);
+                        iter(&mut r.shared_node(k), r, f);
                     },
                     _ => {
-                        panic!("Unknown typ.")
+                        let t = node_type(typ).expect("Unknown typ.");
+                        t.for_each_successor(r, p, len, &mut |p| iter(p,r,f));
                     }
                 }
             }
@@ -148,190 +146,133 @@ fn test_new_coalg() {
 
 type ID = u32; // represents canonical ID of a state or sub-node of a state, refers to a partition number
 
-fn hash_with_op<A,F,H>(repr: &mut [(A,u64)], hasher: &mut H, op: F)
-where F : Fn(u64,u64) -> u64, A:Ord+Copy+Hash, H:Hasher {
-    repr.sort_by_key(|kv| kv.0);
-    let mut i = 0;
-    while i < repr.len() {
-        let (x,v) = repr[i];
-        let mut vtot = v;
-        i += 1;
-        while i < repr.len() {
-            let (x2,v2) = repr[i];
-            if x == x2 {
-                i += 1;
-                vtot = op(vtot,v2);
-            } else { break }
-        }
-        (x,vtot).hash(hasher);
-    }
+/// The signatures of the shared nodes that have been computed so far in a round, indexed by shared node,
+/// and the elements of the nodes that are being canonicalized, which are kept as a stack to avoid an allocation per node
+#[derive(Default)]
+struct Scratch {
+    shared: HMap<u64, u64>,
+    elems: Vec<Elem>,
+}
+
+pub fn add_op(a: u64, b: u64) -> u64 {
+    a.checked_add(b).expect("Overflow while summing the values of an Add node.")
+}
+
+pub fn int_op(a: u64, b: u64) -> u64 {
+    zigzag(unzigzag(a).checked_add(unzigzag(b)).expect("Overflow while summing the values of an Int node."))
+}
+
+pub fn mul_op(a: u64, b: u64) -> u64 {
+    a.checked_mul(b).expect("Overflow while multiplying the values of a Mul node.")
 }
 
-unsafe fn canonicalize_node_unsafe<'a>(mut p : *const u8, r: &CReader, ids: &[ID], w: u32) -> (u64, *const u8) {
+/// Compute the signature of the node with header w whose elements start at p.
+/// A state reference @i in the node gets signature leaf(i).
+/// A reference to a shared node gets the signature of the shared node, which is computed once and kept in scratch.
+/// Returns the signature and a pointer to the end of the node.
+unsafe fn canonicalize_node_with<F>(mut p : *const u8, r: &CReader, w: u32, leaf: &F, scratch: &mut Scratch) -> (u64, *const u8)
+where F : Fn(u32) -> u64 {
     let (typ,tag,len) = decode_header(w);
+    let tag = r.read_tag_mut(tag, &mut p);
     let mut hasher = new_hasher();
     (typ,tag).hash(&mut hasher);
     match typ {
-        LIST_TYP => {
-            for _ in 0..len {
-                let (sig, rest) = canonicalize_unsafe(p, r, ids);
-                sig.hash(&mut hasher);
-                p = rest;
-            }
+        CONST_TYP => {
+            let (v,p2) = r.read_value(p);
+            p = p2;
+            v.hash(&mut hasher);
         },
-        SET_TYP => {
-            let mut repr: Vec<u64> = (0..len).map(|_| {
-                let (sig, rest) = canonicalize_unsafe(p, r, ids);
-                p = rest; sig
-            }).collect();
-            repr.sort_unstable();
-            for &sig in repr.iter().dedup() { sig.hash(&mut hasher); }
+        REF_TYP => {
+            // the reference has the signature of the shared node itself, so sharing doesn't change the partition
+            let k = r.read_value_mut(&mut p);
+            if let Some(&sig) = scratch.shared.get(&k) { return (sig, p) }
+            let (sig, _) = canonicalize_with(r.shared_node(k), r, leaf, scratch);
+            scratch.shared.insert(k, sig);
+            return (sig, p);
         },
-        ADD_TYP|MAX_TYP|OR_TYP => {
-            let mut repr: Vec<(u64,u64)> = (0..len).map(|_| {
-                let (sig, p2) = canonicalize_unsafe(p, r, ids);
-                let (w,p3) = r.read_value(p2);
-                p = p3;
-                (sig,w)
-            }).collect();
-            match typ {
-                ADD_TYP => hash_with_op(&mut repr, &mut hasher, |a,b| a+b),
-                OR_TYP => hash_with_op(&mut repr, &mut hasher, |a,b| a|b),
-                MAX_TYP => hash_with_op(&mut repr, &mut hasher, |a,b| max(a,b)),
-                _ => panic!("Unreachable")
+        _ => {
+            let t = node_type(typ).expect("Unknown typ.");
+            let arity = t.arity();
+            // the elements of sub-nodes are pushed after these and popped again before they are returned
+            let start = scratch.elems.len();
+            for _ in 0..len {
+                let (sig, mut p2) = canonicalize_with(p, r, leaf, scratch);
+                let mut values = [0; MAX_ARITY];
+                t.decode_values(r, &mut p2, &mut values[..arity]);
+                p = p2;
+                scratch.elems.push(Elem { sig, values });
             }
-        },
-        TAG_TYP => {
-            let mut repr : Vec<u64> = (0..len).map(|_| {
-                let (sig, p2) = canonicalize_unsafe(p, r, ids);
-                let (w,p3) = r.read_value(p2);
-                p = p3;
-                // (sig,w)
-                let mut h = new_hasher();
-                sig.hash(&mut h);
-                w.hash(&mut h);
-                h.finish()
-            }).collect();
-            repr.sort_unstable();
-            for &sig in repr.iter().dedup() { sig.hash(&mut hasher); }
-        },
-        _ => panic!("Unknown typ.")
+            t.canonicalize(tag, &mut scratch.elems[start..], r, &mut hasher);
+            scratch.elems.truncate(start);
+        }
     }
     return (hasher.finish(), p);
 }
 
-unsafe fn canonicalize_unsafe<'a>(p : *const u8, r: &CReader, ids: &[ID]) -> (u64, *const u8) {
+unsafe fn canonicalize_with<F>(p : *const u8, r: &CReader, leaf: &F, scratch: &mut Scratch) -> (u64, *const u8)
+where F : Fn(u32) -> u64 {
     let (w,p) = r.read_node(p);
     if is_state(w) {
-        return (ids[get_state(w) as usize] as u64, p);
+        return (leaf(get_state(w)), p);
     } else {
-        return canonicalize_node_unsafe(p, r, ids, get_header(w));
+        return canonicalize_node_with(p, r, get_header(w), leaf, scratch);
+    }
+}
+
+unsafe fn canonicalize_unsafe<'a>(p : *const u8, r: &CReader, ids: &[ID], scratch: &mut Scratch) -> (u64, *const u8) {
+    canonicalize_with(p, r, &|state| ids[state as usize] as u64, scratch)
+}
+
+/// Compute the signature of a node that has been read with Node::read, like canonicalize_with does for its binary form.
+/// The signatures are consistent with each other, but not with those of canonicalize_with.
+pub fn node_signature<F>(node: &Node, r: &CReader, leaf: &F) -> u64
+where F : Fn(u32) -> u64 {
+    let mut hasher = new_hasher();
+    match node {
+        Node::State(state) => return leaf(*state),
+        Node::Const(v) => (CONST_TYP, 0u32, *v).hash(&mut hasher),
+        Node::Shared(_) => panic!("Shared nodes must be inlined."),
+        _ => {
+            let (typ, tag, _) = node.header();
+            let t = node_type(typ).expect("Unknown typ.");
+            (typ, tag).hash(&mut hasher);
+            let mut elems = vec![];
+            node.for_each_elem(|elem, values| {
+                let mut vals = [0; MAX_ARITY];
+                vals[..values.len()].copy_from_slice(values);
+                elems.push(Elem { sig: node_signature(elem, r, leaf), values: vals });
+            });
+            t.canonicalize(tag, &mut elems, r, &mut hasher);
+        }
     }
+    return hasher.finish();
 }
 
+/// Compute the signatures of the given states. Shared nodes are canonicalized at most once per call.
 pub fn repartition_unsafe(coa : &Coalg, states: &[u32], ids: &[ID]) -> Vec<u64> {
-    let mut sigs = vec![];
-    sigs.reserve(states.len());
+    let mut sigs = Vec::with_capacity(states.len());
+    let mut scratch = Scratch::default();
     for &state in states {
         let p = coa.locs[state as usize];
         unsafe {
-            let (sig,_rest) = canonicalize_unsafe(p, &coa.reader, ids);
+            let (sig,_rest) = canonicalize_unsafe(p, &coa.reader, ids, &mut scratch);
             sigs.push(sig);
         }
     }
     return sigs
 }
 
-unsafe fn canonicalize_node_unsafe64<'a>(mut p : *const u8, r: &CReader, ids: &[u64], w: u32) -> (u64, *const u8) {
-    let (typ,tag,len) = decode_header(w);
-    let mut hasher = new_hasher();
-    (typ,tag).hash(&mut hasher);
-    match typ {
-        LIST_TYP => {
-            for _ in 0..len {
-                let (sig, rest) = canonicalize_unsafe64(p, r, ids);
-                sig.hash(&mut hasher);
-                p = rest;
-            }
-        },
-        SET_TYP => {
-            let mut repr: Vec<u64> = (0..len).map(|_| {
-                let (sig, rest) = canonicalize_unsafe64(p, r, ids);
-                p = rest; sig
-            }).collect();
-            repr.sort_unstable();
-            for &sig in repr.iter().dedup() { sig.hash(&mut hasher); }
-        },
-        ADD_TYP|MAX_TYP|OR_TYP => {
-            let mut repr: Vec<(u64,u64)> = (0..len).map(|_| {
-                let (sig, p2) = canonicalize_unsafe64(p, r, ids);
-                let (w,p3) = r.read_value(p2);
-                p = p3;
-                (sig,w)
-            }).collect();
-            match typ {
-                ADD_TYP => hash_with_op(&mut repr, &mut hasher, |a,b| a+b),
-                OR_TYP => hash_with_op(&mut repr, &mut hasher, |a,b| a|b),
-                MAX_TYP => hash_with_op(&mut repr, &mut hasher, |a,b| max(a,b)),
-                _ => panic!("Unreachable")
-            }
-        },
-        TAG_TYP => {
-            let mut repr: Vec<(u64,u64)> = (0..len).map(|_| {
-                let (sig, p2) = canonicalize_unsafe64(p, r, ids);
-
-                // let x = *(p as *const u32);
-                // let w = get_noncompressed32(x);
-                // let p2 = p.add(4);
-                // // let (w,p2) = r.read_node(p);
-                // let sig = ids[get_state(w) as usize] as u64;
-
-                // unsafe fn read_node(self: &Self, data: *const u8) -> (u32, *const u8) {
-                //     let x = *(data as *const u32);
-                //     if is_compressed32(x) { (self.headers[get_compressed32(x) as usize], data.add(1)) }
-                //     else { (get_noncompressed32(x), data.add(4)) }
-                // }
-                // unsafe fn canonicalize_unsafe64<'a>(p : *const u8, r: &CReader, ids: &[u64]) -> (u64, *const u8) {
-                //     let (w,p) = r.read_node(p);
-                //     if is_state(w) {
-                //         return (ids[get_state(w) as usize] as u64, p);
-                //     } else {
-                //         return canonicalize_node_unsafe64(p, r, ids, get_header(w));
-                //     }
-                // }
-
-                let (w,p3) = r.read_value(p2);
-                p = p3;
-                (sig,w)
-                // let mut h = new_hasher();
-                // sig.hash(&mut h);
-                // w.hash(&mut h);
-                // h.finish()
-            }).collect();
-            repr.sort_unstable();
-            for &sig in repr.iter().dedup() { sig.hash(&mut hasher); }
-        },
-        _ => panic!("Unknown typ.")
-    }
-    return (hasher.finish(), p);
-}
-
 pub unsafe fn canonicalize_unsafe64<'a>(p : *const u8, r: &CReader, ids: &[u64]) -> (u64, *const u8) {
-    let (w,p) = r.read_node(p);
-    if is_state(w) {
-        return (ids[get_state(w) as usize] as u64, p);
-    } else {
-        return canonicalize_node_unsafe64(p, r, ids, get_header(w));
-    }
+    canonicalize_with(p, r, &|state| ids[state as usize], &mut Scratch::default())
 }
 
 pub fn repartition_unsafe64(coa : &Coalg, states: &[u32], ids: &[u64]) -> Vec<u64> {
-    let mut sigs = vec![];
-    sigs.reserve(states.len());
+    let mut sigs = Vec::with_capacity(states.len());
+    let mut scratch = Scratch::default();
     for &state in states {
         let p = coa.locs[state as usize];
         unsafe {
-            let (sig,_rest) = canonicalize_unsafe64(p, &coa.reader, ids);
+            let (sig,_rest) = canonicalize_with(p, &coa.reader, &|state| ids[state as usize], &mut scratch);
             sigs.push(sig);
         }
     }
@@ -340,11 +281,11 @@ pub fn repartition_unsafe64(coa : &Coalg, states: &[u32], ids: &[u64]) -> Vec<u6
 
 pub fn repartition_all_unsafe64(data: &[u8], r: &CReader, ids: &[u64]) -> Vec<u64> {
     unsafe {
-        let mut new_ids_raw = vec![];
-        new_ids_raw.reserve(ids.len());
+        let mut new_ids_raw = Vec::with_capacity(ids.len());
+        let mut scratch = Scratch::default();
         let mut p = data.as_ptr();
         while !CReader::is_at_end(data, p) {
-            let (sig, p_next) = canonicalize_unsafe64(p, r, ids);
+            let (sig, p_next) = canonicalize_with(p, r, &|state| ids[state as usize], &mut scratch);
             new_ids_raw.push(sig);
             p = p_next;
         }
@@ -352,73 +293,21 @@ pub fn repartition_all_unsafe64(data: &[u8], r: &CReader, ids: &[u64]) -> Vec<u6
     }
 }
 
-pub unsafe fn canonicalize_node_unsafe_init<'a>(mut p : *const u8, r: &CReader, w: u32) -> (u64, *const u8) {
-    let (typ,tag,len) = decode_header(w);
-    let mut hasher = new_hasher();
-    (typ,tag).hash(&mut hasher);
-    match typ {
-        LIST_TYP => {
-            for _ in 0..len {
-                let (sig, p2) = canonicalize_unsafe_init(p, r);
-                sig.hash(&mut hasher);
-                p = p2;
-            }
-        },
-        SET_TYP => {
-            let mut repr: Vec<u64> = (0..len).map(|_| {
-                let (sig, p2) = canonicalize_unsafe_init(p, r);
-                p = p2; sig
-            }).collect();
-            repr.sort_unstable();
-            for &sig in repr.iter().dedup() { sig.hash(&mut hasher); }
-        },
-        ADD_TYP|MAX_TYP|OR_TYP => {
-            let mut repr: Vec<(u64,u64)> = (0..len).map(|_| {
-                let (sig, p2) = canonicalize_unsafe_init(p, r);
-                let (w,p3) = r.read_value(p2);
-                p = p3;
-                (sig,w)
-            }).collect();
-            match typ {
-                ADD_TYP => hash_with_op(&mut repr, &mut hasher, |a,b| a+b),
-                OR_TYP => hash_with_op(&mut repr, &mut hasher, |a,b| a|b),
-                MAX_TYP => hash_with_op(&mut repr, &mut hasher, |a,b| max(a,b)),
-                _ => panic!("Unreachable")
-            }
-        },
-        TAG_TYP => {
-            let mut repr: Vec<(u64,u64)> = (0..len).map(|_| {
-                let (sig, p2) = canonicalize_unsafe_init(p, r);
-                let (w,p3) = r.read_value(p2);
-                p = p3;
-                (sig,w)
-            }).collect();
-            repr.sort_unstable();
-            for &sig in repr.iter().dedup() { sig.hash(&mut hasher); }
-        },
-        _ => panic!("Unknown typ.")
-    }
-    return (hasher.finish(), p);
-}
-
+/// Initial signature, where all states are considered equal
 pub unsafe fn canonicalize_unsafe_init<'a>(p : *const u8, r: &CReader) -> (u64, *const u8) {
-    let (w,p) = r.read_node(p);
-    if is_state(w) {
-        return (0, p);
-    } else {
-        return canonicalize_node_unsafe_init(p, r, get_header(w));
-    }
+    canonicalize_with(p, r, &|_| 0, &mut Scratch::default())
 }
 
 pub fn init_partition_ids_unsafe(data: &[u8], r: &CReader) -> Vec<u64> {
     unsafe {
         let mut new_ids_raw = vec![];
+        let mut scratch = Scratch::default();
         let mut p = data.as_ptr();
         while !CReader::is_at_end(data, p) {
-            let (sig, p_next) = canonicalize_unsafe_init(p, r);
+            let (sig, p_next) = canonicalize_with(p, r, &|_| 0, &mut scratch);
             new_ids_raw.push(sig);
             p = p_next;
         }
         return new_ids_raw
     }
-}
\ No newline at end of file
+}
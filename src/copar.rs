@@ -0,0 +1,502 @@
+//==================================//
+// Import of CoPaR .coalgebra files //
+//==================================//
+
+// A CoPaR file starts with a functor, followed by one line `name: value` per state, e.g.
+//
+//   2 × P(3 × X × X)
+//   q0: (1, {(0, q1, q2), (2, q0, q0)})
+//   q1: (0, {})
+//
+// Lines starting with '#' are comments. The functor is built from
+//   X                              the state
+//   n, {a, b, c}, N, Z             constants: the finite sets {0..n-1} and {a, b, c}, the naturals and the integers
+//   F × G (or F x G), F + G        products with values (f, g) and sums with values inj i f, where i counts from 0
+//   P F                            finite sets of F, with values {f1, f2}
+//   D F, Distribution F            distributions over F, with values {f1: 0.5, f2: 1/2}
+//   M^(F)                          finitely supported maps from F to the monoid M, with values {f1: m1, f2: m2},
+//                                  where M is N, Z, R, Q, (N, max), (Z, max), (N, min), (Z, min) or (Word, or)
+//   F^A                            maps from the finite set A to F, with values {a: f1, b: f2}
+// and each value is translated to a node:
+//   states become @i, and constants become constants, where integers are zigzag encoded
+//   a product becomes List[0]{...}, except that a leading constant c becomes the tag: c × P F becomes Set[c]{...},
+//   and similarly for D and M^(F), and c × F × G becomes List[c]{f,g}
+//   inj i f becomes List[i]{f}, P F becomes Set, D F becomes Q, and F^A becomes the List of the values in the order of A
+//   N, Z, R and Q become Add, Int, Real and Q, (N, max) and (N, min) become Max and Min, and (Word, or) becomes Or.
+//   The integers of (Z, max) and (Z, min) are shifted by 2^63, which preserves their order.
+// The states keep their CoPaR names.
+
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::{fs::File, path::Path};
+
+use crate::binrep::{CReader, CWriter, Node, LIST_TYP, SET_TYP, ADD_TYP, MAX_TYP, MIN_TYP, OR_TYP, INT_TYP, REAL_TYP, zigzag};
+use crate::hmap::HMap;
+use crate::parsing::read_fraction;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Monoid { Nat, Int, Real, Rat, NatMax, IntMax, NatMin, IntMin, WordOr }
+
+#[derive(Clone, PartialEq, Debug)]
+enum Functor {
+    X,
+    Finite(Vec<String>), // the elements of {a, b, c}, or the numbers 0..n-1
+    Nat,
+    Int,
+    Product(Vec<Functor>),
+    Sum(Vec<Functor>),
+    Pow(Box<Functor>),
+    Dist(Box<Functor>),
+    Monoid(Monoid, Box<Functor>),
+    Exp(Box<Functor>, Vec<String>),
+    Base(Monoid), // a monoid that is not (yet) followed by ^(F)
+}
+
+impl Functor {
+    fn is_constant(self: &Self) -> bool {
+        matches!(self, Functor::Finite(_) | Functor::Nat | Functor::Int)
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Tok { Sym(u8), Num(u64), Word(String) }
+
+fn tokenize(inp: &str) -> Vec<Tok> {
+    let inp = inp.replace('×', "*");
+    let bytes = inp.as_bytes();
+    let mut toks = vec![];
+    let mut depth = 0; // inside {...} words are the elements of a finite set
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        if c.is_ascii_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() {
+            let (n, m) = lexical::parse_partial::<u64,_>(&bytes[i..]).unwrap();
+            toks.push(Tok::Num(n));
+            i += m;
+        } else if c.is_ascii_alphanumeric() || c == b'_' {
+            let n = bytes[i..].iter().take_while(|&&c| c.is_ascii_alphanumeric() || c == b'_').count();
+            let word = &inp[i..i+n];
+            if depth > 0 || ["Distribution", "Word", "or", "max", "min"].contains(&word) {
+                toks.push(Tok::Word(word.to_string()));
+            } else {
+                // single letter functors can be written without spaces, as in Nx(DX)
+                for c in word.bytes() {
+                    toks.push(if c == b'x' { Tok::Sym(b'*') } else { Tok::Word((c as char).to_string()) });
+                }
+            }
+            i += n;
+        } else {
+            if c == b'{' { depth += 1 }
+            if c == b'}' { depth -= 1 }
+            toks.push(Tok::Sym(c));
+            i += 1;
+        }
+    }
+    return toks;
+}
+
+struct FunctorParser { toks: Vec<Tok>, pos: usize }
+
+impl FunctorParser {
+    fn peek(self: &Self) -> Option<&Tok> {
+        self.toks.get(self.pos)
+    }
+
+    fn next(self: &mut Self) -> Tok {
+        let tok = self.toks.get(self.pos).cloned().expect("Unexpected end of the functor.");
+        self.pos += 1;
+        return tok;
+    }
+
+    fn expect(self: &mut Self, c: u8) {
+        let tok = self.next();
+        if tok != Tok::Sym(c) { panic!("Expected {:?} in the functor, got {:?}.", c as char, tok) }
+    }
+
+    fn sum(self: &mut Self) -> Functor {
+        let mut fs = vec![self.product()];
+        while self.peek() == Some(&Tok::Sym(b'+')) {
+            self.pos += 1;
+            fs.push(self.product());
+        }
+        return if fs.len() == 1 { fs.pop().unwrap() } else { Functor::Sum(fs) };
+    }
+
+    fn product(self: &mut Self) -> Functor {
+        let mut fs = vec![self.exp()];
+        while self.peek() == Some(&Tok::Sym(b'*')) {
+            self.pos += 1;
+            fs.push(self.exp());
+        }
+        return if fs.len() == 1 { fs.pop().unwrap() } else { Functor::Product(fs) };
+    }
+
+    fn exp(self: &mut Self) -> Functor {
+        let base = self.prefix();
+        if self.peek() != Some(&Tok::Sym(b'^')) { return base }
+        self.pos += 1;
+        let exponent = self.prefix();
+        if let Functor::Finite(elems) = exponent { return Functor::Exp(Box::new(base), elems) }
+        let monoid = match base {
+            Functor::Base(monoid) => monoid,
+            Functor::Nat => Monoid::Nat,
+            Functor::Int => Monoid::Int,
+            _ => panic!("Unsupported base {:?} of ^ in the functor.", base),
+        };
+        return Functor::Monoid(monoid, Box::new(exponent));
+    }
+
+    fn prefix(self: &mut Self) -> Functor {
+        match self.peek() {
+            Some(Tok::Word(w)) if w == "P" => { self.pos += 1; Functor::Pow(Box::new(self.prefix())) }
+            Some(Tok::Word(w)) if w == "D" || w == "Distribution" => { self.pos += 1; Functor::Dist(Box::new(self.prefix())) }
+            _ => self.atom(),
+        }
+    }
+
+    fn atom(self: &mut Self) -> Functor {
+        match self.next() {
+            Tok::Num(n) => Functor::Finite((0..n).map(|i| i.to_string()).collect()),
+            Tok::Word(w) => match w.as_str() {
+                "X" => Functor::X,
+                "N" => Functor::Nat,
+                "Z" => Functor::Int,
+                "R" => Functor::Base(Monoid::Real),
+                "Q" => Functor::Base(Monoid::Rat),
+                _ => panic!("Unknown functor {}.", w),
+            },
+            Tok::Sym(b'{') => {
+                let mut elems = vec![];
+                while self.peek() != Some(&Tok::Sym(b'}')) {
+                    if !elems.is_empty() { self.expect(b',') }
+                    match self.next() {
+                        Tok::Word(w) => elems.push(w),
+                        Tok::Num(n) => elems.push(n.to_string()),
+                        tok => panic!("Expected an element of a finite set, got {:?}.", tok),
+                    }
+                }
+                self.pos += 1;
+                Functor::Finite(elems)
+            }
+            Tok::Sym(b'(') => {
+                // a monoid such as (Z, max), or a functor in parentheses
+                if let (Some(Tok::Word(m)), Some(Tok::Sym(b',')), Some(Tok::Word(op))) = (self.toks.get(self.pos), self.toks.get(self.pos+1), self.toks.get(self.pos+2)) {
+                    let monoid = match (m.as_str(), op.as_str()) {
+                        ("N", "max") => Monoid::NatMax,
+                        ("Z", "max") => Monoid::IntMax,
+                        ("N", "min") => Monoid::NatMin,
+                        ("Z", "min") => Monoid::IntMin,
+                        ("Word", "or") => Monoid::WordOr,
+                        _ => panic!("Unsupported monoid ({}, {}).", m, op),
+                    };
+                    self.pos += 3;
+                    self.expect(b')');
+                    return Functor::Base(monoid);
+                }
+                let f = self.sum();
+                self.expect(b')');
+                f
+            }
+            tok => panic!("Unexpected {:?} in the functor.", tok),
+        }
+    }
+}
+
+fn parse_functor(inp: &str) -> Functor {
+    let mut p = FunctorParser { toks: tokenize(inp), pos: 0 };
+    let f = p.sum();
+    if p.pos != p.toks.len() { panic!("Did not parse the whole functor, stopped at {:?}.", &p.toks[p.pos..]) }
+    if let Functor::Base(monoid) = f { panic!("The monoid {:?} must be followed by ^(F).", monoid) }
+    return f;
+}
+
+#[test]
+fn test_parse_functor() {
+    use Functor::*;
+    assert_eq!(parse_functor("R^X"), Monoid(self::Monoid::Real, Box::new(X)));
+    assert_eq!(parse_functor("6 x P(Nx(DX))"), Product(vec![Finite((0..6).map(|i| i.to_string()).collect()),
+        Pow(Box::new(Product(vec![Nat, Dist(Box::new(X))])))]));
+    assert_eq!(parse_functor("Z × (Z, max)^(2×X)"), Product(vec![Int,
+        Monoid(self::Monoid::IntMax, Box::new(Product(vec![Finite(vec!["0".into(), "1".into()]), X])))]));
+    assert_eq!(parse_functor("{a, b} + X^{x, y}"), Sum(vec![Finite(vec!["a".into(), "b".into()]),
+        Exp(Box::new(X), vec!["x".into(), "y".into()])]));
+    assert_eq!(parse_functor("Distribution X"), Dist(Box::new(X)));
+}
+
+//==================//
+// Values of states //
+//==================//
+
+/// Parser for the values on the state lines
+struct ValueParser<'a> {
+    inp: &'a [u8],
+    states: &'a HMap<Vec<u8>, u32>,
+}
+
+fn is_name_char(c: u8) -> bool {
+    !c.is_ascii_whitespace() && !b",:(){}".contains(&c)
+}
+
+impl<'a> ValueParser<'a> {
+    fn skip_ws(self: &mut Self) {
+        while self.inp.first().is_some_and(|c| c.is_ascii_whitespace()) { self.inp = &self.inp[1..] }
+    }
+
+    fn error(self: &Self, msg: &str) -> ! {
+        panic!("{}, got {:?}.", msg, String::from_utf8_lossy(self.inp))
+    }
+
+    fn expect(self: &mut Self, c: u8) {
+        self.skip_ws();
+        if self.inp.first() != Some(&c) { self.error(&format!("Expected {:?}", c as char)) }
+        self.inp = &self.inp[1..];
+    }
+
+    /// Try to read the character c
+    fn accept(self: &mut Self, c: u8) -> bool {
+        self.skip_ws();
+        if self.inp.first() == Some(&c) {
+            self.inp = &self.inp[1..];
+            return true;
+        }
+        return false;
+    }
+
+    fn name(self: &mut Self) -> &'a [u8] {
+        self.skip_ws();
+        let n = self.inp.iter().take_while(|&&c| is_name_char(c)).count();
+        if n == 0 { self.error("Expected a name") }
+        let (name, rest) = self.inp.split_at(n);
+        self.inp = rest;
+        return name;
+    }
+
+    fn number<T: lexical::FromLexical>(self: &mut Self) -> T {
+        self.skip_ws();
+        match lexical::parse_partial::<T,_>(self.inp) {
+            Ok((v, n)) => { self.inp = &self.inp[n..]; v }
+            Err(_) => self.error("Expected a number"),
+        }
+    }
+
+    /// Parse a value of the functor f. Returns the node and, if it is a constant that can be used as a tag, the tag.
+    fn value(self: &mut Self, f: &Functor) -> (Node, Option<u32>) {
+        match f {
+            Functor::X => {
+                let name = self.name();
+                match self.states.get(name) {
+                    Some(&state) => (Node::State(state), None),
+                    None => panic!("Unknown state {}.", String::from_utf8_lossy(name)),
+                }
+            }
+            Functor::Finite(elems) => {
+                let name = self.name();
+                let i = elems.iter().position(|e| e.as_bytes() == name)
+                    .unwrap_or_else(|| panic!("{} is not an element of {{{}}}.", String::from_utf8_lossy(name), elems.join(", ")));
+                (Node::Const(i as u64), Some(i as u32))
+            }
+            Functor::Nat => {
+                let v = self.number::<u64>();
+                (Node::Const(v), u32::try_from(v).ok())
+            }
+            Functor::Int => {
+                let v = zigzag(self.number::<i64>());
+                (Node::Const(v), u32::try_from(v).ok())
+            }
+            Functor::Product(fs) => {
+                self.expect(b'(');
+                let mut nodes = vec![];
+                let mut tags = vec![];
+                for (i, f) in fs.iter().enumerate() {
+                    if i > 0 { self.expect(b',') }
+                    let (node, tag) = self.value(f);
+                    nodes.push(node);
+                    tags.push(tag);
+                }
+                self.expect(b')');
+                // a leading constant becomes the tag
+                if let (true, Some(tag)) = (fs[0].is_constant(), tags[0]) {
+                    nodes.remove(0);
+                    if let ([Functor::Pow(_) | Functor::Dist(_) | Functor::Monoid(..)], [node]) = (&fs[1..], &mut nodes[..]) {
+                        set_tag(node, tag);
+                        return (nodes.pop().unwrap(), None);
+                    }
+                    return (Node::Coll(LIST_TYP, tag, nodes), None);
+                }
+                (Node::Coll(LIST_TYP, 0, nodes), None)
+            }
+            Functor::Sum(fs) => {
+                self.skip_ws();
+                if !self.inp.starts_with(b"inj") { self.error("Expected inj") }
+                self.inp = &self.inp[3..];
+                self.accept(b'_');
+                let i = self.number::<u32>();
+                let f = fs.get(i as usize).unwrap_or_else(|| panic!("inj {} is out of range, the sum has {} summands.", i, fs.len()));
+                let (node, _) = self.value(f);
+                (Node::Coll(LIST_TYP, i, vec![node]), None)
+            }
+            Functor::Pow(f) => {
+                let nodes = self.map(|p| p.value(f).0);
+                (Node::Coll(SET_TYP, 0, nodes), None)
+            }
+            Functor::Dist(f) | Functor::Monoid(Monoid::Rat, f) => {
+                let nodes = self.map(|p| {
+                    let (node, _) = p.value(f);
+                    p.expect(b':');
+                    p.skip_ws();
+                    let (num, den, n) = read_fraction(p.inp);
+                    p.inp = &p.inp[n..];
+                    (node, num, den)
+                });
+                (Node::Rat(0, nodes), None)
+            }
+            Functor::Monoid(monoid, f) => {
+                let typ = match monoid {
+                    Monoid::Nat => ADD_TYP,
+                    Monoid::Int => INT_TYP,
+                    Monoid::Real => REAL_TYP,
+                    Monoid::NatMax | Monoid::IntMax => MAX_TYP,
+                    Monoid::NatMin | Monoid::IntMin => MIN_TYP,
+                    Monoid::WordOr => OR_TYP,
+                    Monoid::Rat => unreachable!(),
+                };
+                let nodes = self.map(|p| {
+                    let (node, _) = p.value(f);
+                    p.expect(b':');
+                    let v = match monoid {
+                        Monoid::Nat | Monoid::NatMax | Monoid::NatMin | Monoid::WordOr => p.number::<u64>(),
+                        Monoid::Int => zigzag(p.number::<i64>()),
+                        Monoid::IntMax | Monoid::IntMin => p.number::<i64>() as u64 ^ (1 << 63),
+                        Monoid::Real => {
+                            let v = p.number::<f64>();
+                            if v.is_nan() || v < 0.0 { panic!("Weight {} in R^(F) is not supported, weights must be non-negative.", v) }
+                            v.to_bits()
+                        }
+                        Monoid::Rat => unreachable!(),
+                    };
+                    (node, v)
+                });
+                (Node::Mon(typ, 0, nodes), None)
+            }
+            Functor::Exp(f, elems) => {
+                let mut values: Vec<Option<Node>> = vec![None; elems.len()];
+                self.map(|p| {
+                    let name = p.name();
+                    let i = elems.iter().position(|e| e.as_bytes() == name)
+                        .unwrap_or_else(|| panic!("{} is not an element of {{{}}}.", String::from_utf8_lossy(name), elems.join(", ")));
+                    p.expect(b':');
+                    values[i] = Some(p.value(f).0);
+                });
+                let nodes = values.into_iter().zip(elems).map(|(node, e)| node.unwrap_or_else(|| panic!("Missing value for {}.", e))).collect();
+                (Node::Coll(LIST_TYP, 0, nodes), None)
+            }
+            Functor::Base(_) => unreachable!(),
+        }
+    }
+
+    /// Parse {e1, e2, ...}, calling f to parse each element
+    fn map<T, F>(self: &mut Self, mut f: F) -> Vec<T>
+    where F: FnMut(&mut Self) -> T {
+        self.expect(b'{');
+        let mut out = vec![];
+        if self.accept(b'}') { return out }
+        loop {
+            out.push(f(self));
+            if self.accept(b'}') { return out }
+            self.expect(b',');
+        }
+    }
+}
+
+fn set_tag(node: &mut Node, tag: u32) {
+    match node {
+        Node::Coll(_, t, _) | Node::Mon(_, t, _) | Node::Rat(t, _) | Node::Ext(_, t, _) => *t = tag,
+        _ => panic!("Node has no tag."),
+    }
+}
+
+/// The state name and value of a line `name: value`, or None for blank lines and comments
+fn split_line(line: &[u8]) -> Option<(&[u8], &[u8])> {
+    let start = line.iter().position(|c| !c.is_ascii_whitespace())?;
+    let line = &line[start..];
+    if line[0] == b'#' { return None }
+    let colon = line.iter().position(|&c| c == b':').unwrap_or_else(|| panic!("Expected name: value, got {:?}.", String::from_utf8_lossy(line)));
+    let name = &line[..colon];
+    let name = &name[..name.iter().rposition(|c| !c.is_ascii_whitespace()).map_or(0, |i| i + 1)];
+    return Some((name, &line[colon+1..]));
+}
+
+pub fn read_copar<P>(filename: P) -> (Vec<u8>,CReader)
+where P: AsRef<Path>, {
+    let filename_str = filename.as_ref().display().to_string();
+    if !filename_str.ends_with(".coalgebra") {
+        panic!("File must be *.coalgebra, but is {}", filename_str);
+    }
+    let file = File::open(&filename).
+        expect(&format!("Couldn't open file {:?}", filename.as_ref().display().to_string()));
+    let mut reader = BufReader::new(file);
+    let mut line = vec![];
+    let functor = loop {
+        if 0 == reader.read_until(b'\n', &mut line).expect("Failure while reading file.") { panic!("Missing functor.") }
+        let text = String::from_utf8(line.clone()).expect("Functor is not valid UTF-8.");
+        let text = text.trim();
+        line.clear();
+        if !text.is_empty() && !text.starts_with('#') { break parse_functor(text) }
+    };
+    let start = reader.stream_position().expect("Failure while reading file.");
+
+    // First pass: number the states in the order of their lines
+    let mut states: HMap<Vec<u8>, u32> = HMap::default();
+    let mut state_names = vec![];
+    while 0 < reader.read_until(b'\n', &mut line).expect("Failure while reading file.") {
+        if let Some((name, _)) = split_line(&line) {
+            if states.insert(name.to_vec(), states.len() as u32).is_some() {
+                panic!("State {} is defined more than once.", String::from_utf8_lossy(name));
+            }
+            state_names.push(Some(String::from_utf8(name.to_vec()).expect("State name is not valid UTF-8.")));
+        }
+        line.clear();
+    }
+
+    reader.seek(SeekFrom::Start(start)).expect("Failure while reading file.");
+    let mut w = CWriter::new();
+    while 0 < reader.read_until(b'\n', &mut line).expect("Failure while reading file.") {
+        if let Some((_, value)) = split_line(&line) {
+            let mut p = ValueParser { inp: value, states: &states };
+            let (node, _) = p.value(&functor);
+            p.skip_ws();
+            if !p.inp.is_empty() { p.error("Did not parse everything on the line") }
+            node.write(&mut w);
+        }
+        line.clear();
+    }
+    w.meta.state_names = state_names;
+    w.finish()
+}
+
+#[test]
+fn test_read_copar() {
+    use crate::{naivealg::partref_naive, optalg::partref_nlogn, renumber, binrep::read_nodes};
+    // name, number of states, partition
+    let cases: [(&str, usize, Vec<u32>); 4] = [
+        ("tests/copar_wta.coalgebra", 4, vec![0,1,1,0]),
+        ("tests/copar_dist.coalgebra", 6, vec![0,1,1,2,2,0]),
+        ("tests/copar_monoid.coalgebra", 5, vec![0,0,1,1,2]),
+        ("tests/copar_real.coalgebra", 3, vec![0,0,0]),
+    ];
+    for (filename, n, expected) in cases {
+        let (data, r) = read_copar(filename);
+        assert_eq!(r.meta.state_names.len(), n);
+        let ids1 = partref_naive(&data, &r);
+        let ids2 = partref_nlogn(data, r);
+        assert_eq!(&renumber(&ids1), &ids2);
+        assert_eq!(ids2, expected, "{}", filename);
+    }
+
+    // 2 × P(3 × X × X)
+    // q0: (1, {(0, q1, q2), (2, q0, q0)})
+    let (data, r) = read_copar("tests/copar_wta.coalgebra");
+    assert_eq!(r.meta.state_name(0), Some("q0"));
+    assert_eq!(read_nodes(&data, &r)[0], Node::from_ascii("Set[1]{List[0]{@1,@2},List[2]{@0,@0}}".as_bytes()));
+}
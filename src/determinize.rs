@@ -0,0 +1,109 @@
+//===============================//
+// Determinization (powerset) //
+//===============================//
+
+// Trace equivalence of a nondeterministic system is bisimilarity of its determinization.
+// The input states must be Tag nodes (labelled transitions, as produced by read_aut) or Set nodes
+// (unlabelled transitions, which we treat as transitions with label 0), whose elements are states.
+// Every state of the output is a set of input states, reachable from the initial state.
+// It has a single transition for each label, to the set of all successors of its members with that label.
+// The tag of a node is treated as an acceptance flag: the tag of a set of states is the maximum tag of its members,
+// so for tags 0 and 1 a set is accepting if any of its members is. Transitions to the empty set are left out.
+// Running nlogn on the output then gives the language equivalence classes.
+
+use std::collections::VecDeque;
+
+use crate::binrep::{CReader, CWriter, Node, read_nodes, SET_TYP, TAG_TYP};
+use crate::hmap::HMap;
+use itertools::Itertools;
+
+#[cfg(test)]
+use crate::{io::read_boa_txt, equiv::equiv};
+
+/// The tag and the (label, successor) transitions of a state
+fn transitions(node: &Node) -> (u32, Vec<(u64, u32)>) {
+    let succ = |node: &Node| match node {
+        Node::State(state) => *state,
+        _ => panic!("Determinization requires the elements of nodes to be states.")
+    };
+    match node {
+        Node::Coll(SET_TYP, tag, nodes) => (*tag, nodes.iter().map(|node| (0, succ(node))).collect()),
+        Node::Mon(TAG_TYP, tag, nodes) => (*tag, nodes.iter().map(|(node, label)| (*label, succ(node))).collect()),
+        _ => panic!("Determinization requires Tag or Set nodes.")
+    }
+}
+
+/// Build the powerset coalgebra reachable from the initial state.
+/// The initial state of the output is state 0. Panics if it has more than max_states states.
+pub fn determinize(data: &[u8], r: &CReader, init: u32, max_states: usize) -> (Vec<u8>, CReader) {
+    let states: Vec<(u32, Vec<(u64, u32)>)> = read_nodes(data, r).iter().map(transitions).collect();
+    if init as usize >= states.len() { panic!("Initial state @{} out of range, the coalgebra has {} states.", init, states.len()) }
+
+    let mut ids: HMap<Vec<u32>, u32> = HMap::default();
+    let mut queue: VecDeque<Vec<u32>> = VecDeque::new();
+    ids.insert(vec![init], 0);
+    queue.push_back(vec![init]);
+
+    let mut w = CWriter::new();
+    // the states are new, so only the tag and label names carry over
+    w.meta.tag_names = r.meta.tag_names.clone();
+    w.meta.label_names = r.meta.label_names.clone();
+    let mut trans: Vec<(u64, u32)> = vec![];
+    while let Some(set) = queue.pop_front() {
+        let tag = set.iter().map(|&s| states[s as usize].0).max().unwrap();
+        trans.clear();
+        for &s in &set { trans.extend(states[s as usize].1.iter()) }
+        trans.sort_unstable();
+        trans.dedup();
+
+        let mut succs: Vec<(Node, u64)> = vec![];
+        for (label, group) in &trans.iter().group_by(|lt| lt.0) {
+            let target: Vec<u32> = group.map(|lt| lt.1).collect();
+            let id = match ids.get(&target) {
+                Some(&id) => id,
+                None => {
+                    let id = ids.len() as u32;
+                    if ids.len() >= max_states {
+                        panic!("Determinization exceeded the cap of {} states.", max_states);
+                    }
+                    ids.insert(target.clone(), id);
+                    queue.push_back(target);
+                    id
+                }
+            };
+            succs.push((Node::State(id), label));
+        }
+        Node::Mon(TAG_TYP, tag, succs).write(&mut w);
+    }
+    w.finish()
+}
+
+#[test]
+fn test_determinize() {
+    // 0: Tag[0]{@1:0,@2:0}   a.b + a.c
+    // 1: Tag[0]{@3:1}        b
+    // 2: Tag[0]{@3:2}        c
+    // 3: Tag[0]{}            nil
+    // 4: Tag[0]{@5:0}        a.(b + c)
+    // 5: Tag[0]{@3:1,@3:2}   b + c
+    let (data,r) = read_boa_txt("tests/simulation_test.boa.txt");
+    let (data0, r0) = determinize(&data, &r, 0, 100);
+    assert_eq!(read_nodes(&data0, &r0), vec![
+        Node::from_ascii("Tag[0]{@1:0}".as_bytes()),
+        Node::from_ascii("Tag[0]{@2:1,@2:2}".as_bytes()),
+        Node::from_ascii("Tag[0]{}".as_bytes()),
+    ]);
+    let (data4, r4) = determinize(&data, &r, 4, 100);
+    assert!(equiv(&data0, &r0, &data4, &r4, 0, 0).0);
+
+    let (data1, r1) = determinize(&data, &r, 1, 100);
+    let (data2, r2) = determinize(&data, &r, 2, 100);
+    assert!(!equiv(&data1, &r1, &data2, &r2, 0, 0).0);
+}
+
+#[test]
+#[should_panic(expected = "exceeded the cap")]
+fn test_determinize_cap() {
+    let (data,r) = read_boa_txt("tests/simulation_test.boa.txt");
+    determinize(&data, &r, 0, 2);
+}
@@ -0,0 +1,157 @@
+//======================//
+// Graphviz DOT export //
+//======================//
+
+// Each state is drawn as a circle labelled with its name or number. The node of a state is drawn as a tree of boxes
+// below it, where each box shows the type and tag of a sub-node, like Add[0], and has an edge to each of its elements.
+// The edges are labelled with the values of the elements, or the position of the element in a List node.
+// An element that is a state gets an edge to the circle of that state, and a shared node is drawn once, as a box
+// with a dashed border that every reference to it points to. If a partition is given, the states of each block
+// are put in a cluster and filled with the colour of the block.
+
+use std::io::Write;
+
+use crate::binrep::{write_tag, CReader, Meta, Node, LIST_TYP};
+use crate::nodetype::node_type;
+
+/// Colours of the blocks, which are reused if there are more blocks
+const COLORS: [&str; 8] = ["lightblue", "lightpink", "palegreen", "khaki", "plum", "lightsalmon", "paleturquoise", "wheat"];
+
+/// A string in double quotes, as DOT expects for labels and identifiers
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn state_label(meta: &Meta, state: u32) -> String {
+    match meta.state_name(state) {
+        Some(name) => name.to_string(),
+        None => format!("@{}", state),
+    }
+}
+
+/// The type and tag of a node, like Add[0]
+fn header_label(typ: &str, tag: u32, meta: &Meta) -> String {
+    let mut w = typ.as_bytes().to_vec();
+    write_tag(tag, meta, &mut w);
+    String::from_utf8(w).unwrap()
+}
+
+struct DotWriter<'a> {
+    meta: &'a Meta,
+    lines: Vec<String>,
+    next_box: usize,
+}
+
+impl<'a> DotWriter<'a> {
+    /// Draw the node as a box, or refer to the circle of a state or the box of a shared node, and return its identifier
+    fn node(self: &mut Self, node: &Node) -> String {
+        let (label, elems): (String, Vec<(&Node, String)>) = match node {
+            Node::State(s) => return format!("s{}", s),
+            Node::Shared(k) => return format!("shared{}", k),
+            Node::Const(v) => (v.to_string(), vec![]),
+            _ => {
+                let (typ, tag, _) = node.header();
+                let t = node_type(typ).expect("Unknown typ.");
+                let mut elems = vec![];
+                node.for_each_elem(|elem, values| {
+                    let mut w = vec![];
+                    if t.arity() > 0 {
+                        t.print_values(values, self.meta, &mut w);
+                    } else if typ == LIST_TYP {
+                        w.extend(elems.len().to_string().as_bytes());
+                    }
+                    elems.push((elem, String::from_utf8(w).unwrap()));
+                });
+                (header_label(t.name(), tag, self.meta), elems)
+            }
+        };
+        let id = format!("n{}", self.next_box);
+        self.next_box += 1;
+        self.lines.push(format!("  {} [shape=box, label={}];", id, quote(&label)));
+        self.elements(&id, elems);
+        return id;
+    }
+
+    fn elements(self: &mut Self, id: &str, elems: Vec<(&Node, String)>) {
+        for (node, label) in elems {
+            let child = self.node(node);
+            if label.is_empty() {
+                self.lines.push(format!("  {} -> {};", id, child));
+            } else {
+                self.lines.push(format!("  {} -> {} [label={}];", id, child, quote(&label)));
+            }
+        }
+    }
+}
+
+/// Write the coalgebra in DOT format. If blocks is given, it is the block of each state.
+pub fn write_dot<W: Write>(writer: &mut W, name: &str, data: &[u8], r: &CReader, blocks: Option<&[u32]>) {
+    let nodes = unsafe {
+        let mut nodes = vec![];
+        let mut p = data.as_ptr();
+        while !CReader::is_at_end(data, p) { nodes.push(Node::read_raw(r, &mut p)) }
+        nodes
+    };
+    if let Some(blocks) = blocks {
+        if blocks.len() != nodes.len() { panic!("The partition has {} states, but the coalgebra has {}.", blocks.len(), nodes.len()) }
+    }
+    let mut dw = DotWriter { meta: &r.meta, lines: vec![], next_box: 0 };
+    for k in 0..r.shared_locs.len() {
+        let node = unsafe { Node::read_raw(r, &mut r.shared_node(k as u64)) };
+        let id = dw.node(&node);
+        dw.lines.push(format!("  shared{} [shape=box, style=dashed, label={}];", k, quote(&format!("${}", k))));
+        dw.lines.push(format!("  shared{} -> {};", k, id));
+    }
+    let mut states = vec![];
+    for (s, node) in nodes.iter().enumerate() {
+        let style = match blocks {
+            Some(blocks) => format!(", style=filled, fillcolor={}", COLORS[blocks[s] as usize % COLORS.len()]),
+            None => String::new(),
+        };
+        states.push(format!("s{} [shape=circle, label={}{}];", s, quote(&state_label(&r.meta, s as u32)), style));
+        let root = dw.node(node);
+        dw.lines.push(format!("  s{} -> {} [style=dotted, arrowhead=none];", s, root));
+    }
+
+    let err = "Writing error.";
+    writeln!(writer, "digraph {} {{", quote(name)).expect(err);
+    match blocks {
+        Some(blocks) => {
+            let num_blocks = blocks.iter().max().map_or(0, |&m| m as usize + 1);
+            for b in 0..num_blocks {
+                writeln!(writer, "  subgraph cluster_{} {{", b).expect(err);
+                writeln!(writer, "    label={};", quote(&format!("block {}", b))).expect(err);
+                for (s, line) in states.iter().enumerate() {
+                    if blocks[s] as usize == b { writeln!(writer, "    {}", line).expect(err) }
+                }
+                writeln!(writer, "  }}").expect(err);
+            }
+        }
+        None => for line in &states { writeln!(writer, "  {}", line).expect(err) },
+    }
+    for line in &dw.lines { writeln!(writer, "{}", line).expect(err) }
+    writeln!(writer, "}}").expect(err);
+}
+
+#[test]
+fn test_write_dot() {
+    use crate::io::read_boa_txt;
+    let (data, r) = read_boa_txt("tests/shared_test.boa.txt");
+    let mut out = vec![];
+    write_dot(&mut out, "shared_test", &data, &r, Some(&[0, 0, 1, 2, 2]));
+    let dot = String::from_utf8(out).unwrap();
+    assert!(dot.starts_with("digraph \"shared_test\" {\n  subgraph cluster_0 {\n    label=\"block 0\";\n    s0 [shape=circle, label=\"@0\", style=filled, fillcolor=lightblue];\n"));
+    // $0 = Add[0]{@0:1,@1:1} is drawn once
+    assert_eq!(dot.matches("label=\"Add[0]\"").count(), 2);
+    assert!(dot.contains("  n0 [shape=box, label=\"Add[0]\"];\n  n0 -> s0 [label=\"1\"];\n  n0 -> s1 [label=\"1\"];\n"));
+    assert!(dot.contains("  shared0 [shape=box, style=dashed, label=\"$0\"];\n  shared0 -> n0;\n"));
+    assert!(dot.contains("  s3 -> shared2 [style=dotted, arrowhead=none];\n"));
+    assert!(dot.ends_with("}\n"));
+
+    let (data, r) = read_boa_txt("tests/names_test.boa.txt");
+    let mut out = vec![];
+    write_dot(&mut out, "names_test", &data, &r, None);
+    let dot = String::from_utf8(out).unwrap();
+    assert!(dot.contains("  n0 [shape=box, label=\"List[send]\"];\n  n0 -> s1 [label=\"0\"];\n  n0 -> s2 [label=\"1\"];\n"));
+    assert!(dot.contains("label=\"List[\\\"time out\\\"]\""));
+}
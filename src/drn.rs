@@ -0,0 +1,300 @@
+//=========================//
+// Storm explicit format //
+//=========================//
+
+// Storm's DRN format starts with a header of @-keywords, like
+//   @type: MDP
+//   @reward_models
+//   steps
+//   @nr_states
+//   4
+// followed by @model and a block per state:
+//   state 0 [1] init goal
+//       action a [0]
+//           1 : 0.5
+//           2 : 0.5
+// The brackets give the value of each reward model, and are left out if there are none. Lines starting with // are comments.
+// The states are mapped like those of PRISM models (see prism.rs): a state of a DTMC or CTMC becomes Real[o]{@t:p,...}
+// and a state of an MDP becomes Set[o]{Real[a]{@t:p,...},...}. The tag o stands for the labels and the state rewards,
+// and the tag a for the action and its rewards, as kept in meta.observations. An action whose name is its index
+// among the choices of the state is unnamed. The action rewards of a DTMC or CTMC are part of the observation of
+// the state, as each state has a single action. The model type and the names of the reward models are kept in meta.
+//
+// write_drn writes a coalgebra of this form, like a quotient, with the model type and reward models of meta.
+// Without a model type, a model with Set nodes is written as an MDP, and otherwise as a DTMC if the outgoing
+// probabilities of each state sum to 1 and as a CTMC if not. Reward models without a name are written as r0, r1, ...
+
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::{fs::File, path::Path};
+
+use crate::binrep::{read_nodes, CReader, CWriter, Node, Observation, REAL_TYP, SET_TYP};
+use crate::io::create_file;
+use crate::prism::{action_observation, normalize_rewards, parse, state_observation, tag_of, weighted_transitions, ObservationTags};
+
+/// A probability or rate, which may be a fraction as in the exact mode of Storm
+fn parse_prob(s: &str, line: &str) -> u64 {
+    let p = match s.split_once('/') {
+        Some((num, den)) => parse::<f64>(Some(num.trim()), "a probability", line) / parse::<f64>(Some(den.trim()), "a probability", line),
+        None => parse(Some(s.trim()), "a probability", line),
+    };
+    if p.is_nan() || p < 0.0 { panic!("Negative probability or rate in line {:?}.", line) }
+    p.to_bits()
+}
+
+/// Parse rewards in brackets at the start of s, like [1, 0.5], and return them and the rest of s
+fn parse_rewards<'a>(s: &'a str, line: &str) -> (Vec<f64>, &'a str) {
+    let s = s.trim_start();
+    let Some(rest) = s.strip_prefix('[') else { return (vec![], s) };
+    let (rewards, rest) = rest.split_once(']').unwrap_or_else(|| panic!("Expected ] in line {:?}.", line));
+    let rewards = rewards.split(',').map(|r| parse(Some(r.trim()), "a reward", line)).collect();
+    return (rewards, rest);
+}
+
+/// The name and rewards of an action and its transitions
+type Action = (Option<String>, Vec<f64>, Vec<(Node, u64)>);
+
+struct State {
+    labels: Vec<String>,
+    rewards: Vec<f64>,
+    choices: Vec<Action>,
+}
+
+pub fn read_drn<P>(filename: P) -> (Vec<u8>,CReader)
+where P: AsRef<Path>, {
+    let filename = filename.as_ref();
+    if filename.extension().is_none_or(|ext| ext != "drn") {
+        panic!("File must be *.drn, but is {}", filename.display());
+    }
+    let file = File::open(filename).
+        expect(&format!("Couldn't open file {:?}", filename.display().to_string()));
+    let mut lines = BufReader::new(file).lines()
+        .map(|line| line.expect("Failure while reading file."))
+        .filter(|line| !line.trim_start().starts_with("//"))
+        .peekable();
+
+    // Header, up to @model. The keywords other than @type are followed by their value on the next line, which may be empty.
+    let mut model_type = None;
+    let mut reward_models = vec![];
+    while let Some(line) = lines.next() {
+        let line = line.trim();
+        if line == "@model" { break }
+        if let Some(t) = line.strip_prefix("@type:") {
+            model_type = Some(t.trim().to_string());
+        } else if line == "@parameters" {
+            if lines.peek().is_some_and(|l| !l.trim().is_empty() && !l.trim().starts_with('@')) {
+                panic!("Parametric models are not supported.");
+            }
+        } else if line == "@reward_models" {
+            if let Some(names) = lines.next_if(|l| !l.trim().starts_with('@')) {
+                reward_models = names.split_ascii_whitespace().map(String::from).collect();
+            }
+        } else if line.starts_with('@') && !line.contains(':') {
+            // the value on the next line, like the number of states, isn't needed
+            lines.next_if(|l| !l.trim().starts_with('@'));
+        }
+    }
+    let is_mdp = match model_type.as_deref() {
+        Some("DTMC") | Some("CTMC") => false,
+        Some("MDP") => true,
+        Some(t) => panic!("Unsupported model type {}, expected DTMC, CTMC or MDP.", t),
+        None => panic!("Missing @type in .drn file."),
+    };
+
+    let mut states: Vec<State> = vec![];
+    for line in lines {
+        let trimmed = line.trim();
+        if trimmed.is_empty() { continue }
+        if let Some(rest) = trimmed.strip_prefix("state ") {
+            let rest = rest.trim_start();
+            let (id, rest) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            let id: usize = parse(Some(id), "a state", &line);
+            if id != states.len() { panic!("Expected state {} in line {:?}.", states.len(), line) }
+            let (rewards, rest) = parse_rewards(rest, &line);
+            let labels = rest.split_ascii_whitespace().map(|l| l.trim_matches('"').to_string()).collect();
+            states.push(State { labels, rewards, choices: vec![] });
+        } else if let Some(rest) = trimmed.strip_prefix("action ") {
+            let state = states.last_mut().unwrap_or_else(|| panic!("Action before the first state in line {:?}.", line));
+            let rest = rest.trim_start();
+            let (name, rest) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            let (rewards, _) = parse_rewards(rest, &line);
+            let index = state.choices.len().to_string();
+            let name = if name == index || name == "__NOLABEL__" { None } else { Some(name.to_string()) };
+            state.choices.push((name, rewards, vec![]));
+        } else {
+            let (t, p) = trimmed.split_once(':').unwrap_or_else(|| panic!("Expected target : probability in line {:?}.", line));
+            let t: u32 = parse(Some(t.trim()), "a state", &line);
+            let p = parse_prob(p, &line);
+            let choice = states.last_mut().and_then(|s| s.choices.last_mut())
+                .unwrap_or_else(|| panic!("Transition outside of an action in line {:?}.", line));
+            choice.2.push((Node::State(t), p));
+        }
+    }
+    let num_states = states.len();
+    if let Some(t) = states.iter().flat_map(|s| &s.choices).flat_map(|c| &c.2).find_map(|(t, _)| match t {
+        Node::State(t) if *t as usize >= num_states => Some(*t),
+        _ => None,
+    }) {
+        panic!("State {} out of range, the model has {} states.", t, num_states);
+    }
+
+    let mut tags = ObservationTags::default();
+    let mut w = CWriter::new();
+    for state in states {
+        let rewards = normalize_rewards(&state.rewards);
+        let node = if is_mdp {
+            let tag = tags.tag(Observation::State { labels: state.labels, rewards, action_rewards: vec![] });
+            let dists = state.choices.into_iter().map(|(name, rewards, trans)| {
+                Node::Mon(REAL_TYP, tags.tag(Observation::Action { name, rewards: normalize_rewards(&rewards) }), trans)
+            }).collect();
+            Node::Coll(SET_TYP, tag, dists)
+        } else {
+            if state.choices.len() > 1 { panic!("A state of a DTMC or CTMC has more than one action.") }
+            let (_, action_rewards, trans) = state.choices.into_iter().next().unwrap_or_default();
+            let action_rewards = normalize_rewards(&action_rewards);
+            let tag = tags.tag(Observation::State { labels: state.labels, rewards, action_rewards });
+            Node::Mon(REAL_TYP, tag, trans)
+        };
+        node.write(&mut w);
+    }
+    w.meta.tag_names = tags.names;
+    w.meta.observations = tags.observations;
+    w.meta.model_type = model_type;
+    w.meta.reward_models = reward_models;
+    w.finish()
+}
+
+/// The action tag of a choice, None for a DTMC or CTMC, and its transitions as target and probability
+type WrittenChoice = (Option<u32>, Vec<(u32, String)>);
+
+/// Write a coalgebra of the form produced by read_drn as a .drn file
+pub fn write_drn<P>(filename: P, data: &[u8], r: &CReader)
+where P: AsRef<Path>, {
+    let filename = filename.as_ref();
+    if filename.extension().is_none_or(|ext| ext != "drn") {
+        panic!("File must be *.drn, but is {}", filename.display());
+    }
+    let nodes = read_nodes(data, r);
+    let is_mdp = match r.meta.model_type.as_deref() {
+        Some(t) => t == "MDP",
+        None => nodes.iter().any(|node| matches!(node, Node::Coll(SET_TYP, _, _))),
+    };
+
+    let choices: Vec<Vec<WrittenChoice>> = nodes.iter().map(|node| {
+        if is_mdp {
+            let Node::Coll(SET_TYP, _, dists) = node else { panic!("Export of an MDP requires all states to be Set nodes.") };
+            dists.iter().map(|dist| (Some(tag_of(dist)), weighted_transitions(dist))).collect()
+        } else {
+            vec![(None, weighted_transitions(node))]
+        }
+    }).collect();
+    let model_type = r.meta.model_type.clone().unwrap_or_else(|| {
+        let is_ctmc = !is_mdp && choices.iter().any(|c| {
+            let sum: f64 = c[0].1.iter().map(|(_, p)| p.parse::<f64>().unwrap()).sum();
+            c[0].1.len() > 0 && (sum - 1.0).abs() > 1e-6
+        });
+        String::from(if is_mdp { "MDP" } else if is_ctmc { "CTMC" } else { "DTMC" })
+    });
+    let observations: Vec<(&[String], &[String], &[String])> = nodes.iter().map(|node| state_observation(&r.meta, tag_of(node))).collect();
+    let actions: Vec<Vec<(Option<&str>, &[String])>> = choices.iter().enumerate()
+        .map(|(s, c)| c.iter().map(|(tag, _)| match tag {
+            Some(tag) => action_observation(&r.meta, *tag),
+            None => (None, observations[s].2),
+        }).collect())
+        .collect();
+    let num_rewards = observations.iter().map(|o| o.1)
+        .chain(actions.iter().flatten().map(|a| a.1))
+        .map(|r| r.len()).chain([r.meta.reward_models.len()]).max().unwrap_or(0);
+    let reward_models: Vec<String> = (0..num_rewards)
+        .map(|i| r.meta.reward_models.get(i).cloned().unwrap_or_else(|| format!("r{}", i)))
+        .collect();
+    let rewards = |r: &[String]| -> String {
+        if num_rewards == 0 { return String::new() }
+        let mut rs: Vec<&str> = r.iter().map(String::as_str).collect();
+        rs.resize(num_rewards, "0");
+        format!(" [{}]", rs.join(", "))
+    };
+
+    let mut writer = BufWriter::new(create_file(filename));
+    let mut out = String::new();
+    out.push_str("// Exported by boa\n");
+    out.push_str(&format!("@type: {}\n", model_type));
+    out.push_str("@parameters\n\n@reward_models\n");
+    out.push_str(&reward_models.join(" "));
+    out.push_str(&format!("\n@nr_states\n{}\n@nr_choices\n{}\n@model\n", nodes.len(), choices.iter().map(|c| c.len()).sum::<usize>()));
+    writer.write_all(out.as_bytes()).expect("Writing error.");
+    for (s, state_choices) in choices.iter().enumerate() {
+        let (labels, reward, _) = observations[s];
+        let labels: String = labels.iter().map(|l| format!(" {}", l)).collect();
+        writeln!(writer, "state {}{}{}", s, rewards(reward), labels).expect("Writing error.");
+        for (i, (_, trans)) in state_choices.iter().enumerate() {
+            let (action, reward) = actions[s][i];
+            let action = action.map_or(i.to_string(), String::from);
+            writeln!(writer, "\taction {}{}", action, rewards(reward)).expect("Writing error.");
+            for (t, p) in trans { writeln!(writer, "\t\t{} : {}", t, p).expect("Writing error.") }
+        }
+    }
+}
+
+#[test]
+fn test_read_drn() {
+    use crate::{naivealg::partref_naive, optalg::partref_nlogn};
+    // the MDP of tests/prism_mdp.tra, except that action b of state 0 has a reward
+    let (data, r) = read_drn("tests/drn_mdp.drn");
+    let names: Vec<&String> = r.meta.tag_names.values().collect();
+    assert_eq!(names, vec!["{init}", "a", "b r=2", "{} r=1", "{}", "b"]);
+    let expected = ["Set[0]{Real[1]{@1:0.5,@2:0.5},Real[2]{@3:1}}", "Set[3]{Real[4]{@1:1}}",
+                    "Set[3]{Real[4]{@2:1}}", "Set[5]{Real[4]{@3:1},Real[6]{@1:1}}"];
+    let expected: Vec<Node> = expected.iter().map(|s| Node::from_ascii(s.as_bytes())).collect();
+    assert_eq!(read_nodes(&data, &r), expected);
+    let ids1 = partref_naive(&data, &r);
+    let ids2 = partref_nlogn(data, r);
+    assert_eq!(&crate::renumber(&ids1), &ids2);
+    assert_eq!(ids2, vec![0,1,1,2]);
+}
+
+#[test]
+fn test_write_drn_quotient() {
+    use crate::{optalg::partref_nlogn, quotient::quotient};
+    for (model, num_blocks) in [("dtmc", 3), ("mdp", 3)] {
+        let (data, r) = read_drn(format!("tests/drn_{}.drn", model));
+        let ids = partref_nlogn(data.clone(), r.clone());
+        let (qdata, qr) = quotient(&data, &r, &ids);
+        let path = std::env::temp_dir().join(format!("boa_drn_test_{}_{}.drn", model, std::process::id()));
+        write_drn(&path, &qdata, &qr);
+        let (data2, r2) = read_drn(&path);
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read_nodes(&data2, &r2), read_nodes(&qdata, &qr));
+        assert_eq!(r2.meta.tag_names, qr.meta.tag_names);
+        assert_eq!(partref_nlogn(data2, r2).len(), num_blocks);
+        if model == "dtmc" {
+            assert_eq!(text, "// Exported by boa\n@type: DTMC\n@parameters\n\n@reward_models\n\n@nr_states\n3\n@nr_choices\n3\n@model\n\
+                state 0 init\n\taction 0\n\t\t1 : 1\nstate 1\n\taction 0\n\t\t2 : 1\nstate 2 goal\n\taction 0\n\t\t2 : 1\n");
+        }
+    }
+}
+
+#[test]
+fn test_drn_meta_roundtrip() {
+    // a CTMC whose exit rates are 1, with named reward models and action rewards
+    let dir = std::env::temp_dir().join(format!("boa_drn_meta_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("m.drn");
+    std::fs::write(&path, "@type: CTMC\n@parameters\n\n@reward_models\ntime cost\n@nr_states\n2\n@model\n\
+        state 0 [1, 0] init a,b}\n\taction 0 [0, 3]\n\t\t1 : 1\nstate 1\n\taction 0\n\t\t0 : 1\n").unwrap();
+    let (data, r) = read_drn(&path);
+    assert_eq!(r.meta.model_type.as_deref(), Some("CTMC"));
+    assert_eq!(r.meta.reward_models, vec!["time", "cost"]);
+    assert_eq!(r.meta.observations[&0], Observation::State {
+        labels: vec!["init".to_string(), "a,b}".to_string()], rewards: vec!["1".to_string(), "0".to_string()],
+        action_rewards: vec!["0".to_string(), "3".to_string()] });
+    let out = dir.join("out.drn");
+    write_drn(&out, &data, &r);
+    let text = std::fs::read_to_string(&out).unwrap();
+    assert_eq!(text, "// Exported by boa\n@type: CTMC\n@parameters\n\n@reward_models\ntime cost\n@nr_states\n2\n@nr_choices\n2\n@model\n\
+        state 0 [1, 0] init a,b}\n\taction 0 [0, 3]\n\t\t1 : 1\nstate 1 [0, 0]\n\taction 0 [0, 0]\n\t\t0 : 1\n");
+    let (data2, r2) = read_drn(&out);
+    assert_eq!(read_nodes(&data2, &r2), read_nodes(&data, &r));
+    assert_eq!(r2.meta, r.meta);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
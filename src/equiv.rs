@@ -0,0 +1,372 @@
+//=======================================//
+// Equivalence of states across two files //
+//=======================================//
+
+use crate::binrep::{CReader, CWriter, Meta, Node, RealKeys, read_nodes, LIST_TYP, TAG_TYP};
+use crate::coalg::{init_partition_ids_unsafe, repartition_all_unsafe64};
+use crate::optalg::partref_nlogn;
+use crate::quotient::{canonical, merge_elements};
+use crate::renumber;
+use crate::hmap::HMap;
+
+#[cfg(test)]
+use crate::io::read_boa_txt;
+
+/// Don't record the refinement history for a witness if it would take more than this many entries
+const MAX_HISTORY_ENTRIES: usize = 1 << 27;
+
+/// Form the disjoint union of two coalgebras.
+/// The states of the second coalgebra are shifted so that they come after the states of the first.
+/// Tags are matched by name if they have one, and by number otherwise, so the tags of the second
+/// coalgebra are renumbered where the two files disagree. The labels of Tag nodes are matched in the same way.
+/// The union keeps the tag, label and state names of both.
+/// Both are re-encoded with a fresh writer, which merges their dictionaries.
+/// Returns the union and the number of states of each coalgebra.
+pub fn disjoint_union(data_a: &[u8], r_a: &CReader, data_b: &[u8], r_b: &CReader) -> (Vec<u8>, CReader, u32, u32) {
+    let mut nodes_a = read_nodes(data_a, r_a);
+    let mut nodes_b = read_nodes(data_b, r_b);
+    let num_a = nodes_a.len() as u32;
+    let num_b = nodes_b.len() as u32;
+
+    let mut w = CWriter::new();
+    w.meta = r_a.meta.clone();
+    let mut next_tag = 0;
+    for node in nodes_a.iter_mut().chain(nodes_b.iter_mut()) {
+        node.map_tags(&mut |t| { next_tag = next_tag.max(t as u64 + 1); t });
+    }
+    for &t in r_a.meta.tag_names.keys().chain(r_b.meta.tag_names.keys()) { next_tag = next_tag.max(t as u64 + 1) }
+    let tags_a: HMap<&str, u32> = r_a.meta.tag_names.iter().map(|(t, name)| (name.as_str(), *t)).collect();
+    let mut tag_map: HMap<u32, u32> = HMap::default();
+    let mut fresh = |name: Option<&String>, w: &mut CWriter| {
+        let tag = u32::try_from(next_tag).expect("Too many tags in the union.");
+        next_tag += 1;
+        if let Some(name) = name { w.meta.tag_names.insert(tag, name.clone()); }
+        tag
+    };
+    for (&t, name) in &r_b.meta.tag_names {
+        let t2 = match tags_a.get(name.as_str()) {
+            Some(&t2) => t2,
+            None => fresh(Some(name), &mut w),
+        };
+        tag_map.insert(t, t2);
+    }
+    // unnamed tags of B that A uses for a name
+    for &t in r_a.meta.tag_names.keys() {
+        if !r_b.meta.tag_names.contains_key(&t) { tag_map.insert(t, fresh(None, &mut w)); }
+    }
+
+    let mut next_label = 0;
+    for node in nodes_a.iter_mut().chain(nodes_b.iter_mut()) {
+        node.map_labels(&mut |l| { next_label = next_label.max(l.saturating_add(1)); l });
+    }
+    for name in r_a.meta.label_names.keys().chain(r_b.meta.label_names.keys()) { next_label = next_label.max(name.saturating_add(1)) }
+    let labels_a: HMap<&str, u64> = r_a.meta.label_names.iter().map(|(l, name)| (name.as_str(), *l)).collect();
+    let mut label_map: HMap<u64, u64> = HMap::default();
+    let mut fresh_label = |name: Option<&String>, w: &mut CWriter| {
+        let label = next_label;
+        next_label += 1;
+        if let Some(name) = name { w.meta.label_names.insert(label, name.clone()); }
+        label
+    };
+    for (&l, name) in &r_b.meta.label_names {
+        let l2 = match labels_a.get(name.as_str()) {
+            Some(&l2) => l2,
+            None => fresh_label(Some(name), &mut w),
+        };
+        label_map.insert(l, l2);
+    }
+    for &l in r_a.meta.label_names.keys() {
+        if !r_b.meta.label_names.contains_key(&l) { label_map.insert(l, fresh_label(None, &mut w)); }
+    }
+
+    if !r_a.meta.state_names.is_empty() || !r_b.meta.state_names.is_empty() {
+        let names = |meta: &Meta, n: u32| (0..n).map(|s| meta.state_name(s).map(String::from)).collect::<Vec<_>>();
+        w.meta.state_names = names(&r_a.meta, num_a);
+        w.meta.state_names.extend(names(&r_b.meta, num_b));
+    }
+
+    for node in &nodes_a {
+        node.write(&mut w);
+    }
+    for node in &mut nodes_b {
+        node.map_states(&|s| s + num_a);
+        node.map_tags(&mut |t| *tag_map.get(&t).unwrap_or(&t));
+        node.map_labels(&mut |l| *label_map.get(&l).unwrap_or(&l));
+        node.write(&mut w);
+    }
+    let (data, r) = w.finish();
+    return (data, r, num_a, num_b);
+}
+
+/// Compute the signatures of all states after each round of naive refinement, until the partition is stable.
+/// Returns None if the history would get too large.
+fn refinement_history(data: &[u8], r: &CReader) -> Option<Vec<Vec<u64>>> {
+    let mut history = vec![init_partition_ids_unsafe(data, r)];
+    let num_states = history[0].len();
+    let mut part_count = renumber(&history[0]).iter().max().map_or(0, |m| m + 1);
+    loop {
+        if (history.len() + 1) * num_states > MAX_HISTORY_ENTRIES { return None }
+        let ids = repartition_all_unsafe64(data, r, history.last().unwrap());
+        let new_part_count = renumber(&ids).iter().max().map_or(0, |m| m + 1);
+        history.push(ids);
+        if new_part_count == part_count { return Some(history) }
+        part_count = new_part_count;
+    }
+}
+
+fn show_node(node: &Node, meta: &Meta) -> String {
+    let mut buf = vec![];
+    node.to_ascii_named(meta, &mut buf);
+    String::from_utf8(buf).unwrap()
+}
+
+/// The elements of an unordered node with their labels, which are 0 except for Tag nodes
+fn labelled_elements(node: &Node) -> Vec<(&Node, u64)> {
+    match node {
+        Node::Coll(_, _, nodes) => nodes.iter().map(|node| (node, 0)).collect(),
+        Node::Mon(TAG_TYP, _, nodes) => nodes.iter().map(|(node, label)| (node, *label)).collect(),
+        Node::Mon(_, _, nodes) => nodes.iter().map(|(node, _)| (node, 0)).collect(),
+        Node::Rat(_, nodes) => nodes.iter().map(|(node, _, _)| (node, 0)).collect(),
+        _ => vec![],
+    }
+}
+
+/// The elements of a canonical unordered node as (element, label, values), which are equal for two nodes
+/// if and only if the two nodes are equal
+fn canonical_entries(node: &Node) -> HMap<(Node, u64, Vec<u64>), ()> {
+    match node {
+        Node::Coll(_, _, nodes) => nodes.iter().map(|node| ((node.clone(), 0, vec![]), ())).collect(),
+        Node::Mon(TAG_TYP, _, nodes) => nodes.iter().map(|(node, label)| ((node.clone(), *label, vec![]), ())).collect(),
+        Node::Mon(_, _, nodes) => nodes.iter().map(|(node, v)| ((node.clone(), 0, vec![*v]), ())).collect(),
+        Node::Rat(_, nodes) => nodes.iter().map(|(node, num, den)| ((node.clone(), 0, vec![*num, *den]), ())).collect(),
+        _ => HMap::default(),
+    }
+}
+
+/// Collect the pairs of states (c,d) with c in node x and d in node y that are in different blocks of prev,
+/// and that explain why x and y differ once their states are replaced by these blocks.
+/// Successors are paired the way signatures compare them: the elements of a List, or of a user-defined node, by position,
+/// and the elements of an unordered node if they have the same label (for Tag nodes), and if they go to blocks
+/// that x has with another weight than y and the other way around.
+fn successor_pairs(x: &Node, y: &Node, prev: &[u32], reals: &RealKeys, pairs: &mut Vec<(u32, u32)>) {
+    let key = |node: &Node| {
+        let mut node = node.clone();
+        node.map_states(&|s| prev[s as usize]);
+        merge_elements(&mut node, reals);
+        canonical(&node, reals)
+    };
+    match (x, y) {
+        (Node::State(c), Node::State(d)) if prev[*c as usize] != prev[*d as usize] => pairs.push((*c, *d)),
+        (Node::Coll(LIST_TYP, tag1, xs), Node::Coll(LIST_TYP, tag2, ys)) => {
+            if tag1 != tag2 || xs.len() != ys.len() { return }
+            for (x, y) in xs.iter().zip(ys.iter()) { successor_pairs(x, y, prev, reals, pairs) }
+        }
+        (Node::Ext(typ1, tag1, xs), Node::Ext(typ2, tag2, ys)) => {
+            if typ1 != typ2 || tag1 != tag2 || xs.len() != ys.len() { return }
+            for ((x, _), (y, _)) in xs.iter().zip(ys.iter()) { successor_pairs(x, y, prev, reals, pairs) }
+        }
+        (Node::Coll(typ1, tag1, _), Node::Coll(typ2, tag2, _)) | (Node::Mon(typ1, tag1, _), Node::Mon(typ2, tag2, _)) if typ1 == typ2 && tag1 == tag2 => {
+            unordered_pairs(x, y, key, prev, reals, pairs);
+        }
+        (Node::Rat(tag1, _), Node::Rat(tag2, _)) if tag1 == tag2 => unordered_pairs(x, y, key, prev, reals, pairs),
+        _ => {}
+    }
+}
+
+fn unordered_pairs<K>(x: &Node, y: &Node, key: K, prev: &[u32], reals: &RealKeys, pairs: &mut Vec<(u32, u32)>)
+where K: Fn(&Node) -> Node {
+    let entries_x = canonical_entries(&key(x));
+    let entries_y = canonical_entries(&key(y));
+    let differ = |entries: &HMap<(Node, u64, Vec<u64>), ()>, other: &HMap<(Node, u64, Vec<u64>), ()>| -> HMap<(Node, u64), ()> {
+        entries.keys().filter(|e| !other.contains_key(e)).map(|(node, label, _)| ((node.clone(), *label), ())).collect()
+    };
+    let differ_x = differ(&entries_x, &entries_y);
+    let differ_y = differ(&entries_y, &entries_x);
+    let elems_y: Vec<(&Node, u64, Node)> = labelled_elements(y).into_iter().map(|(node, label)| (node, label, key(node))).collect();
+    for (node_x, label_x) in labelled_elements(x) {
+        let key_x = key(node_x);
+        if !differ_x.contains_key(&(key_x.clone(), label_x)) { continue }
+        for (node_y, label_y, key_y) in &elems_y {
+            if *label_y == label_x && *key_y != key_x && differ_y.contains_key(&(key_y.clone(), *label_y)) {
+                successor_pairs(node_x, node_y, prev, reals, pairs);
+            }
+        }
+    }
+}
+
+/// Explain why states x and y of the disjoint union of A and B are not equivalent.
+/// States below num_a belong to A and are shown as a@i, the others belong to B and are shown as b@i.
+/// Each line of the witness shows a pair of states that is distinguished after some number of rounds,
+/// together with their nodes where every successor is replaced by its block in the round before.
+/// The next pair is a pair of successors that is distinguished in an earlier round, see successor_pairs.
+/// Returns None if the states are equivalent or the model is too large to record the refinement history.
+pub fn witness(data: &[u8], r: &CReader, num_a: u32, x: u32, y: u32) -> Option<Vec<String>> {
+    let history = refinement_history(data, r)?;
+    let nodes = read_nodes(data, r);
+    let blocks: Vec<Vec<u32>> = history.iter().map(|ids| renumber(ids)).collect();
+    let distinguished_at = |a: u32, b: u32| (0..history.len()).find(|&k| history[k][a as usize] != history[k][b as usize]);
+    // The union has the state names of A followed by those of B, and successors replaced by blocks are shown without names
+    let meta_a = &r.meta;
+    let meta_b = Meta { state_names: r.meta.state_names.get(num_a as usize..).unwrap_or(&[]).to_vec(), ..r.meta.clone() };
+    let meta_blocks = Meta { state_names: vec![], ..r.meta.clone() };
+    let label = |s: u32| if s < num_a { format!("a{}", meta_a.state_ref(s)) } else { format!("b{}", meta_b.state_ref(s - num_a)) };
+    let original = |s: u32| {
+        let mut node = nodes[s as usize].clone();
+        if s < num_a { return show_node(&node, meta_a) }
+        node.map_states(&|t| t - num_a);
+        show_node(&node, &meta_b)
+    };
+
+    let reals = RealKeys::default();
+    let mut lines = vec![];
+    let (mut a, mut b) = (x, y);
+    let mut round = distinguished_at(a, b)?;
+    loop {
+        if round == 0 {
+            lines.push(format!("{} = {} and {} = {} differ in their shape", label(a), original(a), label(b), original(b)));
+            return Some(lines);
+        }
+        let prev = &blocks[round - 1];
+        let mut node_a = nodes[a as usize].clone();
+        let mut node_b = nodes[b as usize].clone();
+        node_a.map_states(&|s| prev[s as usize]);
+        node_b.map_states(&|s| prev[s as usize]);
+        lines.push(format!("{} and {} differ after {} rounds: {} vs {} (successors replaced by their block after {} rounds)",
+            label(a), label(b), round, show_node(&node_a, &meta_blocks), show_node(&node_b, &meta_blocks), round - 1));
+
+        let mut pairs = vec![];
+        successor_pairs(&nodes[a as usize], &nodes[b as usize], prev, &reals, &mut pairs);
+        let next = pairs.into_iter()
+            .filter_map(|(c, d)| distinguished_at(c, d).map(|k| (k, c, d)))
+            .filter(|&(k, _, _)| k < round)
+            .min();
+        match next {
+            Some((k, c, d)) => { round = k; a = c; b = d; }
+            None => return Some(lines),
+        }
+    }
+}
+
+/// Check whether state x of coalgebra A and state y of coalgebra B are behaviourally equivalent.
+/// Returns whether they are equivalent, and a witness if they are not and one is available.
+pub fn equiv(data_a: &[u8], r_a: &CReader, data_b: &[u8], r_b: &CReader, x: u32, y: u32) -> (bool, Option<Vec<String>>) {
+    let (data, r, num_a, num_b) = disjoint_union(data_a, r_a, data_b, r_b);
+    if x >= num_a { panic!("Initial state @{} out of range, the first file has {} states.", x, num_a) }
+    if y >= num_b { panic!("Initial state @{} out of range, the second file has {} states.", y, num_b) }
+    let y = y + num_a;
+    let ids = partref_nlogn(data.clone(), r.clone());
+    if ids[x as usize] == ids[y as usize] {
+        return (true, None);
+    }
+    return (false, witness(&data, &r, num_a, x, y));
+}
+
+#[test]
+fn test_equiv() {
+    // 0: List[0]{@0,@1}
+    // 1: List[0]{@1,@1}
+    // 2: List[1]{@0,@0}
+    // 3: List[1]{@0,@0}
+    // 4: List[1]{@3,@4}
+    let (data_a, r_a) = read_boa_txt("tests/test1.boa.txt");
+    let (data_b, r_b) = read_boa_txt("tests/test1.boa.txt");
+    assert!(equiv(&data_a, &r_a, &data_b, &r_b, 0, 1).0);
+    assert!(equiv(&data_a, &r_a, &data_b, &r_b, 2, 3).0);
+
+    let (same, witness) = equiv(&data_a, &r_a, &data_b, &r_b, 0, 2);
+    assert!(!same);
+    let witness = witness.unwrap();
+    assert_eq!(witness, vec!["a@0 = List[0]{@0,@1} and b@2 = List[1]{@0,@0} differ in their shape"]);
+
+    let (same, witness) = equiv(&data_a, &r_a, &data_b, &r_b, 4, 3);
+    assert!(!same);
+    assert_eq!(witness.unwrap().len(), 2);
+}
+
+#[test]
+fn test_disjoint_union() {
+    let (data_a, r_a) = read_boa_txt("tests/test2.boa.txt");
+    let (data_b, r_b) = read_boa_txt("tests/test1.boa.txt");
+    let (data, r, num_a, num_b) = disjoint_union(&data_a, &r_a, &data_b, &r_b);
+    assert_eq!((num_a, num_b), (6, 8));
+    let nodes = read_nodes(&data, &r);
+    assert_eq!(nodes.len(), 14);
+    assert_eq!(nodes[5], Node::from_ascii("List[0]{@4}".as_bytes()));
+    assert_eq!(nodes[6], Node::from_ascii("List[0]{@6,@7}".as_bytes()));
+    assert_eq!(nodes[13], Node::from_ascii("Add[0]{@6:2,@7:1}".as_bytes()));
+}
+
+#[test]
+fn test_disjoint_union_unused_tag_name() {
+    // A uses tags up to 4 and names tag 5 without using it, so the fresh tag for the name of B must not be 5
+    let write = |node: &str, names: &[(u32, &str)]| {
+        let mut w = CWriter::new();
+        Node::from_ascii(node.as_bytes()).write(&mut w);
+        w.meta.tag_names = names.iter().map(|&(t, name)| (t, name.to_string())).collect();
+        w.finish()
+    };
+    let (data_a, r_a) = write("List[4]{}", &[(5, "unused")]);
+    let (data_b, r_b) = write("List[0]{}", &[(0, "used")]);
+    let (data, r, _, _) = disjoint_union(&data_a, &r_a, &data_b, &r_b);
+    assert_eq!(r.meta.tag_names.get(&5).map(String::as_str), Some("unused"));
+    let used = r.meta.tag_names.iter().find(|(_, name)| *name == "used").map(|(&t, _)| t).unwrap();
+    assert!(used > 5);
+    assert_eq!(read_nodes(&data, &r)[1], Node::Coll(crate::binrep::LIST_TYP, used, vec![]));
+}
+
+#[test]
+fn test_equiv_tag_names() {
+    // A: List[send]{@1,@2}, List[recv]{@0}, List["time out"]{}, Tag[300]{@0:1,@3:2}
+    // B: r: List[recv]{@s}, s: List[send]{@r,@t}, List[0]{}, t: List["time out"]{}
+    // The names get different numbers in the two files, and B uses tag 0 as a number
+    let (data_a, r_a) = read_boa_txt("tests/names_test.boa.txt");
+    let (data_b, r_b) = read_boa_txt("tests/names_test2.boa.txt");
+    assert!(equiv(&data_a, &r_a, &data_b, &r_b, 0, 1).0);
+    assert!(equiv(&data_a, &r_a, &data_b, &r_b, 1, 0).0);
+    assert!(equiv(&data_a, &r_a, &data_b, &r_b, 2, 3).0);
+    assert!(!equiv(&data_a, &r_a, &data_b, &r_b, 2, 2).0);
+    let (same, witness) = equiv(&data_a, &r_a, &data_b, &r_b, 1, 1);
+    assert!(!same);
+    assert_eq!(witness.unwrap()[0], "a@1 = List[recv]{@0} and b@s = List[send]{@r,@t} differ in their shape");
+}
+
+#[test]
+fn test_equiv_label_names() {
+    // The same LTS with the transitions in a different order, so its labels get different numbers
+    use crate::io::read_aut;
+    let (data_a, r_a) = read_aut("tests/aut_test.aut");
+    let (data_b, r_b) = read_aut("tests/aut_test2.aut");
+    assert_ne!(r_a.meta.label_names, r_b.meta.label_names);
+    assert!(equiv(&data_a, &r_a, &data_b, &r_b, 0, 0).0);
+    assert!(equiv(&data_a, &r_a, &data_b, &r_b, 1, 2).0);
+    let (same, witness) = equiv(&data_a, &r_a, &data_b, &r_b, 0, 1);
+    assert!(!same);
+    assert_eq!(witness.unwrap()[0], "a@0 = Tag[0]{@1:a,@2:a} and b@1 = Tag[0]{@3:b} differ in their shape");
+}
+
+#[test]
+fn test_witness_unordered() {
+    // A: Set[0]{@1,@2}, List[0]{}, List[1]{}
+    // B: Set[0]{@1,@2}, List[1]{}, List[2]{}
+    // Only a@1 and b@2 have no equivalent successor on the other side, a@2 and b@1 are equivalent
+    let write = |nodes: &[&str]| {
+        let mut w = CWriter::new();
+        for node in nodes { Node::from_ascii(node.as_bytes()).write(&mut w) }
+        w.finish()
+    };
+    let (data_a, r_a) = write(&["Set[0]{@1,@2}", "List[0]{}", "List[1]{}"]);
+    let (data_b, r_b) = write(&["Set[0]{@1,@2}", "List[1]{}", "List[2]{}"]);
+    let (same, witness) = equiv(&data_a, &r_a, &data_b, &r_b, 0, 0);
+    assert!(!same);
+    let witness = witness.unwrap();
+    assert_eq!(witness.len(), 2);
+    assert_eq!(witness[1], "a@1 = List[0]{} and b@2 = List[2]{} differ in their shape");
+
+    // The successors of Tag nodes are paired by label, and of Add nodes by the blocks whose weights differ
+    let (data_a, r_a) = write(&["Tag[0]{@1:1,@2:2}", "List[0]{}", "List[1]{}", "Add[0]{@1:1,@2:2}"]);
+    let (data_b, r_b) = write(&["Tag[0]{@2:2,@1:1}", "List[0]{}", "List[2]{}", "Add[0]{@1:1,@2:2}"]);
+    let witness = equiv(&data_a, &r_a, &data_b, &r_b, 0, 0).1.unwrap();
+    assert_eq!(witness[1], "a@2 = List[1]{} and b@2 = List[2]{} differ in their shape");
+    let witness = equiv(&data_a, &r_a, &data_b, &r_b, 3, 3).1.unwrap();
+    assert_eq!(witness[1], "a@2 = List[1]{} and b@2 = List[2]{} differ in their shape");
+}
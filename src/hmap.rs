@@ -4,9 +4,11 @@
 
 // FxHash appears to be the winner.
 // Although AHash is a lot faster than the default hasher, I've found FxHash to be even faster.
-use fxhash::{FxHashMap, FxHasher64};
-pub fn new_hasher() -> FxHasher64 { FxHasher64::default() }
+use fxhash::{FxHashMap, FxHashSet, FxHasher64};
+pub type SigHasher = FxHasher64;
+pub fn new_hasher() -> SigHasher { SigHasher::default() }
 pub type HMap<K,V> = FxHashMap<K,V>;
+pub type HSet<K> = FxHashSet<K>;
 
 // use ahash::{AHasher, AHashMap};
 // fn new_hasher() -> AHasher { AHasher::default() }
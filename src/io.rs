@@ -1,12 +1,106 @@
-use std::io::{BufRead, BufWriter, Write, Read};
-use std::{path::Path, fs::File, io::BufReader};
+use std::io::{BufRead, BufWriter, Write, Read, Seek, SeekFrom};
+use std::{borrow::Cow, path::Path, fs::File, io::BufReader};
 
 use byteorder::{ReadBytesExt, LittleEndian, WriteBytesExt};
 
-use crate::binrep::{CReader, TAG_TYP};
+use crate::binrep::{CReader, Meta, Observation, TAG_TYP};
 use crate::binrep::CWriter;
 use crate::binrep::Node;
 use crate::hmap::HMap;
+use crate::copar::read_copar;
+use crate::prism::{read_prism, write_prism};
+use crate::drn::{read_drn, write_drn};
+use crate::kiss::{read_kiss2, write_kiss2};
+use crate::timbuk::read_timbuk;
+use crate::parsing::{Names, brace_depth, read_definition, read_shared_definition, skip_ws, write_name};
+
+/// Call f on each state or shared node definition of a .boa.txt file, which is normally a single line.
+/// A node that is split over several lines is joined until its braces are balanced,
+/// and lines with only whitespace and comments are skipped.
+/// Stops and returns false as soon as f returns false.
+fn for_each_statement<R, F>(reader: &mut R, mut f: F) -> bool
+where R: BufRead, F: FnMut(&[u8]) -> bool {
+    let mut line = vec![];
+    let mut stmt = vec![];
+    let mut depth = 0;
+    while 0 < reader.read_until(b'\n', &mut line).expect("Failure while reading file.") {
+        if stmt.is_empty() {
+            if skip_ws(&line).is_empty() {
+                line.clear();
+                continue;
+            }
+            depth = brace_depth(&line);
+            // the common case of a node on a single line is passed on without copying
+            if depth <= 0 && !is_bare_definition(&line) {
+                if !f(&line) { return false }
+                line.clear();
+                continue;
+            }
+        } else {
+            depth += brace_depth(&line);
+        }
+        stmt.extend_from_slice(&line);
+        if depth <= 0 && !is_bare_definition(&stmt) {
+            if !f(&stmt) { return false }
+            stmt.clear();
+        }
+        line.clear();
+    }
+    if !stmt.is_empty() {
+        panic!("Unexpected end of file in {:?}, the braces are not balanced.", String::from_utf8_lossy(&stmt));
+    }
+    return true;
+}
+
+/// A name followed by ':' or a shared node followed by '=' without a node, so the node is on the next line
+fn is_bare_definition(stmt: &[u8]) -> bool {
+    // a complete node ends with '}', a state or a number, so only a line ending with ':' or '=' or a comment needs a closer look
+    match stmt.iter().rev().find(|c| !c.is_ascii_whitespace()) {
+        Some(b':' | b'=') => {},
+        _ => if !stmt.contains(&b'#') { return false },
+    }
+    if let Some((_, rest)) = read_shared_definition(stmt) { return rest.is_empty() }
+    let (name, rest) = read_definition(stmt);
+    return name.is_some() && rest.is_empty();
+}
+
+/// Read the statements of a .boa.txt file, resolving names with names.
+/// Returns None if names are numeric_only() and the file turns out to have names.
+fn read_statements<R: BufRead>(reader: &mut R, names: &mut Names) -> Option<(Vec<u8>,CReader)> {
+    let mut w = CWriter::new();
+    // only the named states are recorded, since machine generated files have many states and no names
+    let mut state_names = vec![];
+    let mut num_states = 0;
+    let complete = for_each_statement(reader, |stmt| {
+        let (shared, name, rest) = match read_shared_definition(stmt) {
+            Some((id, rest)) => (Some(id), None, rest),
+            None => { let (name, rest) = read_definition(stmt); (None, name, rest) },
+        };
+        if name.is_some() && names.is_numeric_only() { return false }
+        let node = Node::from_ascii_named(rest, names);
+        if let Some(id) = shared {
+            // a shared node can only refer to shared nodes defined before it, so they can't be cyclic
+            let k = names.define_shared(id);
+            debug_assert_eq!(k, w.shared_locs.len() as u32);
+            w.write_shared(|w| node.write(w));
+        } else {
+            node.write(&mut w);
+            if let Some(name) = name {
+                state_names.push((num_states, String::from_utf8(name).expect("State name is not valid UTF-8.")));
+            }
+            num_states += 1;
+        }
+        return !names.must_restart();
+    });
+    if !complete { return None }
+    w.meta.tag_names = names.names();
+    w.meta.label_names = names.label_names();
+    if !state_names.is_empty() {
+        w.meta.state_names = vec![None; num_states];
+        for (state, name) in state_names { w.meta.state_names[state] = Some(name) }
+    }
+    Some(w.finish())
+}
 
 pub fn read_boa_txt<P>(filename: P) -> (Vec<u8>,CReader)
 where P: AsRef<Path>, {
@@ -17,14 +111,22 @@ where P: AsRef<Path>, {
     let file = File::open(&filename).
         expect(&format!("Couldn't open file {:?}", filename.as_ref().display().to_string()));
     let mut reader = BufReader::new(file);
-    let mut line = vec![];
-    let mut w = CWriter::new();
-    while 0 < reader.read_until(b'\n', &mut line).expect("Failure while reading file.") {
-        let node = Node::from_ascii(&line);
-        node.write(&mut w);
-        line.clear();
-    }
-    w.finish()
+    // Machine generated files have no names, so they are read in a single pass
+    if let Some(result) = read_statements(&mut reader, &mut Names::numeric_only()) { return result }
+    // Otherwise a first pass finds the tags that are used as numbers, so that symbolic tags get different ones,
+    // and the names of the states, so that they can be referred to before their definition
+    reader.seek(SeekFrom::Start(0)).expect("Failure while reading file.");
+    let mut names = Names::new();
+    let mut state = 0;
+    for_each_statement(&mut reader, |stmt| {
+        names.reserve_numeric(stmt);
+        if read_shared_definition(stmt).is_some() { return true }
+        if let (Some(name), _) = read_definition(stmt) { names.define_state(&name, state) }
+        state += 1;
+        return true;
+    });
+    reader.seek(SeekFrom::Start(0)).expect("Failure while reading file.");
+    return read_statements(&mut reader, &mut names).expect("Names are resolved after the first pass.");
 }
 
 pub fn create_file<P>(filename: P) -> File
@@ -45,10 +147,24 @@ where P: AsRef<Path>, {
     let mut writer = BufWriter::new(file);
     let mut buf = vec![];
     unsafe {
+        for k in 0..r.shared_locs.len() {
+            let node = Node::read_raw(r, &mut r.shared_node(k as u64));
+            write!(buf, "${} = ", k).unwrap();
+            node.to_ascii_named(&r.meta, &mut buf);
+            buf.push(b'\n');
+            writer.write_all(&buf).unwrap();
+            buf.clear();
+        }
         let mut p = data.as_ptr();
+        let mut state = 0;
         while !CReader::is_at_end(data, p) {
-            let node = Node::read(r, &mut p);
-            node.to_ascii(&mut buf);
+            let node = Node::read_raw(r, &mut p);
+            if let Some(name) = r.meta.state_name(state) {
+                write_name(name, &mut buf);
+                buf.extend(b": ");
+            }
+            node.to_ascii_named(&r.meta, &mut buf);
+            state += 1;
             if !CReader::is_at_end(data, p) { buf.push(b'\n') };
             writer.write_all(&buf).unwrap();
             buf.clear();
@@ -56,6 +172,209 @@ where P: AsRef<Path>, {
     }
 }
 
+// A .boa file consists of the 128 entries of the header dictionary (u32), the 128 entries of the value dictionary (u64),
+// and then the data. If the coalgebra has metadata, such as tag names, or shared nodes, the file is prefixed with META_MAGIC
+// and a sequence of sections. Each section is a u32 kind and a u64 length, followed by that many bytes, and the sequence
+// ends with a section of kind END_SECTION. Unknown sections are skipped.
+// The magic can't be confused with the first header dictionary entry, which is 0 or has its lowest bit set.
+const META_MAGIC: u32 = u32::from_le_bytes(*b"BOA+");
+const END_SECTION: u32 = 0;
+const TAG_NAMES_SECTION: u32 = 1; // u32 count, then for each name a u32 tag, a u32 length and the UTF-8 bytes
+const STATE_NAMES_SECTION: u32 = 2; // u32 count, then for each state a u32 length (NO_NAME if it has none) and the UTF-8 bytes
+const NO_NAME: u32 = u32::MAX;
+const SHARED_SECTION: u32 = 3; // u64 count, then the u64 start of each shared node in the shared data, then the shared data
+const LABEL_NAMES_SECTION: u32 = 4; // u32 count, then for each name a u64 label, a u32 length and the UTF-8 bytes
+const INITIAL_SECTION: u32 = 5; // u32 initial state
+// The model type as a string, the reward model names as a list of strings, and a u32 count of observations, each of which
+// is a u32 tag, a u8 kind and then the labels, rewards and action rewards of a state, or the name and rewards of an action.
+// A string is a u32 length (NO_NAME if there is none) and the UTF-8 bytes, and a list of strings is a u32 count and the strings.
+const MODEL_SECTION: u32 = 6;
+const STATE_OBSERVATION: u8 = 0;
+const ACTION_OBSERVATION: u8 = 1;
+
+fn write_string(section: &mut Vec<u8>, s: Option<&str>) {
+    match s {
+        Some(s) => {
+            section.write_u32::<LittleEndian>(s.len() as u32).unwrap();
+            section.extend(s.as_bytes());
+        }
+        None => section.write_u32::<LittleEndian>(NO_NAME).unwrap(),
+    }
+}
+
+fn write_strings(section: &mut Vec<u8>, strings: &[String]) {
+    section.write_u32::<LittleEndian>(strings.len() as u32).unwrap();
+    for s in strings { write_string(section, Some(s)) }
+}
+
+fn read_string(p: &mut &[u8]) -> Option<String> {
+    let err = "File reading error.";
+    let n = p.read_u32::<LittleEndian>().expect(err);
+    if n == NO_NAME { return None }
+    let s = String::from_utf8(p.get(..n as usize).expect(err).to_vec()).expect("String is not valid UTF-8.");
+    *p = &p[n as usize..];
+    return Some(s);
+}
+
+fn read_strings(p: &mut &[u8]) -> Vec<String> {
+    let count = p.read_u32::<LittleEndian>().expect("File reading error.");
+    (0..count).map(|_| read_string(p).expect("Missing string in list.")).collect()
+}
+
+fn write_section<W: Write>(writer: &mut W, kind: u32, section: &[u8]) {
+    writer.write_u32::<LittleEndian>(kind).expect("Writing error.");
+    writer.write_u64::<LittleEndian>(section.len() as u64).expect("Writing error.");
+    writer.write_all(section).expect("Writing error.");
+}
+
+fn write_sections<W: Write>(writer: &mut W, r: &CReader) {
+    let meta = &r.meta;
+    writer.write_u32::<LittleEndian>(META_MAGIC).expect("Writing error.");
+    if !meta.tag_names.is_empty() {
+        let mut section = vec![];
+        section.write_u32::<LittleEndian>(meta.tag_names.len() as u32).unwrap();
+        for (tag, name) in &meta.tag_names {
+            section.write_u32::<LittleEndian>(*tag).unwrap();
+            section.write_u32::<LittleEndian>(name.len() as u32).unwrap();
+            section.extend(name.as_bytes());
+        }
+        write_section(writer, TAG_NAMES_SECTION, &section);
+    }
+    if !meta.state_names.is_empty() {
+        let mut section = vec![];
+        section.write_u32::<LittleEndian>(meta.state_names.len() as u32).unwrap();
+        for name in &meta.state_names {
+            match name {
+                Some(name) => {
+                    section.write_u32::<LittleEndian>(name.len() as u32).unwrap();
+                    section.extend(name.as_bytes());
+                }
+                None => section.write_u32::<LittleEndian>(NO_NAME).unwrap(),
+            }
+        }
+        write_section(writer, STATE_NAMES_SECTION, &section);
+    }
+    if !meta.label_names.is_empty() {
+        let mut section = vec![];
+        section.write_u32::<LittleEndian>(meta.label_names.len() as u32).unwrap();
+        for (label, name) in &meta.label_names {
+            section.write_u64::<LittleEndian>(*label).unwrap();
+            section.write_u32::<LittleEndian>(name.len() as u32).unwrap();
+            section.extend(name.as_bytes());
+        }
+        write_section(writer, LABEL_NAMES_SECTION, &section);
+    }
+    if let Some(initial) = meta.initial {
+        write_section(writer, INITIAL_SECTION, &initial.to_le_bytes());
+    }
+    if meta.model_type.is_some() || !meta.reward_models.is_empty() || !meta.observations.is_empty() {
+        let mut section = vec![];
+        write_string(&mut section, meta.model_type.as_deref());
+        write_strings(&mut section, &meta.reward_models);
+        section.write_u32::<LittleEndian>(meta.observations.len() as u32).unwrap();
+        for (tag, observation) in &meta.observations {
+            section.write_u32::<LittleEndian>(*tag).unwrap();
+            match observation {
+                Observation::State { labels, rewards, action_rewards } => {
+                    section.push(STATE_OBSERVATION);
+                    write_strings(&mut section, labels);
+                    write_strings(&mut section, rewards);
+                    write_strings(&mut section, action_rewards);
+                }
+                Observation::Action { name, rewards } => {
+                    section.push(ACTION_OBSERVATION);
+                    write_string(&mut section, name.as_deref());
+                    write_strings(&mut section, rewards);
+                }
+            }
+        }
+        write_section(writer, MODEL_SECTION, &section);
+    }
+    if !r.shared_locs.is_empty() {
+        let mut section = vec![];
+        section.write_u64::<LittleEndian>(r.shared_locs.len() as u64).unwrap();
+        for &loc in &r.shared_locs {
+            section.write_u64::<LittleEndian>(loc as u64).unwrap();
+        }
+        section.extend(&r.shared);
+        write_section(writer, SHARED_SECTION, &section);
+    }
+    writer.write_u32::<LittleEndian>(END_SECTION).expect("Writing error.");
+    writer.write_u64::<LittleEndian>(0).expect("Writing error.");
+}
+
+/// Read the sections following META_MAGIC into r
+fn read_sections<R: Read>(reader: &mut R, r: &mut CReader) {
+    let err = "File reading error.";
+    let meta = &mut r.meta;
+    loop {
+        let kind = reader.read_u32::<LittleEndian>().expect(err);
+        let len = reader.read_u64::<LittleEndian>().expect(err);
+        if kind == END_SECTION { return }
+        let mut section = vec![0; len as usize];
+        reader.read_exact(&mut section).expect(err);
+        if kind == TAG_NAMES_SECTION {
+            let mut p = &section[..];
+            let count = p.read_u32::<LittleEndian>().expect(err);
+            for _ in 0..count {
+                let tag = p.read_u32::<LittleEndian>().expect(err);
+                let n = p.read_u32::<LittleEndian>().expect(err) as usize;
+                let name = String::from_utf8(p.get(..n).expect(err).to_vec()).expect("Tag name is not valid UTF-8.");
+                p = &p[n..];
+                meta.tag_names.insert(tag, name);
+            }
+        } else if kind == STATE_NAMES_SECTION {
+            let mut p = &section[..];
+            let count = p.read_u32::<LittleEndian>().expect(err);
+            for _ in 0..count {
+                let n = p.read_u32::<LittleEndian>().expect(err);
+                if n == NO_NAME {
+                    meta.state_names.push(None);
+                    continue;
+                }
+                let n = n as usize;
+                let name = String::from_utf8(p.get(..n).expect(err).to_vec()).expect("State name is not valid UTF-8.");
+                p = &p[n..];
+                meta.state_names.push(Some(name));
+            }
+        } else if kind == LABEL_NAMES_SECTION {
+            let mut p = &section[..];
+            let count = p.read_u32::<LittleEndian>().expect(err);
+            for _ in 0..count {
+                let label = p.read_u64::<LittleEndian>().expect(err);
+                let n = p.read_u32::<LittleEndian>().expect(err) as usize;
+                let name = String::from_utf8(p.get(..n).expect(err).to_vec()).expect("Label name is not valid UTF-8.");
+                p = &p[n..];
+                meta.label_names.insert(label, name);
+            }
+        } else if kind == INITIAL_SECTION {
+            meta.initial = Some((&section[..]).read_u32::<LittleEndian>().expect(err));
+        } else if kind == MODEL_SECTION {
+            let mut p = &section[..];
+            meta.model_type = read_string(&mut p);
+            meta.reward_models = read_strings(&mut p);
+            let count = p.read_u32::<LittleEndian>().expect(err);
+            for _ in 0..count {
+                let tag = p.read_u32::<LittleEndian>().expect(err);
+                let observation = match p.read_u8().expect(err) {
+                    STATE_OBSERVATION => Observation::State { labels: read_strings(&mut p), rewards: read_strings(&mut p), action_rewards: read_strings(&mut p) },
+                    ACTION_OBSERVATION => Observation::Action { name: read_string(&mut p), rewards: read_strings(&mut p) },
+                    kind => panic!("Unknown observation kind {}.", kind),
+                };
+                meta.observations.insert(tag, observation);
+            }
+        } else if kind == SHARED_SECTION {
+            let mut p = &section[..];
+            let count = p.read_u64::<LittleEndian>().expect(err);
+            for _ in 0..count {
+                r.shared_locs.push(p.read_u64::<LittleEndian>().expect(err) as usize);
+            }
+            r.shared = p.to_vec();
+            if r.shared_locs.iter().any(|&loc| loc >= r.shared.len()) { panic!("Shared node out of range.") }
+        }
+    }
+}
+
 pub fn read_boa<P>(filename: P) -> (Vec<u8>,CReader)
 where P: AsRef<Path>, {
     let filename_str = filename.as_ref().display().to_string();
@@ -64,8 +383,14 @@ where P: AsRef<Path>, {
     }
     let mut file = File::open(&filename).
         expect(&format!("Couldn't open file {:?}", filename.as_ref().display().to_string()));
-    let mut r = CReader { headers: [0;128], values: [0;128] };
-    for i in 0..r.headers.len() {
+    let mut r = CReader::new();
+    let mut first = file.read_u32::<LittleEndian>().expect("File reading error.");
+    if first == META_MAGIC {
+        read_sections(&mut file, &mut r);
+        first = file.read_u32::<LittleEndian>().expect("File reading error.");
+    }
+    r.headers[0] = first;
+    for i in 1..r.headers.len() {
         r.headers[i] = file.read_u32::<LittleEndian>().expect("File reading error.");
     }
     for i in 0..r.values.len() {
@@ -85,6 +410,9 @@ where P: AsRef<Path>, {
     }
     let file = create_file(filename);
     let mut writer = BufWriter::new(file);
+    if !r.meta.is_empty() || !r.shared_locs.is_empty() {
+        write_sections(&mut writer, r);
+    }
     for header in r.headers {
         writer.write_u32::<LittleEndian>(header).expect("Writing error.");
     }
@@ -94,6 +422,91 @@ where P: AsRef<Path>, {
     writer.write_all(data).expect("Writing error.");
 }
 
+/// Write a partition, given as the block of each state, with one line per block listing its states.
+/// States are shown by name if they have one.
+pub fn write_partition<P>(filename: P, ids: &[u32], meta: &Meta)
+where P: AsRef<Path>, {
+    let num_blocks = ids.iter().max().map_or(0, |&m| m as usize + 1);
+    let mut blocks: Vec<Vec<u32>> = vec![vec![]; num_blocks];
+    for (state, &block) in ids.iter().enumerate() { blocks[block as usize].push(state as u32) }
+    let file = create_file(filename);
+    let mut writer = BufWriter::new(file);
+    for block in blocks {
+        let line = block.iter().map(|&s| meta.state_ref(s)).collect::<Vec<_>>().join(" ");
+        writeln!(writer, "{}", line).expect("Writing error.");
+    }
+}
+
+/// Read a partition as written by write_partition, returning the block of each of the num_states states.
+/// The blocks are numbered in the order of the lines, and blank lines are skipped.
+pub fn read_partition<P>(filename: P, num_states: usize, meta: &Meta) -> Vec<u32>
+where P: AsRef<Path>, {
+    let file = File::open(&filename).
+        expect(&format!("Couldn't open file {:?}", filename.as_ref().display().to_string()));
+    let mut ids = vec![u32::MAX; num_states];
+    let mut block = 0;
+    for line in BufReader::new(file).lines() {
+        let line = line.expect("Failure while reading file.");
+        if line.trim().is_empty() { continue }
+        for s in line.split_ascii_whitespace() {
+            let state = meta.find_state(s).unwrap_or_else(|| panic!("Unknown state {} in the partition.", s));
+            let id = ids.get_mut(state as usize).unwrap_or_else(|| panic!("State {} of the partition out of range.", s));
+            if *id != u32::MAX { panic!("State {} is in more than one block of the partition.", s) }
+            *id = block;
+        }
+        block += 1;
+    }
+    if let Some(state) = ids.iter().position(|&id| id == u32::MAX) {
+        panic!("State {} is in no block of the partition.", meta.state_ref(state as u32));
+    }
+    return ids;
+}
+
+/// Parse the header `des (initial, transitions, states)` of an .aut file
+fn read_aut_header(line: &[u8]) -> Option<(u32, usize, u32)> {
+    let rest = line.trim_ascii().strip_prefix(b"des")?.trim_ascii_start().strip_prefix(b"(")?.strip_suffix(b")")?;
+    let mut parts = rest.split(|&c| c == b',').map(|part| part.trim_ascii());
+    let initial = lexical::parse(parts.next()?).ok()?;
+    let num_edges = lexical::parse(parts.next()?).ok()?;
+    let num_states = lexical::parse(parts.next()?).ok()?;
+    if parts.next().is_some() { return None }
+    return Some((initial, num_edges, num_states));
+}
+
+/// Parse a transition `(source, label, target)`, returning the label without quotes.
+/// The label is everything between the first and the last comma, so a quoted label can contain commas and parentheses.
+/// A quoted label may contain quotes, escaped as \" by write_aut, and backslashes escaped as \\.
+fn read_aut_transition(line: &[u8]) -> Option<(u32, Cow<'_, [u8]>, u32)> {
+    let rest = line.trim_ascii().strip_prefix(b"(")?.strip_suffix(b")")?;
+    let first = rest.iter().position(|&c| c == b',')?;
+    let last = rest.iter().rposition(|&c| c == b',')?;
+    if first == last { return None }
+    let source = lexical::parse(rest[..first].trim_ascii()).ok()?;
+    let target = lexical::parse(rest[last+1..].trim_ascii()).ok()?;
+    let label = rest[first+1..last].trim_ascii();
+    let label = match label.strip_prefix(b"\"") {
+        Some(quoted) => unescape_aut_label(quoted.strip_suffix(b"\"")?),
+        None if label.is_empty() || label.contains(&b'"') => return None,
+        None => Cow::Borrowed(label),
+    };
+    return Some((source, label, target));
+}
+
+/// Replace \" by " and \\ by \ in a quoted label, keeping other backslashes
+fn unescape_aut_label(label: &[u8]) -> Cow<'_, [u8]> {
+    if !label.contains(&b'\\') { return Cow::Borrowed(label) }
+    let mut unescaped = Vec::with_capacity(label.len());
+    let mut i = 0;
+    while i < label.len() {
+        if label[i] == b'\\' && matches!(label.get(i+1), Some(b'"') | Some(b'\\')) { i += 1 }
+        unescaped.push(label[i]);
+        i += 1;
+    }
+    return Cow::Owned(unescaped);
+}
+
+/// Read an .aut file into a coalgebra of Tag nodes, keeping the names of the labels and the initial state.
+/// Panics with the line number on malformed lines, and if the number of transitions differs from the header.
 pub fn read_aut<P>(filename: P) -> (Vec<u8>,CReader)
 where P: AsRef<Path>, {
     let filename_str = filename.as_ref().display().to_string();
@@ -104,14 +517,17 @@ where P: AsRef<Path>, {
         expect(&format!("Couldn't open file {:?}", filename.as_ref().display().to_string()));
     let mut reader = BufReader::new(file);
     let mut line = vec![];
-    let _n = reader.read_until(b'\n', &mut line).expect("Failure while reading file.");
-    debug_assert!(&line[0..8] == [b'd', b'e', b's', b' ', b'(', b'0', b',', b' ']);
-    let line = &line[8..];
-    let (_num_edges,m) = lexical::parse_partial::<u32,_>(&line).expect("Expected a number in aut header (1).");
-    let line = &line[m..];
-    debug_assert!(&line[0..2] == [b',', b' ']);
-    let line = &line[2..];
-    let (num_states,_m2) = lexical::parse_partial::<u32,_>(&line).expect("Expected a number in aut header (2).");
+    let mut line_number = 0;
+    // the header is the first line that isn't blank
+    while line.trim_ascii().is_empty() {
+        line.clear();
+        line_number += 1;
+        if reader.read_until(b'\n', &mut line).expect("Failure while reading file.") == 0 { panic!("Missing .aut header.") }
+    }
+    let (initial, num_edges, num_states) = read_aut_header(&line).unwrap_or_else(|| {
+        panic!("Expected the header des (initial, transitions, states) in line {}, got {:?}.", line_number, String::from_utf8_lossy(&line))
+    });
+    if initial >= num_states && num_states > 0 { panic!("Initial state {} out of range, the header declares {} states.", initial, num_states) }
 
     let mut states : Vec<Vec<(u64,u32)>> = vec![];
     for _ in 0..num_states { states.push(vec![]); }
@@ -119,29 +535,34 @@ where P: AsRef<Path>, {
     let mut label_counter = 0;
     let mut label_map : HMap<Vec<u8>,u64> = HMap::default();
 
-    let mut line = vec![];
+    let mut edges = 0;
+    line.clear();
     while 0 < reader.read_until(b'\n', &mut line).expect("Failure while reading file.") {
-        let (source,m1) = lexical::parse_partial::<u32,_>(&line[1..]).expect("Expected a number in aut body.");
-        line.reverse();
-        let (_target,m2) = lexical::parse_partial::<u32,_>(&line[2..]).expect("Expected a number in aut body.");
-        line.reverse();
-        let label_start = 1+m1;
-        let label_end = line.len() - 2 - m2;
-        let (target,_m2) = lexical::parse_partial::<u32,_>(&line[label_end..]).expect("Expected a number in aut body.");
-        let label_str = Vec::from(&line[label_start..label_end]);
-        // println!("label: {}", &String::from_utf8(label_str.clone()).expect("UTF8 ERROR"));
-        // Parse label
-        let label = if label_map.contains_key(&label_str) {
-                label_map[&label_str]
-            }else{
-                label_map.insert(label_str, label_counter);
+        line_number += 1;
+        if line.trim_ascii().is_empty() {
+            line.clear();
+            continue;
+        }
+        let (source, label_str, target) = read_aut_transition(&line).unwrap_or_else(|| {
+            panic!("Expected a transition (source, label, target) in line {}, got {:?}.", line_number, String::from_utf8_lossy(&line))
+        });
+        if source >= num_states || target >= num_states {
+            panic!("State out of range in line {}, the header declares {} states.", line_number, num_states);
+        }
+        let label = match label_map.get(label_str.as_ref()) {
+            Some(&label) => label,
+            None => {
+                label_map.insert(label_str.into_owned(), label_counter);
                 label_counter += 1;
                 label_counter-1
-            };
+            }
+        };
 
         states[source as usize].push((label,target));
+        edges += 1;
         line.clear();
     }
+    if edges != num_edges { panic!("The header declares {} transitions, but the file has {}.", num_edges, edges) }
 
     let mut w = CWriter::new();
 
@@ -150,32 +571,583 @@ where P: AsRef<Path>, {
         let node = Node::Mon(TAG_TYP, 0, trans);
         node.write(&mut w);
     }
+    w.meta.label_names = label_map.into_iter()
+        .map(|(name, label)| (label, String::from_utf8(name).expect("Label is not valid UTF-8.")))
+        .collect();
+    if initial != 0 { w.meta.initial = Some(initial) }
 
     w.finish()
 }
 
-pub fn convert_file(filename: &str) {
+/// Write a coalgebra of Tag nodes whose elements are states as an .aut file, with initial state 0 unless it has another one.
+/// The labels are written by name if they have one, with quotes and backslashes escaped.
+/// The tags of the nodes are not written, as .aut has no state labels.
+pub fn write_aut<P>(filename: P, data: &[u8], r: &CReader)
+where P: AsRef<Path>, {
+    let filename_str = filename.as_ref().display().to_string();
+    if !filename_str.ends_with(".aut") {
+        panic!("File must be *.aut, but is {}", filename_str);
+    }
+    let nodes = crate::binrep::read_nodes(data, r);
+    let mut lines = vec![];
+    for (s, node) in nodes.iter().enumerate() {
+        let Node::Mon(TAG_TYP, _, trans) = node else { panic!("Writing .aut requires Tag nodes.") };
+        for (target, label) in trans {
+            let Node::State(t) = target else { panic!("Writing .aut requires the elements of Tag nodes to be states.") };
+            let label = r.meta.label_names.get(label).cloned().unwrap_or_else(|| label.to_string());
+            lines.push(format!("({}, \"{}\", {})", s, label.replace('\\', "\\\\").replace('"', "\\\""), t));
+        }
+    }
+    let file = create_file(filename);
+    let mut writer = BufWriter::new(file);
+    writeln!(writer, "des ({}, {}, {})", r.meta.initial.unwrap_or(0), lines.len(), nodes.len()).expect("Writing error.");
+    for line in lines { writeln!(writer, "{}", line).expect("Writing error.") }
+}
+
+/// A token of a DOT file, with its line number
+#[derive(Debug, PartialEq)]
+enum DotToken {
+    Id(String), // identifier, number or quoted string
+    Arrow,
+    Sym(u8), // one of { } [ ] = ; ,
+}
+
+/// Split a DOT file into tokens, skipping comments
+fn dot_tokens(text: &str) -> Vec<(DotToken, usize)> {
+    let bytes = text.as_bytes();
+    let mut tokens = vec![];
+    let mut line = 1;
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        if c == b'\n' { line += 1 }
+        if c.is_ascii_whitespace() {
+            i += 1;
+        } else if bytes[i..].starts_with(b"//") || (c == b'#' && (i == 0 || bytes[i-1] == b'\n')) {
+            while i < bytes.len() && bytes[i] != b'\n' { i += 1 }
+        } else if bytes[i..].starts_with(b"/*") {
+            let end = text[i+2..].find("*/").unwrap_or_else(|| panic!("Unterminated comment in line {}.", line));
+            line += text[i..i+2+end].matches('\n').count();
+            i += end + 4;
+        } else if bytes[i..].starts_with(b"->") {
+            tokens.push((DotToken::Arrow, line));
+            i += 2;
+        } else if b"{}[]=;,".contains(&c) {
+            tokens.push((DotToken::Sym(c), line));
+            i += 1;
+        } else if c == b'"' {
+            let start_line = line;
+            let mut id = String::new();
+            let mut chars = text[i+1..].char_indices();
+            loop {
+                match chars.next() {
+                    None => panic!("Unterminated string in line {}.", start_line),
+                    Some((j, '"')) => { i += j + 2; break }
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, '"')) => id.push('"'),
+                        Some((_, '\n')) => line += 1, // line continuation
+                        Some((_, c)) => { id.push('\\'); id.push(c) }
+                        None => panic!("Unterminated string in line {}.", start_line),
+                    },
+                    Some((_, c)) => {
+                        if c == '\n' { line += 1 }
+                        id.push(c)
+                    }
+                }
+            }
+            tokens.push((DotToken::Id(id), start_line));
+        } else if c.is_ascii_alphanumeric() || c == b'_' || c == b'.' || c == b'-' || c >= 0x80 {
+            let n = text[i..].find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.' || c == '-')).unwrap_or(text.len() - i);
+            // a->b is an edge, not the identifier a-
+            let n = if text[i+n..].starts_with('>') && text[i..i+n].ends_with('-') { n - 1 } else { n };
+            if n == 0 { panic!("Unexpected character in line {}.", line) }
+            tokens.push((DotToken::Id(text[i..i+n].to_string()), line));
+            i += n;
+        } else if c == b'<' {
+            panic!("HTML labels are not supported, in line {}.", line);
+        } else {
+            panic!("Unexpected character {:?} in line {}.", c as char, line);
+        }
+    }
+    return tokens;
+}
+
+/// Read an attribute list [k1=v1, k2=v2] starting at tokens[i], returning the attributes and the index after it
+fn dot_attributes(tokens: &[(DotToken, usize)], mut i: usize) -> (Vec<(&str, &str)>, usize) {
+    let mut attrs = vec![];
+    while let Some((DotToken::Sym(b'['), _)) = tokens.get(i) {
+        i += 1;
+        loop {
+            match &tokens.get(i) {
+                Some((DotToken::Sym(b']'), _)) => { i += 1; break }
+                Some((DotToken::Sym(b',' | b';'), _)) => i += 1,
+                Some((DotToken::Id(key), line)) => {
+                    match (&tokens.get(i+1), &tokens.get(i+2)) {
+                        (Some((DotToken::Sym(b'='), _)), Some((DotToken::Id(value), _))) => {
+                            attrs.push((key.as_str(), value.as_str()));
+                            i += 3;
+                        }
+                        _ => panic!("Expected {}=value in line {}.", key, line),
+                    }
+                }
+                Some((_, line)) => panic!("Malformed attribute list in line {}.", line),
+                None => panic!("Unterminated attribute list."),
+            }
+        }
+    }
+    return (attrs, i);
+}
+
+/// Read a DOT file of an LTS, DFA or Mealy machine, as written by automata learning tools like LearnLib and AALpy.
+/// Each node becomes a state named after its identifier, and each edge s -> t [label="a/1"] a transition of a Tag node,
+/// with label a/1, where spaces around the / are removed. For a DFA the tag of a Tag node is 1 (named accepting)
+/// if its node has shape doublecircle, and 0 otherwise. Nodes named __start*, which have no shape, only point to
+/// the initial state. Subgraphs and HTML labels are not supported.
+pub fn read_dot<P>(filename: P) -> (Vec<u8>,CReader)
+where P: AsRef<Path>, {
+    let filename_str = filename.as_ref().display().to_string();
+    if !filename_str.ends_with(".dot") && !filename_str.ends_with(".gv") {
+        panic!("File must be *.dot or *.gv, but is {}", filename_str);
+    }
+    let text = std::fs::read_to_string(&filename).
+        expect(&format!("Couldn't open file {:?}", filename.as_ref().display().to_string()));
+    let tokens = dot_tokens(&text);
+
+    let mut ids: HMap<String, u32> = HMap::default();
+    let mut names: Vec<String> = vec![];
+    let mut accepting: Vec<bool> = vec![];
+    let mut trans: Vec<Vec<(u64, u32)>> = vec![];
+    let mut label_map: HMap<String, u64> = HMap::default();
+    let mut initial = None;
+    let is_start = |id: &str| id.starts_with("__start");
+    let mut state = |id: &str, names: &mut Vec<String>, accepting: &mut Vec<bool>, trans: &mut Vec<Vec<(u64, u32)>>| -> u32 {
+        *ids.entry(id.to_string()).or_insert_with(|| {
+            names.push(id.to_string());
+            accepting.push(false);
+            trans.push(vec![]);
+            names.len() as u32 - 1
+        })
+    };
+
+    // header: [strict] (digraph|graph) [name] {
+    let mut i = 0;
+    while let Some((DotToken::Id(_), _)) = tokens.get(i) { i += 1 }
+    match tokens.get(i) {
+        Some((DotToken::Sym(b'{'), _)) => i += 1,
+        Some((_, line)) => panic!("Expected digraph name {{ in line {}.", line),
+        None => panic!("Empty DOT file."),
+    }
+    while i < tokens.len() {
+        let (token, line) = &tokens[i];
+        match token {
+            DotToken::Sym(b';') => i += 1,
+            DotToken::Sym(b'}') => break,
+            DotToken::Id(id) if matches!(id.as_str(), "node" | "edge" | "graph") && tokens.get(i+1).is_some_and(|t| t.0 == DotToken::Sym(b'[')) => {
+                i = dot_attributes(&tokens, i+1).1;
+            }
+            DotToken::Id(_) if tokens.get(i+1).is_some_and(|t| t.0 == DotToken::Sym(b'=')) => {
+                i += 3; // graph attribute like rankdir=LR
+            }
+            DotToken::Id(id) => {
+                // a node, or a chain of edges a -> b -> c
+                let mut chain = vec![id.as_str()];
+                i += 1;
+                while let Some((DotToken::Arrow, line)) = tokens.get(i) {
+                    match tokens.get(i+1) {
+                        Some((DotToken::Id(id), _)) => chain.push(id.as_str()),
+                        _ => panic!("Expected a node after -> in line {}.", line),
+                    }
+                    i += 2;
+                }
+                let (attrs, next) = dot_attributes(&tokens, i);
+                i = next;
+                let attr = |key: &str| attrs.iter().rev().find(|(k, _)| *k == key).map(|(_, v)| *v);
+                if chain.len() == 1 {
+                    if is_start(id) { continue }
+                    let s = state(id, &mut names, &mut accepting, &mut trans);
+                    if let Some(shape) = attr("shape") { accepting[s as usize] = shape == "doublecircle" }
+                    continue;
+                }
+                let label = match attr("label").unwrap_or("").split_once('/') {
+                    Some((input, output)) => format!("{}/{}", input.trim(), output.trim()),
+                    None => attr("label").unwrap_or("").trim().to_string(),
+                };
+                for pair in chain.windows(2) {
+                    let t = state(pair[1], &mut names, &mut accepting, &mut trans);
+                    if is_start(pair[0]) {
+                        if initial.is_some_and(|init| init != t) { panic!("More than one initial state, in line {}.", line) }
+                        initial = Some(t);
+                        continue;
+                    }
+                    let s = state(pair[0], &mut names, &mut accepting, &mut trans);
+                    let next_label = label_map.len() as u64;
+                    let l = *label_map.entry(label.clone()).or_insert(next_label);
+                    trans[s as usize].push((l, t));
+                }
+            }
+            _ => panic!("Unexpected {:?} in line {}.", token, line),
+        }
+    }
+
+    let mut w = CWriter::new();
+    for (s, state_trans) in trans.into_iter().enumerate() {
+        let succs: Vec<(Node, u64)> = state_trans.into_iter().map(|(l, t)| (Node::State(t), l)).collect();
+        Node::Mon(TAG_TYP, accepting[s] as u32, succs).write(&mut w);
+    }
+    if accepting.contains(&true) { w.meta.tag_names.insert(1, "accepting".to_string()); }
+    w.meta.label_names = label_map.into_iter().map(|(name, l)| (l, name)).collect();
+    w.meta.state_names = names.into_iter().map(Some).collect();
+    w.meta.initial = initial.filter(|&s| s != 0);
+    w.finish()
+}
+
+/// The file name without the extension of one of the formats read by read_file
+pub fn file_stem(filename: &str) -> &str {
+    return [".boa.txt", ".boa", ".aut", ".coalgebra", ".tra", ".drn", ".dot", ".gv", ".kiss2", ".kiss", ".timbuk"].iter()
+        .find_map(|ext| filename.strip_suffix(ext)).unwrap_or(filename);
+}
+
+/// Read a coalgebra from any supported input format, dispatching on the file extension
+pub fn read_file(filename: &str) -> (Vec<u8>,CReader) {
     if filename.ends_with(".boa") {
-        let new_filename = [&filename[0..filename.len()-4],".boa.txt"].concat();
-        let (data,r) = read_boa(filename);
-        write_boa_txt(new_filename, &data, &r);
+        read_boa(filename)
     } else if filename.ends_with(".boa.txt") {
-        let new_filename = [&filename[0..filename.len()-8],".boa"].concat();
-        let (data,r) = read_boa_txt(filename);
-        write_boa(new_filename, &data, &r);
+        read_boa_txt(filename)
     } else if filename.ends_with(".aut") {
-        let new_filename = [&filename[0..filename.len()-4],".boa"].concat();
-        let (data,r) = read_aut(filename);
-        write_boa(new_filename, &data, &r);
+        read_aut(filename)
+    } else if filename.ends_with(".coalgebra") {
+        read_copar(filename)
+    } else if filename.ends_with(".tra") {
+        read_prism(filename)
+    } else if filename.ends_with(".drn") {
+        read_drn(filename)
+    } else if filename.ends_with(".dot") || filename.ends_with(".gv") {
+        read_dot(filename)
+    } else if filename.ends_with(".kiss2") || filename.ends_with(".kiss") {
+        read_kiss2(filename)
+    } else if filename.ends_with(".timbuk") {
+        read_timbuk(filename)
     } else {
         panic!("Unknown file type: {}", filename)
     }
 }
 
+/// Write a coalgebra in the format given by the file extension
+pub fn write_file(filename: &str, data: &[u8], r: &CReader) {
+    if filename.ends_with(".boa") {
+        write_boa(filename, data, r)
+    } else if filename.ends_with(".boa.txt") {
+        write_boa_txt(filename, data, r)
+    } else if filename.ends_with(".tra") {
+        write_prism(filename, data, r)
+    } else if filename.ends_with(".drn") {
+        write_drn(filename, data, r)
+    } else if filename.ends_with(".aut") {
+        write_aut(filename, data, r)
+    } else if filename.ends_with(".kiss2") || filename.ends_with(".kiss") {
+        write_kiss2(filename, data, r)
+    } else {
+        panic!("Unknown output file type: {}", filename)
+    }
+}
+
+/// Convert a .boa file to .boa.txt, and a file in any other format to .boa, next to the input file
+pub fn convert_file(filename: &str) {
+    let (data,r) = read_file(filename);
+    if filename.ends_with(".boa") {
+        write_boa_txt([file_stem(filename), ".boa.txt"].concat(), &data, &r);
+    } else {
+        write_boa([file_stem(filename), ".boa"].concat(), &data, &r);
+    }
+}
+
 #[test]
 fn test_convert_file() {
     std::fs::remove_file("tests/test1_converted.boa.txt").unwrap();
     std::fs::remove_file("tests/test1.boa").unwrap();
     convert_file("tests/test1_converted.boa");
     convert_file("tests/test1.boa.txt");
-}
\ No newline at end of file
+}
+#[test]
+fn test_names_roundtrip() {
+    let (_, r) = read_boa_txt("tests/names_test.boa.txt");
+    assert_eq!(r.meta.tag_names.values().collect::<Vec<_>>(), vec!["send", "recv", "time out"]);
+    assert!(r.meta.state_names.is_empty());
+    let (_, r) = read_boa_txt("tests/names_test2.boa.txt");
+    assert_eq!(r.meta.state_names, vec![Some("r".to_string()), Some("s".to_string()), None, Some("t".to_string())]);
+    assert_eq!(r.meta.find_state("@t"), Some(3));
+    assert_eq!(r.meta.find_state("2"), Some(2));
+
+    for filename in ["tests/names_test.boa.txt", "tests/names_test2.boa.txt"] {
+        let (data, r) = read_boa_txt(filename);
+        let path = std::env::temp_dir().join(format!("boa_names_test_{}.boa", std::process::id()));
+        write_boa(&path, &data, &r);
+        let (data2, r2) = read_boa(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(data, data2);
+        assert_eq!(r.meta, r2.meta);
+        let path_txt = std::env::temp_dir().join(format!("boa_names_test_{}.boa.txt", std::process::id()));
+        write_boa_txt(&path_txt, &data2, &r2);
+        let text = std::fs::read_to_string(&path_txt).unwrap();
+        std::fs::remove_file(&path_txt).unwrap();
+        assert_eq!(text, std::fs::read_to_string(filename).unwrap());
+    }
+}
+
+#[test]
+fn test_model_roundtrip() {
+    // the model type, the reward models and the observations of the tags of an imported model
+    let (data, r) = crate::drn::read_drn("tests/drn_mdp.drn");
+    assert_eq!(r.meta.model_type.as_deref(), Some("MDP"));
+    assert_eq!(r.meta.reward_models, vec!["steps"]);
+    let path = std::env::temp_dir().join(format!("boa_model_test_{}.boa", std::process::id()));
+    write_boa(&path, &data, &r);
+    let (data2, r2) = read_boa(&path);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(data, data2);
+    assert_eq!(r.meta, r2.meta);
+}
+
+#[test]
+fn test_shared_roundtrip() {
+    let (data, r) = read_boa_txt("tests/shared_test.boa.txt");
+    assert_eq!(r.shared_locs.len(), 3);
+    let nodes = crate::binrep::read_nodes(&data, &r);
+    assert_eq!(nodes[0], Node::from_ascii("List[0]{List[1]{Add[0]{@0:1,@1:1},@2},Add[0]{@0:1,@1:1}}".as_bytes()));
+    assert_eq!(nodes[3], Node::from_ascii("Set[0]{}".as_bytes()));
+
+    let path = std::env::temp_dir().join(format!("boa_shared_test_{}.boa", std::process::id()));
+    write_boa(&path, &data, &r);
+    let (data2, r2) = read_boa(&path);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(data, data2);
+    assert_eq!(r.shared, r2.shared);
+    assert_eq!(r.shared_locs, r2.shared_locs);
+    let path_txt = std::env::temp_dir().join(format!("boa_shared_test_{}.boa.txt", std::process::id()));
+    write_boa_txt(&path_txt, &data2, &r2);
+    let text = std::fs::read_to_string(&path_txt).unwrap();
+    std::fs::remove_file(&path_txt).unwrap();
+    assert_eq!(text, "$0 = Add[0]{@0:1,@1:1}\n$1 = List[1]{$0,@2}\n$2 = Set[0]{}\n\
+        List[0]{$1,$0}\nList[0]{$1,Add[0]{@1:1,@0:1}}\nList[0]{List[1]{$0,@3},$0}\n$2\nSet[0]{}");
+}
+
+#[test]
+fn test_aut_labels() {
+    use crate::{optalg::partref_nlogn, quotient::quotient};
+    let (data, r) = read_aut("tests/aut_test.aut");
+    assert_eq!(r.meta.label_names.values().collect::<Vec<_>>(), vec!["a", "b", "c d"]);
+
+    // the labels are kept by the binary and the text format
+    let path = std::env::temp_dir().join(format!("boa_aut_test_{}.boa", std::process::id()));
+    write_boa(&path, &data, &r);
+    let (data2, r2) = read_boa(&path);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(r.meta, r2.meta);
+    let path_txt = std::env::temp_dir().join(format!("boa_aut_test_{}.boa.txt", std::process::id()));
+    write_boa_txt(&path_txt, &data2, &r2);
+    let text = std::fs::read_to_string(&path_txt).unwrap();
+    let (data3, r3) = read_boa_txt(&path_txt);
+    std::fs::remove_file(&path_txt).unwrap();
+    assert_eq!(text.lines().next(), Some("Tag[0]{@1:a,@2:a}"));
+    assert_eq!(text.lines().last(), Some("Tag[0]{@0:\"c d\"}"));
+    assert_eq!(crate::binrep::read_nodes(&data3, &r3), crate::binrep::read_nodes(&data, &r));
+    assert_eq!(r3.meta.label_names, r.meta.label_names);
+
+    let ids = partref_nlogn(data.clone(), r.clone());
+    assert_eq!(ids, vec![0,1,1,2,2]);
+    let (qdata, qr) = quotient(&data, &r, &ids);
+    let path_aut = std::env::temp_dir().join(format!("boa_aut_test_{}.aut", std::process::id()));
+    write_aut(&path_aut, &qdata, &qr);
+    let text = std::fs::read_to_string(&path_aut).unwrap();
+    let (data4, r4) = read_aut(&path_aut);
+    std::fs::remove_file(&path_aut).unwrap();
+    assert_eq!(text, "des (0, 3, 3)\n(0, \"a\", 1)\n(1, \"b\", 2)\n(2, \"c d\", 0)\n");
+    assert_eq!(crate::binrep::read_nodes(&data4, &r4), crate::binrep::read_nodes(&qdata, &qr));
+}
+
+#[test]
+fn test_read_aut_robust() {
+    let (data, r) = read_aut("tests/aut_robust.aut");
+    assert_eq!(r.meta.initial, Some(2));
+    assert_eq!(r.meta.label_names.values().collect::<Vec<_>>(), vec!["a(1, 2)", "b", "tau", "x,y", "i(3,4)"]);
+    let nodes = crate::binrep::read_nodes(&data, &r);
+    assert_eq!(nodes[1], Node::Mon(TAG_TYP, 0, vec![(Node::State(2), 1), (Node::State(0), 4)]));
+    assert_eq!(nodes[2], Node::Mon(TAG_TYP, 0, vec![(Node::State(0), 2), (Node::State(2), 3)]));
+    // the quotient keeps the initial state
+    let (_, qr) = crate::quotient::quotient(&data, &r, &[0, 1, 2]);
+    assert_eq!(qr.meta.initial, Some(2));
+
+    // the initial state and the labels survive writing .boa and .aut, also with CRLF line endings
+    let path = std::env::temp_dir().join(format!("boa_aut_robust_{}.boa", std::process::id()));
+    write_boa(&path, &data, &r);
+    let (data2, r2) = read_boa(&path);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(r2.meta, r.meta);
+    let path_aut = std::env::temp_dir().join(format!("boa_aut_robust_{}.aut", std::process::id()));
+    write_aut(&path_aut, &data2, &r2);
+    let text = std::fs::read_to_string(&path_aut).unwrap();
+    assert!(text.starts_with("des (2, 5, 3)\n(0, \"a(1, 2)\", 1)\n"));
+    std::fs::write(&path_aut, text.replace('\n', "\r\n")).unwrap();
+    let (data3, r3) = read_aut(&path_aut);
+    write_aut(&path_aut, &data3, &r3);
+    let text3 = std::fs::read_to_string(&path_aut).unwrap();
+    std::fs::remove_file(&path_aut).unwrap();
+    assert_eq!(text3, text);
+}
+
+#[test]
+fn test_aut_quoted_labels() {
+    // labels with quotes and backslashes are escaped by write_aut and read back unchanged
+    let path_aut = std::env::temp_dir().join(format!("boa_aut_quoted_{}.aut", std::process::id()));
+    std::fs::write(&path_aut, "des (0, 3, 2)\n(0, \"say \\\"hi\\\"\", 1)\n(1, \"a\\\\\", 0)\n(1, \"b\\c\", 1)\n").unwrap();
+    let (data, r) = read_aut(&path_aut);
+    assert_eq!(r.meta.label_names.values().collect::<Vec<_>>(), vec!["say \"hi\"", "a\\", "b\\c"]);
+    write_aut(&path_aut, &data, &r);
+    let text = std::fs::read_to_string(&path_aut).unwrap();
+    let (data2, r2) = read_aut(&path_aut);
+    std::fs::remove_file(&path_aut).unwrap();
+    assert_eq!(text, "des (0, 3, 2)\n(0, \"say \\\"hi\\\"\", 1)\n(1, \"a\\\\\", 0)\n(1, \"b\\\\c\", 1)\n");
+    assert_eq!(crate::binrep::read_nodes(&data2, &r2), crate::binrep::read_nodes(&data, &r));
+    assert_eq!(r2.meta.label_names, r.meta.label_names);
+}
+
+#[cfg(test)]
+fn read_aut_str(text: &str) {
+    let path = std::env::temp_dir().join(format!("boa_aut_malformed_{}_{:?}.aut", std::process::id(), std::thread::current().id()));
+    std::fs::write(&path, text).unwrap();
+    let result = std::panic::catch_unwind(|| read_aut(&path));
+    std::fs::remove_file(&path).unwrap();
+    if let Err(e) = result { std::panic::resume_unwind(e) }
+}
+
+#[test]
+#[should_panic(expected = "in line 3")]
+fn test_read_aut_malformed_line() {
+    read_aut_str("des (0, 2, 2)\n(0, a, 1)\n(1, a)\n");
+}
+
+#[test]
+#[should_panic(expected = "declares 3 transitions, but the file has 2")]
+fn test_read_aut_edge_count() {
+    read_aut_str("des (0, 3, 2)\n(0, a, 1)\n(1, a, 0)\n");
+}
+
+#[test]
+#[should_panic(expected = "State out of range in line 2")]
+fn test_read_aut_state_range() {
+    read_aut_str("des (0, 1, 2)\n(0, a, 2)\n");
+}
+
+#[test]
+fn test_read_dot() {
+    use crate::{equiv::equiv, optalg::partref_nlogn};
+    let (data, r) = read_dot("tests/mealy.dot");
+    assert_eq!(r.meta.initial, Some(2));
+    assert_eq!(r.meta.find_state("s3"), Some(3));
+    assert_eq!(r.meta.label_names.values().collect::<Vec<_>>(), vec!["a/0", "b/1", "a/1", "b/0"]);
+    assert_eq!(crate::binrep::read_nodes(&data, &r)[0], Node::from_ascii("Tag[0]{@1:0,@2:1}".as_bytes()));
+    assert!(equiv(&data, &r, &data, &r, 0, 2).0);
+    assert_eq!(partref_nlogn(data, r), vec![0,1,0,1]);
+
+    let (data, r) = read_dot("tests/dfa.dot");
+    assert_eq!(r.meta.initial, None);
+    assert_eq!(r.meta.tag_names.values().collect::<Vec<_>>(), vec!["accepting"]);
+    assert_eq!(crate::binrep::read_nodes(&data, &r)[1], Node::from_ascii("Tag[1]{@1:0,@2:1}".as_bytes()));
+    assert_eq!(partref_nlogn(data, r), vec![0,1,2]);
+}
+
+#[test]
+fn test_dot_tokens() {
+    use DotToken::*;
+    let tokens: Vec<DotToken> = dot_tokens("a->\"b \\\"c\\\"\" [x=1.5, y=-2]; // comment\n# comment\nc").into_iter().map(|t| t.0).collect();
+    assert_eq!(tokens, vec![Id("a".into()), Arrow, Id("b \"c\"".into()), Sym(b'['), Id("x".into()), Sym(b'='), Id("1.5".into()),
+        Sym(b','), Id("y".into()), Sym(b'='), Id("-2".into()), Sym(b']'), Sym(b';'), Id("c".into())]);
+}
+
+#[test]
+fn test_label_names_text() {
+    // numeric labels are reserved, so the name gets the next free label
+    let mut names = Names::new();
+    let input = "Tag[0]{@0:0,@0:send,@0:1}".as_bytes();
+    names.reserve_numeric(input);
+    assert_eq!(Node::from_ascii_named(input, &mut names), Node::Mon(TAG_TYP, 0, vec![(Node::State(0), 0), (Node::State(0), 2), (Node::State(0), 1)]));
+    assert_eq!(names.label_names().values().collect::<Vec<_>>(), vec!["send"]);
+}
+
+#[test]
+fn test_write_partition() {
+    let (_, r) = read_boa_txt("tests/names_test2.boa.txt");
+    let path = std::env::temp_dir().join(format!("boa_partition_test_{}.txt", std::process::id()));
+    write_partition(&path, &[0, 1, 0, 2], &r.meta);
+    let text = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(text, "@r @2\n@s\n@t\n");
+}
+
+#[test]
+fn test_read_partition() {
+    let (_, r) = read_boa_txt("tests/names_test2.boa.txt");
+    let path = std::env::temp_dir().join(format!("boa_read_partition_test_{}.txt", std::process::id()));
+    write_partition(&path, &[0, 1, 0, 2], &r.meta);
+    let ids = read_partition(&path, 4, &r.meta);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(ids, vec![0, 1, 0, 2]);
+}
+
+#[test]
+fn test_read_single_pass() {
+    // files without names are read in one pass, the others are read again once a name turns up
+    for file in ["tests/test1.boa.txt", "tests/shared_test.boa.txt"] {
+        let mut reader = BufReader::new(File::open(file).unwrap());
+        let (data, r) = read_statements(&mut reader, &mut Names::numeric_only()).unwrap();
+        let (data2, r2) = read_boa_txt(file);
+        assert_eq!(data, data2);
+        assert_eq!(r.shared_locs, r2.shared_locs);
+    }
+    for file in ["tests/names_test.boa.txt", "tests/names_test2.boa.txt", "tests/whitespace_test.boa.txt"] {
+        let mut reader = BufReader::new(File::open(file).unwrap());
+        assert!(read_statements(&mut reader, &mut Names::numeric_only()).is_none());
+    }
+    // a state defined by name after it is used
+    let input = "List[0]{@1}\nx: List[0]{}\n";
+    assert!(read_statements(&mut input.as_bytes(), &mut Names::numeric_only()).is_none());
+    // whitespace, comments and nodes over several lines don't need a second pass either
+    let input = "# comment: {\nList[0]{@0, @1}  # state 0\nList[1]{\n  @0,\n  @1 }\n$1 =\n  Set[0]{}\nList[0]{$1,$1}\n";
+    let (data, r) = read_statements(&mut input.as_bytes(), &mut Names::numeric_only()).unwrap();
+    let nodes = crate::binrep::read_nodes(&data, &r);
+    assert_eq!(nodes[1], Node::from_ascii("List[1]{@0,@1}".as_bytes()));
+    assert_eq!(nodes[2], Node::from_ascii("List[0]{Set[0]{},Set[0]{}}".as_bytes()));
+}
+
+#[test]
+fn test_is_bare_definition() {
+    assert!(is_bare_definition("s0:\r\n".as_bytes()));
+    assert!(is_bare_definition("\"s 0\" : # comment\n".as_bytes()));
+    assert!(is_bare_definition("$3 =\n".as_bytes()));
+    assert!(!is_bare_definition("s0: List[0]{}\n".as_bytes()));
+    assert!(!is_bare_definition("List[0]{@0}\n".as_bytes()));
+    assert!(!is_bare_definition("List[0]{ # a: \n".as_bytes()));
+}
+
+#[test]
+fn test_whitespace_and_comments() {
+    // The same coalgebra as test1, with comments, blank lines and nodes split over lines
+    let (data, r) = read_boa_txt("tests/whitespace_test.boa.txt");
+    let (data1, r1) = read_boa_txt("tests/test1.boa.txt");
+    assert_eq!(data, data1);
+    assert_eq!(r.headers, r1.headers);
+    assert_eq!(r.values, r1.values);
+    assert_eq!(r.meta.state_name(4), Some("four"));
+}
+
+#[test]
+fn test_for_each_statement() {
+    let input = "# header\r\n\r\nList[0]{@0}\r\ns: \r\n  Set[0]{\r\n    @0,   # first\r\n    @1\r\n  }\r\n";
+    let mut stmts = vec![];
+    for_each_statement(&mut input.as_bytes(), |stmt| { stmts.push(String::from_utf8(stmt.to_vec()).unwrap()); true });
+    assert_eq!(stmts, vec!["List[0]{@0}\r\n", "s: \r\n  Set[0]{\r\n    @0,   # first\r\n    @1\r\n  }\r\n"]);
+    let mut names = Names::new();
+    let (name, rest) = read_definition(stmts[1].as_bytes());
+    assert_eq!(name, Some(b"s".to_vec()));
+    assert_eq!(Node::from_ascii_named(rest, &mut names), Node::from_ascii("Set[0]{@0,@1}".as_bytes()));
+}
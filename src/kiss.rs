@@ -0,0 +1,263 @@
+//=================//
+// KISS2 format //
+//=================//
+
+// KISS2 describes a finite state machine, as in the MCNC/LGSynth benchmarks, by a header and one row per transition:
+//   .i 2
+//   .o 1
+//   .s 3
+//   .r st0
+//   0- st0 st1 0
+//   .e
+// A row gives an input cube, the current state, the next state and the outputs. The input cube may contain - for
+// don't-care inputs, and is expanded into all the inputs it covers, so that a state becomes Tag[0]{@t:"01/0",...} with
+// one label per input and output, like the Mealy machines read from DOT files. Outputs are kept as they are, so an output
+// with a - only equals the same output with a -. States are numbered in order of appearance, and the reset state given
+// by .r, or else the current state of the first row, is the initial state.
+//
+// write_kiss2 writes a coalgebra of this form, like a quotient. The inputs of a state that go to the same next state with
+// the same output are merged into cubes again. A state only exists in KISS2 if it appears in a row, so writing fails
+// if a state has no transitions and isn't the next state of any.
+
+use std::collections::BTreeSet;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::{fs::File, path::Path};
+
+use crate::binrep::{read_nodes, CReader, CWriter, Node, TAG_TYP};
+use crate::hmap::HMap;
+use crate::io::create_file;
+
+/// The most don't-care inputs in a cube, which is expanded into 2^n inputs
+const MAX_DONT_CARES: usize = 24;
+
+fn is_cube(s: &str) -> bool {
+    return s.bytes().all(|c| c == b'0' || c == b'1' || c == b'-');
+}
+
+/// All the inputs covered by the cube
+fn expand(cube: &str) -> Vec<String> {
+    let mut inputs = vec![String::new()];
+    for c in cube.chars() {
+        if c == '-' {
+            inputs = inputs.into_iter().flat_map(|i| [i.clone() + "0", i + "1"]).collect();
+        } else {
+            for i in inputs.iter_mut() { i.push(c) }
+        }
+    }
+    return inputs;
+}
+
+pub fn read_kiss2<P>(filename: P) -> (Vec<u8>,CReader)
+where P: AsRef<Path>, {
+    let filename = filename.as_ref();
+    if filename.extension().is_none_or(|ext| ext != "kiss2" && ext != "kiss") {
+        panic!("File must be *.kiss2 or *.kiss, but is {}", filename.display());
+    }
+    let file = File::open(filename).
+        expect(&format!("Couldn't open file {:?}", filename.display().to_string()));
+    let mut num_inputs = None;
+    let mut num_outputs = None;
+    let mut reset = None;
+    let mut ids: HMap<String, u32> = HMap::default();
+    let mut names: Vec<String> = vec![];
+    let mut trans: Vec<Vec<(u64, u32)>> = vec![];
+    let mut label_map: HMap<String, u64> = HMap::default();
+    let mut state = |name: &str, names: &mut Vec<String>, trans: &mut Vec<Vec<(u64, u32)>>| -> u32 {
+        if name == "*" { panic!("Unspecified states (*) are not supported.") }
+        *ids.entry(name.to_string()).or_insert_with(|| {
+            names.push(name.to_string());
+            trans.push(vec![]);
+            names.len() as u32 - 1
+        })
+    };
+
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.expect("Failure while reading file.");
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() { continue }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let num = |field: Option<&&str>| -> usize {
+            field.and_then(|f| f.parse().ok()).unwrap_or_else(|| panic!("Expected a number in line {}.", i + 1))
+        };
+        match fields[0] {
+            ".i" => num_inputs = Some(num(fields.get(1))),
+            ".o" => num_outputs = Some(num(fields.get(1))),
+            ".r" => reset = Some(fields.get(1).unwrap_or_else(|| panic!("Expected a state in line {}.", i + 1)).to_string()),
+            ".e" | ".end" => break,
+            // the numbers of rows and states, and the names of the inputs and outputs, aren't needed
+            ".p" | ".s" | ".ilb" | ".ob" | ".type" => {}
+            f if f.starts_with('.') => panic!("Unknown keyword {} in line {}.", f, i + 1),
+            _ => {
+                let (Some(ni), Some(no)) = (num_inputs, num_outputs) else { panic!("Missing .i or .o before line {}.", i + 1) };
+                let expected = (ni > 0) as usize + 2 + (no > 0) as usize;
+                if fields.len() != expected { panic!("Expected {} fields in line {}, but found {}.", expected, i + 1, fields.len()) }
+                let (cube, rest) = if ni > 0 { (fields[0], &fields[1..]) } else { ("", &fields[..]) };
+                let output = if no > 0 { rest[2] } else { "" };
+                if cube.len() != ni || !is_cube(cube) { panic!("Expected {} inputs of 0, 1 or - in line {}.", ni, i + 1) }
+                if output.len() != no || !is_cube(output) { panic!("Expected {} outputs of 0, 1 or - in line {}.", no, i + 1) }
+                if cube.matches('-').count() > MAX_DONT_CARES { panic!("Too many don't-care inputs in line {}.", i + 1) }
+                let s = state(rest[0], &mut names, &mut trans);
+                let t = state(rest[1], &mut names, &mut trans);
+                for input in expand(cube) {
+                    let next_label = label_map.len() as u64;
+                    let l = *label_map.entry(format!("{}/{}", input, output)).or_insert(next_label);
+                    trans[s as usize].push((l, t));
+                }
+            }
+        }
+    }
+    let initial = reset.map(|name| names.iter().position(|n| *n == name).unwrap_or_else(|| panic!("Unknown reset state {}.", name)) as u32);
+
+    let mut w = CWriter::new();
+    for state_trans in trans {
+        let succs: Vec<(Node, u64)> = state_trans.into_iter().map(|(l, t)| (Node::State(t), l)).collect();
+        Node::Mon(TAG_TYP, 0, succs).write(&mut w);
+    }
+    w.meta.label_names = label_map.into_iter().map(|(name, l)| (l, name)).collect();
+    w.meta.state_names = names.into_iter().map(Some).collect();
+    w.meta.initial = initial.filter(|&s| s != 0);
+    w.finish()
+}
+
+/// Cover the inputs, which all have the same length, exactly by cubes, by splitting on the first input and using a
+/// don't-care for it if both halves are the same
+fn merge_cubes(inputs: BTreeSet<Vec<u8>>) -> BTreeSet<Vec<u8>> {
+    if inputs.first().is_none_or(|i| i.is_empty()) { return inputs }
+    let (mut zeros, mut ones) = (BTreeSet::new(), BTreeSet::new());
+    for input in inputs {
+        if input[0] == b'0' { zeros.insert(input[1..].to_vec()); } else { ones.insert(input[1..].to_vec()); }
+    }
+    let prefix = |c: u8, cubes: BTreeSet<Vec<u8>>| cubes.into_iter().map(move |cube| [vec![c], cube].concat());
+    if zeros == ones { return prefix(b'-', merge_cubes(zeros)).collect() }
+    return prefix(b'0', merge_cubes(zeros)).chain(prefix(b'1', merge_cubes(ones))).collect();
+}
+
+/// The next state and the outputs of a row
+type Row<'a> = (u32, &'a str);
+
+/// Write a coalgebra of Tag nodes whose labels are named like 01/1 in KISS2 format
+pub fn write_kiss2<P>(filename: P, data: &[u8], r: &CReader)
+where P: AsRef<Path>, {
+    let filename = filename.as_ref();
+    if filename.extension().is_none_or(|ext| ext != "kiss2" && ext != "kiss") {
+        panic!("File must be *.kiss2 or *.kiss, but is {}", filename.display());
+    }
+    let nodes = read_nodes(data, r);
+    let state_name = |s: u32| match r.meta.state_name(s) {
+        Some(name) if !name.is_empty() && !name.contains(char::is_whitespace) && name != "*" => name.to_string(),
+        _ => format!("s{}", s),
+    };
+    let mut widths = None;
+    let mut rows = vec![];
+    let mut in_rows = vec![false; nodes.len()];
+    for (s, node) in nodes.iter().enumerate() {
+        let Node::Mon(TAG_TYP, 0, succs) = node else { panic!("Export to KISS2 requires all states to be Tag[0] nodes.") };
+        // the inputs of each next state and output, in order of appearance
+        let mut groups: Vec<(Row, BTreeSet<Vec<u8>>)> = vec![];
+        let mut group_of: HMap<Row, usize> = HMap::default();
+        for (succ, l) in succs {
+            let Node::State(t) = succ else { panic!("Export to KISS2 requires the elements of the Tag nodes to be states.") };
+            in_rows[s] = true;
+            in_rows[*t as usize] = true;
+            let label = r.meta.label_names.get(l).unwrap_or_else(|| panic!("Label {} has no name of the form inputs/outputs.", l));
+            let (input, output) = label.split_once('/')
+                .filter(|(i, o)| is_cube(i) && is_cube(o))
+                .unwrap_or_else(|| panic!("Label {:?} is not of the form inputs/outputs with 0, 1 or -.", label));
+            if *widths.get_or_insert((input.len(), output.len())) != (input.len(), output.len()) {
+                panic!("Label {:?} has another number of inputs or outputs than the others.", label);
+            }
+            let k = *group_of.entry((*t, output)).or_insert_with(|| { groups.push(((*t, output), BTreeSet::new())); groups.len() - 1 });
+            groups[k].1.extend(expand(input).into_iter().map(String::into_bytes));
+        }
+        for ((t, output), inputs) in groups {
+            for cube in merge_cubes(inputs) {
+                let cube = String::from_utf8(cube).unwrap();
+                let row = [cube.as_str(), &state_name(s as u32), &state_name(t), output];
+                rows.push(row.iter().filter(|f| !f.is_empty()).cloned().collect::<Vec<_>>().join(" "));
+            }
+        }
+    }
+    if let Some(s) = in_rows.iter().position(|&b| !b) {
+        panic!("State {} has no transitions and isn't the next state of any, so it can't be written to KISS2.", state_name(s as u32));
+    }
+    let (num_inputs, num_outputs) = widths.unwrap_or((0, 0));
+
+    let mut writer = BufWriter::new(create_file(filename));
+    let mut out = String::new();
+    out.push_str("# Exported by boa\n");
+    out.push_str(&format!(".i {}\n.o {}\n.p {}\n.s {}\n", num_inputs, num_outputs, rows.len(), nodes.len()));
+    if !nodes.is_empty() { out.push_str(&format!(".r {}\n", state_name(r.meta.initial.unwrap_or(0)))) }
+    for row in rows { out.push_str(&row); out.push('\n') }
+    out.push_str(".e\n");
+    writer.write_all(out.as_bytes()).expect("Writing error.");
+}
+
+#[test]
+fn test_read_kiss2() {
+    use crate::{equiv::equiv, naivealg::partref_naive, optalg::partref_nlogn};
+    let (data, r) = read_kiss2("tests/kiss2_test.kiss2");
+    assert_eq!(r.meta.state_names, ["st0", "st1", "st2", "st3"].map(|s| Some(s.to_string())));
+    assert_eq!(r.meta.initial, Some(1));
+    // the cubes are expanded, so 0- gives 00 and 01
+    let labels: Vec<&str> = r.meta.label_names.values().map(String::as_str).collect();
+    assert_eq!(labels, vec!["00/0", "01/0", "10/0", "11/0", "00/1", "10/1", "00/-", "01/-", "10/-", "11/-"]);
+    assert_eq!(read_nodes(&data, &r)[0], Node::from_ascii("Tag[0]{@1:0,@1:1,@2:2,@2:3}".as_bytes()));
+    assert!(equiv(&data, &r, &data, &r, 1, 2).0);
+    let ids1 = partref_naive(&data, &r);
+    let ids2 = partref_nlogn(data, r);
+    assert_eq!(&crate::renumber(&ids1), &ids2);
+    assert_eq!(ids2, vec![0,1,1,2]);
+}
+
+#[test]
+fn test_write_kiss2_quotient() {
+    use crate::{optalg::partref_nlogn, quotient::quotient};
+    let (data, r) = read_kiss2("tests/kiss2_test.kiss2");
+    let ids = partref_nlogn(data.clone(), r.clone());
+    let (qdata, qr) = quotient(&data, &r, &ids);
+    let path = std::env::temp_dir().join(format!("boa_kiss2_test_{}.kiss2", std::process::id()));
+    write_kiss2(&path, &qdata, &qr);
+    let (data2, r2) = read_kiss2(&path);
+    let text = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    // the inputs of st0 are merged into one cube
+    assert_eq!(text, "# Exported by boa\n.i 2\n.o 1\n.p 4\n.s 3\n.r st1\n-- st0 st1 0\n-0 st1 st0 1\n-1 st1 st1 0\n-- st3 st3 -\n.e\n");
+    assert_eq!(r2.meta.initial, Some(1));
+    assert_eq!(partref_nlogn(data2, r2), vec![0,1,2]);
+}
+
+#[cfg(test)]
+fn write_kiss2_nodes(nodes: &[&str]) -> String {
+    let mut w = CWriter::new();
+    for node in nodes { Node::from_ascii(node.as_bytes()).write(&mut w) }
+    w.meta.label_names = [(0, "0/1".to_string()), (1, "1/0".to_string())].into_iter().collect();
+    let (data, r) = w.finish();
+    let path = std::env::temp_dir().join(format!("boa_kiss2_nodes_{}_{:?}.kiss2", std::process::id(), std::thread::current().id()));
+    // write_kiss2 fails before it creates the file
+    write_kiss2(&path, &data, &r);
+    let text = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    return text;
+}
+
+#[test]
+fn test_write_kiss2_without_transitions() {
+    // state 1 has no transitions, but is kept as the next state of state 0
+    let text = write_kiss2_nodes(&["Tag[0]{@1:0,@0:1}", "Tag[0]{}"]);
+    assert_eq!(text, "# Exported by boa\n.i 1\n.o 1\n.p 2\n.s 2\n.r s0\n0 s0 s1 1\n1 s0 s0 0\n.e\n");
+}
+
+#[test]
+#[should_panic(expected = "State s2 has no transitions")]
+fn test_write_kiss2_lost_state() {
+    write_kiss2_nodes(&["Tag[0]{@1:0}", "Tag[0]{@0:1}", "Tag[0]{}"]);
+}
+
+#[test]
+fn test_merge_cubes() {
+    let cubes = |s: &[&str]| s.iter().map(|c| c.as_bytes().to_vec()).collect::<BTreeSet<_>>();
+    assert_eq!(merge_cubes(cubes(&["000", "001", "010", "011", "111"])), cubes(&["0--", "111"]));
+    assert_eq!(merge_cubes(cubes(&["00", "11"])), cubes(&["00", "11"]));
+    assert_eq!(merge_cubes(cubes(&["000", "010", "100", "110", "111"])), cubes(&["0-0", "100", "11-"]));
+    assert_eq!(merge_cubes(cubes(&[""])), cubes(&[""]));
+}
@@ -0,0 +1,82 @@
+#![allow(dead_code)]
+#![allow(clippy::needless_return, clippy::needless_arbitrary_self_type, clippy::len_zero, clippy::needless_range_loop,
+         clippy::redundant_field_names, clippy::needless_lifetimes, clippy::extra_unused_lifetimes,
+         clippy::expect_fun_call, clippy::map_entry, clippy::missing_safety_doc, clippy::new_without_default,
+         clippy::needless_borrows_for_generic_args)]
+use std::hash::Hash;
+use hmap::HMap;
+
+pub mod hmap;
+pub mod util;
+pub mod parsing;
+pub mod io;
+pub mod binrep;
+pub mod nodetype;
+pub mod coalg;
+pub mod refpart;
+pub mod naivealg;
+pub mod optalg;
+pub mod equiv;
+pub mod onthefly;
+pub mod simulation;
+pub mod determinize;
+pub mod backward;
+pub mod copar;
+pub mod prism;
+pub mod drn;
+pub mod kiss;
+pub mod timbuk;
+pub mod dot;
+pub mod quotient;
+
+
+pub fn renumber<A> (ids: &[A]) -> Vec<u32>
+where A:Hash+Eq {
+    let mut canon_map = HMap::default();
+    let mut last_id = 0;
+    let res = ids.iter().map(|id| {
+        if canon_map.contains_key(&id) {
+            canon_map[&id]
+        } else {
+            canon_map.insert(id, last_id);
+            last_id += 1;
+            last_id - 1
+        }
+    }).collect();
+    // println!("Canon map size: {}", data_size(&canon_map));
+    return res;
+}
+
+pub fn renumber_sort<A> (sigs: &[A]) -> Vec<u32>
+where A:Ord+Copy {
+    // TODO: Try sorting array 0..n by key sigs[i]
+    let mut xs:Vec<u32> = (0..sigs.len() as u32).collect();
+    xs.sort_unstable_by_key(|i| sigs[*i as usize]);
+    let mut ids:Vec<u32> = vec![0;sigs.len()];
+    let mut id = 0;
+    let mut last_sig = sigs[xs[0] as usize];
+    for i in xs {
+        let sig = sigs[i as usize];
+        if sig != last_sig {
+            id += 1;
+            last_sig = sig;
+        }
+        ids[i as usize] = id;
+    }
+    // make sure the first id is 0
+    // n log n algorithm relies on this (but could improve it so that it doesn't)
+    let firstid = ids[0];
+    if firstid != 0 {
+        for id in ids.iter_mut() {
+            if *id == 0 { *id = firstid }
+            else if *id == firstid { *id = 0 }
+        }
+    }
+    return ids
+}
+
+#[test]
+fn test_renumber_sort() {
+    assert_eq!(renumber_sort(&[3,1,3,1,5,3,0,1]), vec![0,1,0,1,3,0,2
+    ,1]);
+}
@@ -1,82 +1,27 @@
-#![allow(dead_code)]
-use std::{hash::{Hash}, time::SystemTime};
-use hmap::HMap;
-
-mod hmap;
-mod util;
-mod parsing;
-mod io;
-mod binrep;
-mod coalg;
-mod refpart;
-mod naivealg;
-mod optalg;
-
-
-fn renumber<A> (ids: &[A]) -> Vec<u32>
-where A:Hash+Eq {
-    let mut canon_map = HMap::default();
-    let mut last_id = 0;
-    let res = ids.iter().map(|id| {
-        if canon_map.contains_key(&id) {
-            canon_map[&id]
-        } else {
-            canon_map.insert(id, last_id);
-            last_id += 1;
-            last_id - 1
-        }
-    }).collect();
-    // println!("Canon map size: {}", data_size(&canon_map));
-    return res;
-}
-
-fn renumber_sort<A> (sigs: &[A]) -> Vec<u32>
-where A:Ord+Copy {
-    // TODO: Try sorting array 0..n by key sigs[i]
-    let mut xs:Vec<u32> = (0..sigs.len() as u32).collect();
-    xs.sort_unstable_by_key(|i| sigs[*i as usize]);
-    let mut ids:Vec<u32> = vec![0;sigs.len()];
-    let mut id = 0;
-    let mut last_sig = sigs[xs[0] as usize];
-    for i in xs {
-        let sig = sigs[i as usize];
-        if sig != last_sig {
-            id += 1;
-            last_sig = sig;
-        }
-        ids[i as usize] = id;
-    }
-    // make sure the first id is 0
-    // n log n algorithm relies on this (but could improve it so that it doesn't)
-    let firstid = ids[0];
-    if firstid != 0 {
-        for id in ids.iter_mut() {
-            if *id == 0 { *id = firstid }
-            else if *id == firstid { *id = 0 }
-        }
-    }
-    return ids
-}
-
-#[test]
-fn test_renumber_sort() {
-    assert_eq!(renumber_sort(&vec![3,1,3,1,5,3,0,1]), vec![0,1,0,1,3,0,2
-    ,1]);
-}
-
-
-
-
+#![allow(clippy::needless_return)]
+use std::time::SystemTime;
 
 use clap::{Parser, ArgEnum};
+use itertools::Itertools;
 
-use crate::{io::{convert_file, read_boa}, naivealg::partref_naive, optalg::partref_nlogn};
+use boa::{util, binrep::{Meta, read_nodes}, io::{convert_file, create_file, file_stem, read_file, read_partition, write_boa, write_file, write_partition}, dot::write_dot, quotient::quotient, naivealg::partref_naive, optalg::partref_nlogn, equiv::equiv, onthefly::check_onthefly, coalg::Coalg, simulation::simulation_preorder, determinize::determinize, backward::{Predecessors, refine_backward}};
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ArgEnum)]
 enum Action {
     Convert,
     Naive,
     Nlogn,
+    Equiv,
+    Check,
+    Simulation,
+    Determinize,
+    Dot,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ArgEnum)]
+enum Direction {
+    Forward,
+    Backward,
 }
 
 /// Binary coalgebraic partition refinement.\n\
@@ -86,13 +31,75 @@ enum Action {
 about = "Binary coalgebraic partition refinement.\n\
 - Use `boa convert file.boa.txt` to convert a text file to binary format.\n\
 - Use `boa convert file.boa` to convert a binary file to text format.\n\
+- Use `boa convert file.coalgebra` to convert a CoPaR file to binary format.\n\
+- Use `boa convert file.tra` to convert a PRISM model to binary format, with the labels and rewards of file.lab and file.srew.\n\
+- Use `boa convert file.drn` to convert a Storm model to binary format.\n\
+- Use `boa convert file.dot` to convert a Mealy machine, DFA or LTS in DOT format, as written by LearnLib or AALpy, to binary format.\n\
+- Use `boa convert file.kiss2` to convert a finite state machine in KISS2 format to binary format.\n\
+- Use `boa convert file.timbuk` to convert a weighted tree automaton in Timbuk format to binary format.\n\
+- Use `boa convert file.boa --output file.drn` to convert to the format given by the extension of the output file instead.\n\
 - Use `boa naive file.boa` to run the naive algorithm.\n\
-- Use `boa nlogn file.boa` to run the nlogn algorithm.", long_about = None)]
+- Use `boa nlogn file.boa` to run the nlogn algorithm.\n\
+- Use `boa nlogn file.boa --direction backward` to compute backward bisimulation instead.\n\
+- Use `boa nlogn file.boa --partition out.txt` to also write the blocks of the partition, one per line.\n\
+- Use `boa nlogn file.tra --output min.tra` to also write the quotient, as .boa, .boa.txt, PRISM .tra (with .lab and .srew), Storm .drn, Aldebaran .aut or KISS2 .kiss2.\n\
+- Use `boa equiv a.boa b.boa --init-a x --init-b y` to check whether state x of a.boa and state y of b.boa are equivalent.\n\
+- Use `boa check file.boa --init-a x --init-b y` to check whether states x and y are equivalent without computing the whole partition.\n\
+- Use `boa simulation file.boa` to compute the simulation preorder of an LTS-like coalgebra (Set, Or and Tag nodes).\n\
+- Use `boa determinize file.boa --init x` to write the powerset coalgebra reachable from state x to file.det.boa.\n\
+- Use `boa dot file.boa --partition p.txt` to draw the coalgebra to file.dot, with the states grouped by the blocks in p.txt.\n\
+- Use `boa dot file.boa --quotient` to draw the quotient by bisimilarity, or by the partition in --partition, instead.", long_about = None)]
 struct Args {
     #[clap(arg_enum)]
     action: Action,
 
     file: String,
+
+    /// Compute forward or backward bisimulation (for naive and nlogn)
+    #[clap(long, arg_enum, default_value = "forward")]
+    direction: Direction,
+
+    /// Second input file (for equiv)
+    file2: Option<String>,
+
+    /// Initial state in the first file (for equiv), or the first state (for check), as a number or a name.
+    /// Defaults to the initial state of the file, which is 0 unless the file says otherwise
+    #[clap(long)]
+    init_a: Option<String>,
+
+    /// Initial state in the second file (for equiv), or the second state (for check), as a number or a name.
+    /// Defaults to the initial state of the file
+    #[clap(long)]
+    init_b: Option<String>,
+
+    /// Initial state (for determinize), as a number or a name. Defaults to the initial state of the file
+    #[clap(long)]
+    init: Option<String>,
+
+    /// Write the partition to this file, one line per block (for naive and nlogn), or read it from this file (for dot)
+    #[clap(long)]
+    partition: Option<String>,
+
+    /// Draw the quotient instead of the coalgebra itself (for dot)
+    #[clap(long)]
+    quotient: bool,
+
+    /// Write the quotient by the partition to this file, in the format given by its extension (for naive and nlogn),
+    /// the converted input (for convert), or the drawing (for dot, instead of file.dot)
+    #[clap(long)]
+    output: Option<String>,
+
+    /// Maximum number of states of the output (for determinize)
+    #[clap(long, default_value_t = 1_000_000)]
+    max_states: usize,
+}
+
+/// Look up a state given on the command line as a number or a name, or else take the initial state of the file
+fn find_state(meta: &Meta, s: &Option<String>) -> u32 {
+    match s {
+        Some(s) => meta.find_state(s).unwrap_or_else(|| panic!("Unknown state {}.", s)),
+        None => meta.initial.unwrap_or(0),
+    }
 }
 
 fn main() {
@@ -100,17 +107,39 @@ fn main() {
     match args.action {
         Action::Convert => {
             println!("file: {}", &args.file);
-            convert_file(&args.file);
+            match &args.output {
+                Some(output) => {
+                    let (data,r) = read_file(&args.file);
+                    write_file(output, &data, &r);
+                    println!("output: {}", output);
+                },
+                None => convert_file(&args.file),
+            }
         },
         Action::Naive|Action::Nlogn => {
             let start_time = SystemTime::now();
             println!("file: {}", &args.file);
-            let (data,r) = read_boa(&args.file);
+            let (data,r) = read_file(&args.file);
+            let meta = r.meta.clone();
+            if args.output.is_some() && args.direction == Direction::Backward {
+                panic!("--output is only supported for forward bisimulation.");
+            }
+            let input = args.output.as_ref().map(|_| (data.clone(), r.clone()));
             let parsing_time = start_time.elapsed().unwrap();
             println!("size_mb: {}", util::mb(data.len()));
             println!("parsing_time_s: {}", parsing_time.as_secs_f32());
             // start_time = SystemTime::now();
-            let ids = if args.action == Action::Naive {
+            let ids = if args.direction == Direction::Backward {
+                println!("algorithm: backward");
+                let preds = Predecessors::new(&data, &r).unwrap_or_else(|err| {
+                    eprintln!("error: {}", err);
+                    std::process::exit(1);
+                });
+                println!("m_edges: {}", preds.edges.len());
+                let (ids, iters) = refine_backward(&preds, &r.reals);
+                println!("iters: {}", iters);
+                ids
+            } else if args.action == Action::Naive {
                 println!("algorithm: naive");
                 partref_naive(&data, &r)
             } else {
@@ -121,6 +150,95 @@ fn main() {
             println!("n_states: {}", ids.len());
             println!("n_states_min: {}", ids.iter().max().unwrap()+1);
             // println!("selfreport_time_s: {}", computation_time.as_secs_f32());
+            if let Some(filename) = &args.partition {
+                write_partition(filename, &ids, &meta);
+                println!("partition: {}", filename);
+            }
+            if let (Some(filename), Some((data, r))) = (&args.output, &input) {
+                let (qdata, qr) = quotient(data, r, &ids);
+                write_file(filename, &qdata, &qr);
+                println!("output: {}", filename);
+            }
+        },
+        Action::Equiv => {
+            let file2 = args.file2.as_ref().expect("equiv needs two input files.");
+            println!("file_a: {}", &args.file);
+            println!("file_b: {}", file2);
+            let (data_a,r_a) = read_file(&args.file);
+            let (data_b,r_b) = read_file(file2);
+            let (x, y) = (find_state(&r_a.meta, &args.init_a), find_state(&r_b.meta, &args.init_b));
+            let (same, witness) = equiv(&data_a, &r_a, &data_b, &r_b, x, y);
+            println!("equivalent: {}", same);
+            if !same {
+                match witness {
+                    Some(lines) => for line in lines { println!("witness: {}", line) },
+                    None => println!("witness: unavailable"),
+                }
+            }
+        },
+        Action::Check => {
+            let start_time = SystemTime::now();
+            println!("file: {}", &args.file);
+            let (data,r) = read_file(&args.file);
+            let (x, y) = (find_state(&r.meta, &args.init_a), find_state(&r.meta, &args.init_b));
+            let coa = Coalg::new(data, r);
+            let res = check_onthefly(&coa, x, y);
+            println!("equivalent: {}", res.equivalent);
+            println!("method: {:?}", res.method);
+            println!("explored_pairs: {}", res.explored_pairs);
+            println!("explored_states: {}", res.explored_states);
+            println!("iters: {}", res.iters);
+            println!("selfreport_time_s: {}", start_time.elapsed().unwrap().as_secs_f32());
+        },
+        Action::Simulation => {
+            println!("file: {}", &args.file);
+            let (data,r) = read_file(&args.file);
+            let meta = r.meta.clone();
+            let (ids, rel) = simulation_preorder(data, r);
+            let mut blocks: Vec<Vec<u32>> = vec![vec![]; rel.num_blocks];
+            for (state, &block) in ids.iter().enumerate() { blocks[block as usize].push(state as u32) }
+            let pairs = rel.pairs();
+            println!("n_states: {}", ids.len());
+            println!("n_blocks: {}", rel.num_blocks);
+            println!("n_pairs: {}", pairs.len());
+            for (i, block) in blocks.iter().enumerate() {
+                println!("block {}: {}", i, block.iter().map(|&s| meta.state_ref(s)).join(" "));
+            }
+            for (a, b) in pairs {
+                println!("{} <= {}", a, b);
+            }
+        },
+        Action::Determinize => {
+            println!("file: {}", &args.file);
+            let (data,r) = read_file(&args.file);
+            let (det_data, det_r) = determinize(&data, &r, find_state(&r.meta, &args.init), args.max_states);
+            let new_filename = [file_stem(&args.file), ".det.boa"].concat();
+            write_boa(&new_filename, &det_data, &det_r);
+            println!("output: {}", new_filename);
+        },
+        Action::Dot => {
+            println!("file: {}", &args.file);
+            let (data,r) = read_file(&args.file);
+            let num_states = read_nodes(&data, &r).len();
+            let blocks = args.partition.as_ref().map(|p| read_partition(p, num_states, &r.meta));
+            let stem = file_stem(&args.file);
+            let name = std::path::Path::new(stem).file_name().map_or(stem.into(), |n| n.to_string_lossy());
+            // do not overwrite an input that is itself a DOT file
+            let ext = if stem.len() < args.file.len() && args.file.ends_with(".dot") { ".boa.dot" } else { ".dot" };
+            let new_filename = args.output.clone().unwrap_or_else(|| [stem, ext].concat());
+            let mut writer = std::io::BufWriter::new(create_file(&new_filename));
+            if args.quotient {
+                let ids = blocks.unwrap_or_else(|| partref_nlogn(data.clone(), r.clone()));
+                let (qdata, mut qr) = quotient(&data, &r, &ids);
+                // each state of the quotient is labelled with the states of its block
+                let mut members = vec![vec![]; ids.iter().max().map_or(0, |&m| m as usize + 1)];
+                for (s, &b) in ids.iter().enumerate() { members[b as usize].push(r.meta.state_ref(s as u32)) }
+                qr.meta.state_names = members.into_iter().map(|m| Some(m.join(" "))).collect();
+                write_dot(&mut writer, &name, &qdata, &qr, None);
+            } else {
+                write_dot(&mut writer, &name, &data, &r, blocks.as_deref());
+            }
+            println!("output: {}", new_filename);
         },
     }
 }
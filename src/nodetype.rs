@@ -0,0 +1,517 @@
+//============//
+// Node types //
+//============//
+
+// Every node type, built-in (List, Set, Add, ...) or plugged in by another crate, implements the NodeType trait
+// and is found by its type code with node_type. Other types are registered under an unused type code with
+// register_node_type, before any input is read.
+// A node is written as Name[tag]{e1:v1,e2:v2,...} and has the binary layout of a header followed by its elements,
+// where each element is a successor node followed by arity() values. The trait says how the values are parsed,
+// printed, encoded and decoded, how the successors are iterated over, which Node a decoded node becomes,
+// and how the signature of a node is computed from the signatures of its elements.
+// Constants and references to shared nodes have no elements, and are handled where they are read.
+
+use std::cmp::{max, min};
+use std::hash::Hash;
+use std::sync::OnceLock;
+
+use itertools::Itertools;
+use num_bigint::BigUint;
+use num_rational::Ratio;
+use num_traits::{CheckedAdd, ToPrimitive, Zero};
+
+use crate::binrep::{CReader, CWriter, Meta, Node, RealKeys, LIST_TYP, SET_TYP, ADD_TYP, MAX_TYP, OR_TYP, TAG_TYP, REAL_TYP, RAT_TYP, INT_TYP, MIN_TYP, MUL_TYP, CONST_TYP, REF_TYP, unzigzag};
+use crate::coalg::{add_op, int_op, mul_op};
+use crate::hmap::SigHasher;
+use crate::parsing::{self, Names};
+
+/// Maximum number of values that follow each element of a node
+pub const MAX_ARITY: usize = 2;
+
+/// The elements of a node being built, each with its values followed by zeros
+pub type ElemIter<'a> = dyn ExactSizeIterator<Item = (Node, [u64; MAX_ARITY])> + 'a;
+
+/// Collect the elements of a node being built.
+/// This is a plain loop because collect() does not use the exact length of a dyn iterator.
+pub fn map_elems<T>(elems: &mut ElemIter, mut f: impl FnMut(Node, [u64; MAX_ARITY]) -> T) -> Vec<T> {
+    let mut out = Vec::with_capacity(elems.len());
+    for (node, values) in elems { out.push(f(node, values)) }
+    return out;
+}
+
+/// Type codes must fit in the 6 bits available in a non-compressed header
+pub const NUM_TYPS: usize = 64;
+
+/// An element of a node during canonicalization: its signature and the values that follow it
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct Elem {
+    pub sig: u64,
+    pub values: [u64; MAX_ARITY], // only the first arity() values are used, the rest are 0
+}
+
+pub trait NodeType: Send + Sync {
+    /// The name in the text format, e.g. "Dist" for Dist[t]{...}. Must consist of ASCII letters.
+    fn name(&self) -> &str;
+
+    /// The number of values that follow each element, at most MAX_ARITY.
+    /// With arity 0 the elements are written without ':'.
+    fn arity(&self) -> usize;
+
+    /// Parse the arity() values of an element after the ':' into values, returning the number of bytes read.
+    /// By default the values are unsigned numbers separated by '/'.
+    fn parse_values(&self, inp: &[u8], _names: &mut Names, values: &mut [u64]) -> usize {
+        let mut n = 0;
+        for (i, v) in values.iter_mut().enumerate() {
+            if i > 0 {
+                if inp.get(n) != Some(&b'/') { panic!("Expected '/' between the values of a {} node.", self.name()) }
+                n += 1;
+            }
+            let (val,m) = lexical::parse_partial::<u64,_>(&inp[n..]).expect("Expected a number.");
+            *v = val;
+            n += m;
+        }
+        return n;
+    }
+
+    /// Print the values of an element after the ':', using the names in meta
+    fn print_values(&self, values: &[u64], _meta: &Meta, w: &mut Vec<u8>) {
+        for (i, v) in values.iter().enumerate() {
+            if i > 0 { w.push(b'/') }
+            w.extend(lexical::to_string(*v).as_bytes());
+        }
+    }
+
+    /// Write the values of an element after its successor
+    fn encode_values(&self, values: &[u64], w: &mut CWriter) {
+        for &v in values { w.write_value(v) }
+    }
+
+    /// Read the arity() values of an element that were written by encode_values, moving p past them
+    unsafe fn decode_values(&self, r: &CReader, p: &mut *const u8, values: &mut [u64]) {
+        for v in values.iter_mut() { *v = r.read_value_mut(p) }
+    }
+
+    /// Call f on the successor of each of the len elements of a node, which start at p.
+    /// f reads the successor at p and moves p past it. Afterwards p is at the end of the node.
+    unsafe fn for_each_successor(&self, r: &CReader, p: &mut *const u8, len: u16, f: &mut dyn FnMut(&mut *const u8)) {
+        let mut values = [0; MAX_ARITY];
+        for _ in 0..len {
+            f(p);
+            self.decode_values(r, p, &mut values[..self.arity()]);
+        }
+    }
+
+    /// The node with type code typ, the tag and the elements, each with arity() values followed by zeros.
+    /// The elements must all be taken from the iterator.
+    /// By default this is a Node::Ext.
+    fn make_node(&self, typ: u8, tag: u32, elems: &mut ElemIter) -> Node {
+        Node::Ext(typ, tag, map_elems(elems, |node, values| (node, values[..self.arity()].to_vec())))
+    }
+
+    /// Hash the signature of a node with the given tag and elements into the hasher.
+    /// Nodes that are equal according to the semantics of the type must hash the same,
+    /// e.g. the order of the elements should not matter for unordered types.
+    /// The type code and tag have already been hashed.
+    fn canonicalize(&self, tag: u32, elems: &mut [Elem], r: &CReader, hasher: &mut SigHasher);
+}
+
+//=================//
+// Built-in types //
+//=================//
+
+/// List and Set, whose elements have no values
+struct Coll { name: &'static str, ordered: bool }
+
+impl NodeType for Coll {
+    fn name(&self) -> &str { self.name }
+    fn arity(&self) -> usize { 0 }
+    fn make_node(&self, typ: u8, tag: u32, elems: &mut ElemIter) -> Node {
+        Node::Coll(typ, tag, map_elems(elems, |node, _| node))
+    }
+    fn canonicalize(&self, _tag: u32, elems: &mut [Elem], _r: &CReader, hasher: &mut SigHasher) {
+        if !self.ordered { elems.sort_unstable() }
+        let sigs = elems.iter().map(|elem| elem.sig);
+        if self.ordered {
+            for sig in sigs { sig.hash(hasher) }
+        } else {
+            for sig in sigs.dedup() { sig.hash(hasher) }
+        }
+    }
+}
+
+/// Add, Max, Or, Min and Mul, whose values are summed per block with op
+struct Monoid { name: &'static str, op: fn(u64, u64) -> u64 }
+
+impl NodeType for Monoid {
+    fn name(&self) -> &str { self.name }
+    fn arity(&self) -> usize { 1 }
+    fn make_node(&self, typ: u8, tag: u32, elems: &mut ElemIter) -> Node {
+        monoid_node(typ, tag, elems)
+    }
+    fn canonicalize(&self, _tag: u32, elems: &mut [Elem], _r: &CReader, hasher: &mut SigHasher) {
+        hash_with_op(elems, hasher, self.op)
+    }
+}
+
+/// A node of a built-in type with one value per element
+fn monoid_node(typ: u8, tag: u32, elems: &mut ElemIter) -> Node {
+    Node::Mon(typ, tag, map_elems(elems, |node, values| (node, values[0])))
+}
+
+/// Int, whose values are signed and stored zigzag encoded
+struct Int;
+
+impl NodeType for Int {
+    fn name(&self) -> &str { "Int" }
+    fn arity(&self) -> usize { 1 }
+    fn parse_values(&self, inp: &[u8], _names: &mut Names, values: &mut [u64]) -> usize {
+        let (v, n) = parsing::read_int(inp);
+        values[0] = v;
+        return n;
+    }
+    fn print_values(&self, values: &[u64], _meta: &Meta, w: &mut Vec<u8>) {
+        w.extend(lexical::to_string(unzigzag(values[0])).as_bytes());
+    }
+    fn make_node(&self, typ: u8, tag: u32, elems: &mut ElemIter) -> Node {
+        monoid_node(typ, tag, elems)
+    }
+    fn canonicalize(&self, _tag: u32, elems: &mut [Elem], _r: &CReader, hasher: &mut SigHasher) {
+        hash_with_op(elems, hasher, int_op)
+    }
+}
+
+/// Real, whose values are the bits of non-negative f64s
+struct Real;
+
+impl NodeType for Real {
+    fn name(&self) -> &str { "Real" }
+    fn arity(&self) -> usize { 1 }
+    fn parse_values(&self, inp: &[u8], _names: &mut Names, values: &mut [u64]) -> usize {
+        let (v, n) = parsing::read_real(inp);
+        values[0] = v;
+        return n;
+    }
+    fn print_values(&self, values: &[u64], _meta: &Meta, w: &mut Vec<u8>) {
+        w.extend(lexical::to_string(f64::from_bits(values[0])).as_bytes());
+    }
+    fn make_node(&self, typ: u8, tag: u32, elems: &mut ElemIter) -> Node {
+        monoid_node(typ, tag, elems)
+    }
+    fn canonicalize(&self, _tag: u32, elems: &mut [Elem], r: &CReader, hasher: &mut SigHasher) {
+        hash_real(elems, hasher, &r.reals)
+    }
+}
+
+/// Tag, whose values are labels that may have a name
+struct Labels;
+
+impl NodeType for Labels {
+    fn name(&self) -> &str { "Tag" }
+    fn arity(&self) -> usize { 1 }
+    fn parse_values(&self, inp: &[u8], names: &mut Names, values: &mut [u64]) -> usize {
+        let (v, n) = parsing::read_label(inp, names);
+        values[0] = v;
+        return n;
+    }
+    fn print_values(&self, values: &[u64], meta: &Meta, w: &mut Vec<u8>) {
+        match meta.label_names.get(&values[0]) {
+            Some(name) => parsing::write_name(name, w),
+            None => w.extend(lexical::to_string(values[0]).as_bytes()),
+        }
+    }
+    fn make_node(&self, typ: u8, tag: u32, elems: &mut ElemIter) -> Node {
+        monoid_node(typ, tag, elems)
+    }
+    fn canonicalize(&self, _tag: u32, elems: &mut [Elem], _r: &CReader, hasher: &mut SigHasher) {
+        elems.sort_unstable();
+        for (sig, label) in elems.iter().map(|elem| (elem.sig, elem.values[0])).dedup() { (sig, label).hash(hasher) }
+    }
+}
+
+/// Q, whose values are a numerator and a denominator in lowest terms
+struct Rational;
+
+impl NodeType for Rational {
+    fn name(&self) -> &str { "Q" }
+    fn arity(&self) -> usize { 2 }
+    fn parse_values(&self, inp: &[u8], _names: &mut Names, values: &mut [u64]) -> usize {
+        let (num, den, n) = parsing::read_fraction(inp);
+        values[0] = num;
+        values[1] = den;
+        return n;
+    }
+    fn print_values(&self, values: &[u64], _meta: &Meta, w: &mut Vec<u8>) {
+        w.extend(lexical::to_string(values[0]).as_bytes());
+        if values[1] != 1 {
+            w.push(b'/');
+            w.extend(lexical::to_string(values[1]).as_bytes());
+        }
+    }
+    fn make_node(&self, _typ: u8, tag: u32, elems: &mut ElemIter) -> Node {
+        Node::Rat(tag, map_elems(elems, |node, values| (node, values[0], values[1])))
+    }
+    fn canonicalize(&self, _tag: u32, elems: &mut [Elem], _r: &CReader, hasher: &mut SigHasher) {
+        hash_rat(elems, hasher)
+    }
+}
+
+static LIST: Coll = Coll { name: "List", ordered: true };
+static SET: Coll = Coll { name: "Set", ordered: false };
+static ADD: Monoid = Monoid { name: "Add", op: add_op };
+static MAX: Monoid = Monoid { name: "Max", op: max };
+static OR: Monoid = Monoid { name: "Or", op: |a, b| a | b };
+static MIN: Monoid = Monoid { name: "Min", op: min };
+static MUL: Monoid = Monoid { name: "Mul", op: mul_op };
+
+/// Names of the built-in node types that have elements, so that parsing doesn't need to ask each type for its name
+const BUILTIN_NAMES: [(u8, &str); 11] = [
+    (LIST_TYP, "List"), (SET_TYP, "Set"), (ADD_TYP, "Add"), (MAX_TYP, "Max"), (OR_TYP, "Or"), (TAG_TYP, "Tag"),
+    (REAL_TYP, "Real"), (RAT_TYP, "Q"), (INT_TYP, "Int"), (MIN_TYP, "Min"), (MUL_TYP, "Mul"),
+];
+
+fn builtin(typ: u8) -> Option<&'static dyn NodeType> {
+    return match typ {
+        LIST_TYP => Some(&LIST),
+        SET_TYP => Some(&SET),
+        ADD_TYP => Some(&ADD),
+        MAX_TYP => Some(&MAX),
+        OR_TYP => Some(&OR),
+        TAG_TYP => Some(&Labels),
+        REAL_TYP => Some(&Real),
+        RAT_TYP => Some(&Rational),
+        INT_TYP => Some(&Int),
+        MIN_TYP => Some(&MIN),
+        MUL_TYP => Some(&MUL),
+        _ => None,
+    };
+}
+
+/// Hash the elements with their values summed per signature with op
+pub fn hash_with_op<F>(elems: &mut [Elem], hasher: &mut SigHasher, op: F)
+where F : Fn(u64,u64) -> u64 {
+    elems.sort_unstable_by_key(|elem| elem.sig);
+    let mut i = 0;
+    while i < elems.len() {
+        let x = elems[i].sig;
+        let mut vtot = elems[i].values[0];
+        i += 1;
+        while i < elems.len() && elems[i].sig == x {
+            vtot = op(vtot, elems[i].values[0]);
+            i += 1;
+        }
+        (x,vtot).hash(hasher);
+    }
+}
+
+/// Real weights are summed per block, and the sums are hashed by their key in reals
+fn hash_real(elems: &mut [Elem], hasher: &mut SigHasher, reals: &RealKeys) {
+    // Summing in sorted order makes the result independent of the order of the weights in the node.
+    elems.sort_unstable();
+    let mut i = 0;
+    while i < elems.len() {
+        let x = elems[i].sig;
+        let mut vtot = 0.0;
+        while i < elems.len() && elems[i].sig == x {
+            vtot += f64::from_bits(elems[i].values[0]);
+            i += 1;
+        }
+        (x,reals.key(vtot)).hash(hasher);
+    }
+}
+
+/// Rational weights are summed per block exactly. The sum is computed with 128 bit integers,
+/// switching to big integers if that overflows. Both give the same hash for the same fraction.
+fn hash_rat(elems: &mut [Elem], hasher: &mut SigHasher) {
+    elems.sort_unstable_by_key(|elem| elem.sig);
+    let mut i = 0;
+    while i < elems.len() {
+        let x = elems[i].sig;
+        let mut small: Option<Ratio<u128>> = Some(Ratio::zero());
+        let mut big: Ratio<BigUint> = Ratio::zero();
+        while i < elems.len() && elems[i].sig == x {
+            let [num, den] = elems[i].values;
+            small = match small {
+                Some(q) => {
+                    let sum = q.checked_add(&Ratio::new_raw(num as u128, den as u128));
+                    if sum.is_none() { big = Ratio::new(BigUint::from(*q.numer()), BigUint::from(*q.denom())) }
+                    sum
+                },
+                None => None,
+            };
+            if small.is_none() { big += Ratio::new(BigUint::from(num), BigUint::from(den)) }
+            i += 1;
+        }
+        let fits = match &small {
+            Some(q) => Some((*q.numer(), *q.denom())),
+            None => big.numer().to_u128().zip(big.denom().to_u128()),
+        };
+        match fits {
+            Some((num, den)) => (x,num,den).hash(hasher),
+            None => (x,big.numer().to_bytes_le(),big.denom().to_bytes_le()).hash(hasher),
+        }
+    }
+}
+
+#[test]
+fn test_hash_rat() {
+    use std::hash::Hasher;
+    use crate::hmap::new_hasher;
+    let hash = |repr: &[(u64,u64,u64)]| {
+        let mut elems: Vec<Elem> = repr.iter().map(|&(sig, num, den)| Elem { sig, values: [num, den] }).collect();
+        let mut h = new_hasher();
+        hash_rat(&mut elems, &mut h);
+        h.finish()
+    };
+    assert_eq!(hash(&[(0,1,3),(1,1,2),(0,1,3)]), hash(&[(1,1,2),(0,2,3)]));
+    assert_ne!(hash(&[(0,1,3),(0,1,3)]), hash(&[(0,1,3)]));
+    // sums that overflow 128 bits
+    let (p1, p2, p3) = (u64::MAX - 58, u64::MAX - 82, u64::MAX - 94);
+    assert_eq!(hash(&[(0,1,p1),(0,1,p2),(0,1,p3)]), hash(&[(0,1,p3),(0,1,p1),(0,1,p2)]));
+    assert_ne!(hash(&[(0,1,p1),(0,1,p2),(0,1,p3)]), hash(&[(0,1,p1),(0,1,p2),(0,2,p3)]));
+    assert_eq!(hash(&[(0,1,p1),(0,1,p2),(0,p1-1,p1)]), hash(&[(0,1,1),(0,1,p2)]));
+}
+
+//==========//
+// Registry //
+//==========//
+
+static REGISTRY: [OnceLock<&'static dyn NodeType>; NUM_TYPS] = [const { OnceLock::new() }; NUM_TYPS];
+
+pub fn is_builtin(typ: u8) -> bool {
+    typ == CONST_TYP || typ == REF_TYP || builtin(typ).is_some()
+}
+
+/// Register a node type under the type code typ, e.g. register_node_type(60, &Bag) for a unit struct Bag,
+/// or with Box::leak for a type that has data.
+/// Panics if the code is taken by a built-in or previously registered type, or the name is already in use.
+pub fn register_node_type(typ: u8, node_type: &'static dyn NodeType) {
+    if typ as usize >= NUM_TYPS { panic!("Type code {} is too large, must be below {}.", typ, NUM_TYPS) }
+    if is_builtin(typ) { panic!("Type code {} belongs to a built-in node type.", typ) }
+    if node_type.arity() > MAX_ARITY { panic!("Node type {} has arity {}, at most {} is supported.", node_type.name(), node_type.arity(), MAX_ARITY) }
+    if node_type.name().is_empty() || !node_type.name().bytes().all(|c| c.is_ascii_alphabetic()) {
+        panic!("Node type name {:?} must consist of ASCII letters.", node_type.name())
+    }
+    if typ_from_name(node_type.name().as_bytes()).is_some() { panic!("Node type name {} is already in use.", node_type.name()) }
+    if REGISTRY[typ as usize].set(node_type).is_err() { panic!("Type code {} is already registered.", typ) }
+}
+
+/// The built-in or registered node type with type code typ, if any
+pub fn node_type(typ: u8) -> Option<&'static dyn NodeType> {
+    builtin(typ).or_else(|| REGISTRY.get(typ as usize)?.get().copied())
+}
+
+/// The name of a built-in or registered node type
+pub fn typ_name(typ: u8) -> String {
+    node_type(typ).unwrap_or_else(|| panic!("Unknown typ {}.", typ)).name().to_string()
+}
+
+/// The type code of a built-in or registered node type with the given name
+pub fn typ_from_name(name: &[u8]) -> Option<u8> {
+    if let Some((typ, _)) = BUILTIN_NAMES.iter().find(|(_, n)| n.as_bytes() == name) {
+        return Some(*typ);
+    }
+    REGISTRY.iter().position(|t| t.get().is_some_and(|t| t.name().as_bytes() == name)).map(|typ| typ as u8)
+}
+
+#[cfg(test)]
+mod test_types {
+    use std::hash::{Hash, Hasher};
+    use std::sync::Once;
+    use itertools::Itertools;
+    use crate::binrep::CReader;
+    use crate::hmap::SigHasher;
+    use super::{register_node_type, Elem, NodeType};
+
+    /// Multiset of successors: unlike Set, the multiplicities matter
+    struct Bag;
+
+    impl NodeType for Bag {
+        fn name(&self) -> &str { "Bag" }
+        fn arity(&self) -> usize { 0 }
+        fn canonicalize(&self, _tag: u32, elems: &mut [Elem], _r: &CReader, hasher: &mut SigHasher) {
+            elems.sort_unstable();
+            for elem in elems.iter() { hasher.write_u64(elem.sig) }
+        }
+    }
+
+    /// Weights in the vector space over GF(2): values are combined with xor, and zero weights are dropped
+    struct Xor;
+
+    impl NodeType for Xor {
+        fn name(&self) -> &str { "Xor" }
+        fn arity(&self) -> usize { 1 }
+        fn canonicalize(&self, _tag: u32, elems: &mut [Elem], _r: &CReader, hasher: &mut SigHasher) {
+            elems.sort_unstable();
+            for (sig, group) in &elems.iter().group_by(|elem| elem.sig) {
+                let v = group.fold(0, |acc, elem| acc ^ elem.values[0]);
+                if v != 0 { (sig, v).hash(hasher) }
+            }
+        }
+    }
+
+    pub const BAG_TYP: u8 = 60;
+    pub const XOR_TYP: u8 = 61;
+
+    static REGISTER: Once = Once::new();
+
+    pub fn register() {
+        REGISTER.call_once(|| {
+            register_node_type(BAG_TYP, &Bag);
+            register_node_type(XOR_TYP, &Xor);
+        });
+    }
+}
+
+#[test]
+fn test_node_type_ascii_binary() {
+    use crate::binrep::{CWriter, Node};
+    test_types::register();
+    let node_str = "List[0]{Bag[1]{@0,@0,Xor[2]{@1:3}},Xor[3]{}}";
+    let node = Node::from_ascii(node_str.as_bytes());
+    assert_eq!(node, Node::Coll(LIST_TYP, 0, vec![
+        Node::Ext(test_types::BAG_TYP, 1, vec![(Node::State(0), vec![]), (Node::State(0), vec![]),
+            (Node::Ext(test_types::XOR_TYP, 2, vec![(Node::State(1), vec![3])]), vec![])]),
+        Node::Ext(test_types::XOR_TYP, 3, vec![])]));
+    let mut out = vec![];
+    node.to_ascii(&mut out);
+    assert_eq!(String::from_utf8(out).unwrap(), node_str);
+    let mut w = CWriter::new();
+    node.write(&mut w);
+    let (data, r) = w.finish();
+    unsafe {
+        assert_eq!(node, Node::read(&r, &mut data.as_ptr()));
+    }
+    assert_eq!(typ_from_name(b"Xor"), Some(test_types::XOR_TYP));
+    assert_eq!(typ_name(test_types::BAG_TYP), "Bag");
+}
+
+#[test]
+fn test_node_type_partition() {
+    // Bag[0]{@2,@2,@3}
+    // Bag[0]{@3,@2,@2}
+    // Bag[0]{@2,@3}
+    // Xor[0]{@3:5,@4:3}
+    // Xor[0]{@4:6}
+    // Xor[0]{@5:6,@5:6}
+    test_types::register();
+    let (data, r) = crate::io::read_boa_txt("tests/nodetype_test.boa.txt");
+    let ids1 = crate::naivealg::partref_naive(&data, &r);
+    let ids2 = crate::optalg::partref_nlogn(data, r);
+    assert_eq!(&crate::renumber(&ids1), &ids2);
+    assert_eq!(&ids2, &vec![0,0,1,2,2,3]);
+}
+
+#[test]
+fn test_builtin_names() {
+    for (typ, name) in BUILTIN_NAMES {
+        assert_eq!(node_type(typ).unwrap().name(), name);
+        assert_eq!(typ_from_name(name.as_bytes()), Some(typ));
+    }
+}
+
+#[test]
+#[should_panic(expected = "built-in")]
+fn test_register_builtin_code() {
+    test_types::register();
+    register_node_type(SET_TYP, node_type(test_types::BAG_TYP).unwrap());
+}
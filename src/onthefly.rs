@@ -0,0 +1,341 @@
+//=============================================//
+// On-the-fly equivalence check of two states //
+//=============================================//
+
+// Checking whether two particular states are equivalent doesn't require the whole partition.
+// We try to build a bisimulation up to congruence in the style of HKC (Bonchi & Pous):
+// assume that the two states are equivalent, and match up their nodes according to the semantics of each node type.
+// Where the matching determines which successors must be equivalent, e.g. for a List or a Tag with distinct labels,
+// we only explore those pairs, and a mismatch means that the two states are not equivalent.
+// Other nodes, such as a Set or an Add with several elements, are first compared modulo the equivalence
+// that has been assumed so far, which is the congruence closure step that keeps the explored part small.
+// If they differ modulo that equivalence, we guess which of their elements are equivalent, pairing up elements
+// of the same shape, and explore those pairs too. Once nothing is left to explore, the guessed nodes are compared
+// again modulo the final equivalence. A mismatch that depends on a guess is inconclusive, and then we fall back to
+// refining the fragment of the coalgebra that is reachable from the two states with partref_nlogn.
+
+use std::collections::VecDeque;
+
+use crate::binrep::{real_eq, CWriter, Node, LIST_TYP, SET_TYP, ADD_TYP, MAX_TYP, OR_TYP, TAG_TYP, REAL_TYP, INT_TYP, MIN_TYP, MUL_TYP};
+use crate::coalg::{Coalg, canonicalize_unsafe_init, node_signature};
+use crate::hmap::HMap;
+use crate::optalg::refine_nlogn;
+
+#[cfg(test)]
+use crate::{io::read_boa_txt, optalg::partref_nlogn};
+
+/// Match the nodes n1 and n2, collecting the pairs of states that must be equivalent for the nodes to be equal,
+/// and the pairs of sub-nodes whose matching depends on which of their elements are equivalent.
+/// Returns false if the nodes are never equal.
+fn match_nodes<'a>(n1: &'a Node, n2: &'a Node, pairs: &mut Vec<(u32,u32)>, open: &mut Vec<(&'a Node, &'a Node)>) -> bool {
+    match (n1, n2) {
+        (Node::State(a), Node::State(b)) => {
+            pairs.push((*a, *b));
+            true
+        },
+        (Node::Coll(typ1, tag1, nodes1), Node::Coll(typ2, tag2, nodes2)) => {
+            if typ1 != typ2 || tag1 != tag2 { return false }
+            match *typ1 {
+                LIST_TYP => {
+                    if nodes1.len() != nodes2.len() { return false }
+                    nodes1.iter().zip(nodes2.iter()).all(|(c1, c2)| match_nodes(c1, c2, pairs, open))
+                },
+                SET_TYP => {
+                    match (nodes1.len(), nodes2.len()) {
+                        (0, 0) => true,
+                        (0, _) | (_, 0) => false,
+                        (1, 1) => match_nodes(&nodes1[0], &nodes2[0], pairs, open),
+                        _ => { open.push((n1, n2)); true }
+                    }
+                },
+                _ => panic!("Unknown typ.")
+            }
+        },
+        (Node::Mon(typ1, tag1, nodes1), Node::Mon(typ2, tag2, nodes2)) => {
+            if typ1 != typ2 || tag1 != tag2 { return false }
+            match *typ1 {
+                ADD_TYP | MAX_TYP | OR_TYP | REAL_TYP | INT_TYP | MIN_TYP | MUL_TYP => {
+                    let same_weight = |v1: u64, v2: u64| {
+                        if *typ1 == REAL_TYP { real_eq(f64::from_bits(v1), f64::from_bits(v2)) } else { v1 == v2 }
+                    };
+                    match (nodes1.len(), nodes2.len()) {
+                        (0, 0) => true,
+                        (0, _) | (_, 0) => false,
+                        (1, 1) => same_weight(nodes1[0].1, nodes2[0].1) && match_nodes(&nodes1[0].0, &nodes2[0].0, pairs, open),
+                        _ => { open.push((n1, n2)); true }
+                    }
+                },
+                TAG_TYP => {
+                    // With distinct labels on both sides the successors are determined by their label
+                    let mut labels1: Vec<&(Node,u64)> = nodes1.iter().collect();
+                    let mut labels2: Vec<&(Node,u64)> = nodes2.iter().collect();
+                    labels1.sort_by_key(|kv| kv.1);
+                    labels2.sort_by_key(|kv| kv.1);
+                    let distinct = |labels: &[&(Node,u64)]| labels.windows(2).all(|w| w[0].1 != w[1].1);
+                    if !distinct(&labels1) || !distinct(&labels2) {
+                        open.push((n1, n2));
+                        return true
+                    }
+                    if labels1.len() != labels2.len() || labels1.iter().zip(labels2.iter()).any(|(a,b)| a.1 != b.1) {
+                        return false
+                    }
+                    labels1.iter().zip(labels2.iter()).all(|(kv1, kv2)| match_nodes(&kv1.0, &kv2.0, pairs, open))
+                },
+                _ => panic!("Unknown typ.")
+            }
+        },
+        (Node::Rat(tag1, nodes1), Node::Rat(tag2, nodes2)) => {
+            if tag1 != tag2 { return false }
+            match (nodes1.len(), nodes2.len()) {
+                (0, 0) => true,
+                (0, _) | (_, 0) => false,
+                // weights are stored in lowest terms
+                (1, 1) => (nodes1[0].1, nodes1[0].2) == (nodes2[0].1, nodes2[0].2) && match_nodes(&nodes1[0].0, &nodes2[0].0, pairs, open),
+                _ => { open.push((n1, n2)); true }
+            }
+        },
+        (Node::Const(a), Node::Const(b)) => a == b,
+        (Node::Ext(typ1, tag1, _), Node::Ext(typ2, tag2, _)) => {
+            // the semantics of user-defined types is only known through their signatures
+            if typ1 != typ2 || tag1 != tag2 { return false }
+            open.push((n1, n2));
+            true
+        },
+        _ => false
+    }
+}
+
+/// Guess which elements of the nodes u and v are equivalent, pushing the guessed pairs onto todo.
+/// Each element is paired with an element of the other node with the same shape, preferring one that is already
+/// equivalent to it and then one with the same values.
+/// Returns false if some element has no element of the same shape in the other node.
+fn guess_pairs<F, G>(u: &Node, v: &Node, coa: &Coalg, leaf: &F, shape: &G, todo: &mut Vec<(Node, Node, bool)>) -> bool
+where F: Fn(u32) -> u64, G: Fn(u32) -> u64 {
+    let elems = |node: &Node| {
+        let mut elems = vec![];
+        node.for_each_elem(|elem, values| {
+            elems.push((elem.clone(), values.to_vec(), node_signature(elem, &coa.reader, leaf), node_signature(elem, &coa.reader, shape)))
+        });
+        elems
+    };
+    let (elems1, elems2) = (elems(u), elems(v));
+    for (elems, others) in [(&elems1, &elems2), (&elems2, &elems1)] {
+        for (elem, values, sig, shape) in elems {
+            let candidates: Vec<_> = others.iter().filter(|other| other.3 == *shape).collect();
+            if candidates.is_empty() { return false }
+            if candidates.iter().any(|other| other.2 == *sig) { continue }
+            let other = candidates.iter().find(|other| other.1 == *values).unwrap_or(&candidates[0]);
+            todo.push((elem.clone(), other.0.clone(), true));
+        }
+    }
+    return true;
+}
+
+struct UnionFind {
+    parent: HMap<u32,u32>,
+}
+
+impl UnionFind {
+    fn find(self: &mut Self, x: u32) -> u32 {
+        let root = self.root(x);
+        // path compression
+        let mut y = x;
+        while let Some(&p) = self.parent.get(&y) {
+            if p != root { self.parent.insert(y, root); }
+            y = p;
+        }
+        return root;
+    }
+
+    /// Like find, but without path compression
+    fn root(self: &Self, x: u32) -> u32 {
+        let mut root = x;
+        while let Some(&p) = self.parent.get(&root) { root = p }
+        return root;
+    }
+
+    fn union(self: &mut Self, x: u32, y: u32) {
+        let (rx, ry) = (self.find(x), self.find(y));
+        if rx != ry { self.parent.insert(rx, ry); }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Method {
+    UpToCongruence, // decided by the bisimulation up to congruence
+    LocalRefinement, // decided by refining the reachable fragment
+}
+
+pub struct CheckResult {
+    pub equivalent: bool,
+    pub method: Method,
+    pub explored_pairs: usize,
+    pub explored_states: usize,
+    pub iters: usize,
+}
+
+fn read_state(coa: &Coalg, state: u32) -> Node {
+    let mut p = coa.locs[state as usize];
+    unsafe { Node::read(&coa.reader, &mut p) }
+}
+
+/// The signature of a state where all states are considered equal, which differs for states that are not equivalent
+fn shape(coa: &Coalg, state: u32) -> u64 {
+    unsafe { canonicalize_unsafe_init(coa.locs[state as usize], &coa.reader).0 }
+}
+
+/// Check whether states x and y of the coalgebra are equivalent, exploring only what is needed
+pub fn check_onthefly(coa: &Coalg, x: u32, y: u32) -> CheckResult {
+    let n = coa.num_states();
+    if x >= n || y >= n { panic!("State out of range, the coalgebra has {} states.", n) }
+    let mut uf = UnionFind { parent: HMap::default() };
+    // pairs of states or sub-nodes that must be equal, and whether that depends on a guess
+    let mut todo = vec![(Node::State(x), Node::State(y), false)];
+    // nodes whose elements were guessed to be equivalent, which must be equal modulo the final equivalence
+    let mut guessed = vec![];
+    let mut pairs = vec![];
+    let mut explored_pairs = 0;
+    let fall_back = |explored_pairs| CheckResult { explored_pairs, ..check_local(coa, x, y) };
+    while let Some((n1, n2, is_guess)) = todo.pop() {
+        let (n1, n2) = match (n1, n2) {
+            (Node::State(a), Node::State(b)) => {
+                if uf.find(a) == uf.find(b) { continue }
+                explored_pairs += 1;
+                uf.union(a, b);
+                (read_state(coa, a), read_state(coa, b))
+            },
+            nodes => nodes,
+        };
+        pairs.clear();
+        let mut open = vec![];
+        if !match_nodes(&n1, &n2, &mut pairs, &mut open) {
+            if is_guess { return fall_back(explored_pairs) }
+            return CheckResult { equivalent: false, method: Method::UpToCongruence, explored_pairs, explored_states: 0, iters: 0 }
+        }
+        todo.extend(pairs.iter().map(|&(a, b)| (Node::State(a), Node::State(b), is_guess)));
+        for &(u, v) in &open {
+            let leaf = |state| uf.root(state) as u64;
+            if node_signature(u, &coa.reader, &leaf) == node_signature(v, &coa.reader, &leaf) { continue }
+            if !guess_pairs(u, v, coa, &leaf, &|state| shape(coa, state), &mut todo) { return fall_back(explored_pairs) }
+            guessed.push((u.clone(), v.clone()));
+        }
+    }
+    // Equivalent elements don't make weighted nodes equal when the weights per class differ
+    let leaf = |state| uf.root(state) as u64;
+    if guessed.iter().any(|(u, v)| node_signature(u, &coa.reader, &leaf) != node_signature(v, &coa.reader, &leaf)) {
+        return fall_back(explored_pairs)
+    }
+    return CheckResult { equivalent: true, method: Method::UpToCongruence, explored_pairs, explored_states: 0, iters: 0 }
+}
+
+/// Refine the fragment of the coalgebra reachable from x and y with partref_nlogn
+fn check_local(coa: &Coalg, x: u32, y: u32) -> CheckResult {
+    // Before exploring anything, check whether the shapes of the two states already differ
+    if shape(coa, x) != shape(coa, y) {
+        return CheckResult { equivalent: false, method: Method::LocalRefinement, explored_pairs: 0, explored_states: 2, iters: 0 }
+    }
+
+    // Collect the reachable states and number them in the order in which they are found
+    let mut local: HMap<u32,u32> = HMap::default();
+    let mut order = vec![];
+    let mut queue = VecDeque::new();
+    for s in [x, y] {
+        if !local.contains_key(&s) {
+            local.insert(s, order.len() as u32);
+            order.push(s);
+            queue.push_back(s);
+        }
+    }
+    let mut nodes = vec![];
+    let mut succs = vec![];
+    while let Some(s) = queue.pop_front() {
+        let node = read_state(coa, s);
+        succs.clear();
+        node.successors(&mut succs);
+        for &t in &succs {
+            if !local.contains_key(&t) {
+                local.insert(t, order.len() as u32);
+                order.push(t);
+                queue.push_back(t);
+            }
+        }
+        nodes.push(node);
+    }
+
+    // Re-encode the fragment with the local numbering
+    let mut w = CWriter::new();
+    for node in nodes.iter_mut() {
+        node.map_states(&|t| local[&t]);
+        node.write(&mut w);
+    }
+    let (data, r) = w.finish();
+    let (partition, iters) = refine_nlogn(&Coalg::new(data, r));
+    let ids = &partition.state2block;
+    return CheckResult { equivalent: ids[local[&x] as usize] == ids[local[&y] as usize], method: Method::LocalRefinement, explored_pairs: 0, explored_states: order.len(), iters }
+}
+
+#[test]
+fn test_check_onthefly() {
+    // 0: List[0]{@0,@1}
+    // 1: List[0]{@1,@1}
+    // 2: List[1]{@0,@0}
+    // 3: List[1]{@0,@0}
+    // 4: List[1]{@3,@4}
+    // 5: Add[0]{@0:1,@1:1}
+    // 6: Add[0]{@0:2}
+    // 7: Add[0]{@0:2,@1:1}
+    let (data,r) = read_boa_txt("tests/test1.boa.txt");
+    let coa = Coalg::new(data, r);
+    let res = check_onthefly(&coa, 0, 1);
+    assert!(res.equivalent);
+    assert_eq!(res.method, Method::UpToCongruence);
+    let res = check_onthefly(&coa, 2, 4);
+    assert!(!res.equivalent);
+    assert_eq!(res.method, Method::UpToCongruence);
+    // the weights only agree once 0 and 1 are assumed to be equivalent
+    let res = check_onthefly(&coa, 5, 6);
+    assert!(res.equivalent);
+    assert_eq!(res.method, Method::UpToCongruence);
+    let res = check_onthefly(&coa, 5, 7);
+    assert!(!res.equivalent);
+    assert_eq!(res.method, Method::LocalRefinement);
+}
+
+#[test]
+fn test_check_onthefly_nondeterministic() {
+    // 0: Tag[0]{@1:0,@2:0}
+    // 1: Tag[0]{@1:1}
+    // 2: Tag[0]{@3:1}
+    // 3: Tag[0]{@2:1}
+    // 4: Tag[0]{@2:0,@3:0,@1:0}
+    // 5: Tag[0]{@1:0,@6:0}
+    // 6: Tag[0]{}
+    // 7: Real[0]{@1:0.5,@2:0.5}
+    // 8: Real[0]{@3:1.0}
+    // 9: Real[0]{@1:0.5,@6:0.5}
+    let (data,r) = read_boa_txt("tests/onthefly_test.boa.txt");
+    let coa = Coalg::new(data, r);
+    for (x, y, equivalent, method) in [(0, 4, true, Method::UpToCongruence), (7, 8, true, Method::UpToCongruence),
+                                       (0, 5, false, Method::LocalRefinement), (7, 9, false, Method::LocalRefinement)] {
+        let res = check_onthefly(&coa, x, y);
+        assert_eq!((res.equivalent, res.method), (equivalent, method), "@{} @{}", x, y);
+    }
+    // only the pairs of states that are reachable from 0 and 4 are explored
+    assert!(check_onthefly(&coa, 0, 4).explored_pairs <= 4);
+}
+
+#[test]
+fn test_check_onthefly_agrees_with_nlogn() {
+    let files = (1..=10).map(|i| format!("tests/paper_test{}.boa.txt", i))
+        .chain(["const_test", "onthefly_test", "simulation_test", "real_test", "rat_test"].map(|name| format!("tests/{}.boa.txt", name)));
+    for filename in files {
+        let (data,r) = read_boa_txt(&filename);
+        let coa = Coalg::new(data.clone(), r.clone());
+        let ids = partref_nlogn(data, r);
+        for x in 0..coa.num_states() {
+            for y in 0..coa.num_states() {
+                assert_eq!(check_onthefly(&coa, x, y).equivalent, ids[x as usize] == ids[y as usize], "{} @{} @{}", filename, x, y);
+            }
+        }
+    }
+}
@@ -3,25 +3,15 @@ use std::time::SystemTime;
 use datasize::data_size;
 
 #[cfg(test)]
-use crate::{naivealg::partref_naive, io::read_boa_txt};
+use crate::{naivealg::partref_naive, io::read_boa_txt, binrep::{Node, CWriter}};
 
 use crate::{binrep::CReader, refpart::RefinablePartition, coalg::repartition_unsafe, coalg::Coalg, renumber, util, };
 
-fn partref_nlogn_raw(data: Vec<u8>, r: CReader) -> Vec<u32> {
-  // println!("===================== Starting partref_nlogn");
-  // panic!("Stopped");
-  let start_time = SystemTime::now();
-  // print!("Initializing backrefs...");
-  let coa = Coalg::new(data, r);
-  let backrefs_time = start_time.elapsed().unwrap();
-  println!("backrefs_time_s: {}", backrefs_time.as_secs_f32());
-  // coa.dump();
-  // coa.dump_backrefs();
-  println!("m_edges: {}", coa.backrefs.len());
+/// Refine the partition of the states of the coalgebra until it is stable, returning it and the number of iterations
+pub fn refine_nlogn(coa: &Coalg) -> (RefinablePartition, usize) {
   let mut iters = 0;
   let mut partition = RefinablePartition::new(coa.num_states());
 
-  let start_time_iters = SystemTime::now();
   while let Some(block_id) = if false { partition.worklist.pop_front() } else { partition.worklist.pop_back() } {
 
       // let (start,mid,end) = partition.partition[block_id as usize];
@@ -29,7 +19,7 @@ fn partref_nlogn_raw(data: Vec<u8>, r: CReader) -> Vec<u32> {
 
       let states = partition.refiners(block_id);
       // println!("states = {:?}", states);
-      let signatures = renumber::<u64>(&repartition_unsafe(&coa, states, &partition.state2block));
+      let signatures = renumber::<u64>(&repartition_unsafe(coa, states, &partition.state2block));
       // println!("partition id = {:?}, partition = {:?}, states = {:?}, sigs = {:?}", block_id, partition.partition[block_id as usize], states, &signatures);
       let new_blocks = partition.refine(block_id, &signatures);
       // println!("shrunk partition = {:?}, new partitions = {:?}, buffer = {:?}", partition.partition[block_id as usize], &new_partitions.iter().map(|pid| partition.partition[*pid as usize]).collect::<Vec<(u32,u32,u32)>>(), &partition.buffer);
@@ -47,6 +37,22 @@ fn partref_nlogn_raw(data: Vec<u8>, r: CReader) -> Vec<u32> {
       }
       iters += 1;
   }
+  return (partition, iters);
+}
+
+fn partref_nlogn_raw(data: Vec<u8>, r: CReader) -> Vec<u32> {
+  // println!("===================== Starting partref_nlogn");
+  // panic!("Stopped");
+  let start_time = SystemTime::now();
+  // print!("Initializing backrefs...");
+  let coa = Coalg::new(data, r);
+  let backrefs_time = start_time.elapsed().unwrap();
+  println!("backrefs_time_s: {}", backrefs_time.as_secs_f32());
+  // coa.dump();
+  // coa.dump_backrefs();
+  println!("m_edges: {}", coa.backrefs.len());
+  let start_time_iters = SystemTime::now();
+  let (partition, iters) = refine_nlogn(&coa);
   println!("iters: {} ", iters);
   // println!("coalg_input_mb: {}", util::mb(data_size(&coa.data)));
   println!("coalg_refs_mb: {}", util::mb(data_size(&coa) - data_size(&coa.data)));
@@ -83,6 +89,78 @@ fn test_partref_nlogn() {
   assert_eq!(&ids, &vec![0,1,2,3,4,5]);
 }
 
+#[test]
+fn test_partref_real() {
+  // Real[0]{@0:0.1,@0:0.2}
+  // Real[0]{@1:0.3}
+  // Real[0]{@0:0.5}
+  // Real[0]{@2:0.25,@0:0.25,@2:0.25,@1:0.25}
+  // Real[0]{@2:0.5,@1:0.5}
+  let (data,r) = read_boa_txt("tests/real_test.boa.txt");
+  let ids1 = partref_naive(&data, &r);
+  let ids2 = partref_nlogn(data, r);
+  assert_eq!(&renumber(&ids1), &ids2);
+  assert_eq!(&ids2, &vec![0,0,1,2,2]);
+}
+
+#[test]
+fn test_partref_real_large_and_close() {
+  // Real[0]{@0:1e10}
+  // Real[0]{@0:1e11}
+  // Real[0]{@0:1.5e-9}, 1.5e-9 was a rounding boundary with fixed buckets
+  // Real[0]{@0:1.4999999999e-9,@0:1e-20}
+  // Real[0]{@0:1.500000000001e-9}
+  let mut w = CWriter::new();
+  for weights in [vec![1e10], vec![1e11], vec![1.5e-9], vec![1.4999999999e-9, 1e-20], vec![1.500000000001e-9]] {
+    Node::Mon(crate::binrep::REAL_TYP, 0, weights.iter().map(|v: &f64| (Node::State(0), v.to_bits())).collect()).write(&mut w);
+  }
+  let (data, r) = w.finish();
+  let ids1 = partref_naive(&data, &r);
+  let ids2 = partref_nlogn(data, r);
+  assert_eq!(&renumber(&ids1), &ids2);
+  assert_eq!(&ids2, &vec![0,1,2,2,2]);
+}
+
+#[test]
+fn test_partref_rat() {
+  // Q[0]{@0:1/3,@1:1/3,@2:1/3}
+  // Q[0]{@0:2/3,@2:1/3}
+  // Q[1]{@2:1}
+  // Q[0]{@3:1/2,@2:1/2}
+  let (data,r) = read_boa_txt("tests/rat_test.boa.txt");
+  let ids1 = partref_naive(&data, &r);
+  let ids2 = partref_nlogn(data, r);
+  assert_eq!(&renumber(&ids1), &ids2);
+  assert_eq!(&ids2, &vec![0,0,1,2]);
+}
+
+#[test]
+fn test_partref_int_min_mul() {
+  // Int[0]{@0:-1,@1:3}
+  // Int[0]{@1:2}
+  // Int[0]{@0:-1}
+  // Min[0]{@3:3,@4:5}
+  // Min[0]{@3:3}
+  // Mul[0]{@5:2,@6:3}
+  // Mul[0]{@6:6}
+  // Mul[0]{@5:5}
+  let (data,r) = read_boa_txt("tests/int_test.boa.txt");
+  let ids1 = partref_naive(&data, &r);
+  let ids2 = partref_nlogn(data, r);
+  assert_eq!(&renumber(&ids1), &ids2);
+  assert_eq!(&ids2, &vec![0,0,1,2,2,3,3,4]);
+}
+
+#[test]
+#[should_panic(expected = "Overflow")]
+fn test_partref_add_overflow() {
+  let node = Node::from_ascii("Add[0]{@0:18446744073709551615,@0:1}".as_bytes());
+  let mut w = CWriter::new();
+  node.write(&mut w);
+  let (data,r) = w.finish();
+  partref_nlogn(data, r);
+}
+
 #[test]
 fn test_partref_wlan() {
   let filename = "tests/small/wlan0_time_bounded.nm_TRANS_TIME_MAX=10,DEADLINE=100_582327_771088_roundrobin_4.boa.txt";
@@ -94,4 +172,42 @@ fn test_partref_wlan() {
   let (data, r) = read_boa_txt(&filename);
   let ids = partref_nlogn(data, r);
   assert_eq!(*ids.iter().max().unwrap(), 243324);
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_partref_const() {
+  // List[0]{@1,1}
+  // 1
+  // List[0]{@3,1}
+  // 1
+  // List[0]{@5,1}
+  // 2
+  // List[0]{}
+  let (data,r) = read_boa_txt("tests/const_test.boa.txt");
+  let ids1 = partref_naive(&data, &r);
+  let ids2 = partref_nlogn(data, r);
+  assert_eq!(&renumber(&ids1), &ids2);
+  assert_eq!(&ids2, &vec![0,1,0,1,2,3,4]);
+}
+
+#[test]
+fn test_partref_shared() {
+  // $7 = Add[0]{@0:1,@1:1}
+  // $3 = List[1]{$7,@2}
+  // List[0]{$3,$7}
+  // List[0]{$3,Add[0]{@1:1,@0:1}}
+  // List[0]{List[1]{$7,@3},$7}
+  // $5 = Set[0]{}
+  // $5
+  // Set[0]{}
+  let (data,r) = read_boa_txt("tests/shared_test.boa.txt");
+  let ids1 = partref_naive(&data, &r);
+  // the same coalgebra with the shared nodes inlined
+  let mut w = CWriter::new();
+  for node in crate::binrep::read_nodes(&data, &r) { node.write(&mut w) }
+  let (data_inlined, r_inlined) = w.finish();
+  let ids2 = partref_nlogn(data, r);
+  assert_eq!(&renumber(&ids1), &ids2);
+  assert_eq!(&ids2, &partref_nlogn(data_inlined, r_inlined));
+  assert_eq!(&ids2, &vec![0,0,1,2,2]);
+}
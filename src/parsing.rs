@@ -1,116 +1,466 @@
 
-use crate::{binrep::{Node, LIST_TYP, ADD_TYP, SET_TYP, OR_TYP, MAX_TYP, TAG_TYP}};
+use crate::binrep::{Node, zigzag};
+#[cfg(test)]
+use crate::binrep::{LIST_TYP, ADD_TYP, SET_TYP, OR_TYP, MAX_TYP, REAL_TYP, INT_TYP, MIN_TYP, MUL_TYP};
+use crate::nodetype::{node_type, typ_from_name, NodeType, MAX_ARITY};
+use crate::hmap::{HMap, HSet};
+use std::collections::BTreeMap;
+use num_integer::Integer;
 
+// Whitespace (including line breaks) and comments from '#' to the end of the line may appear between any two tokens.
+// Machine generated files have none, in which case skipping it costs a single comparison per token.
+
+/// Skip whitespace and comments
+#[inline]
+pub fn skip_ws(mut inp: &[u8]) -> &[u8] {
+  // machine-generated files have no whitespace between tokens, so check that case first
+  if inp.first().is_some_and(|&c| c > b' ' && c != b'#') { return inp }
+  loop {
+    match inp.first() {
+      Some(b' ' | b'\t' | b'\r' | b'\n') => inp = &inp[1..],
+      Some(b'#') => {
+        let n = inp.iter().position(|&c| c == b'\n').unwrap_or(inp.len());
+        inp = &inp[n..];
+      },
+      _ => return inp,
+    }
+  }
+}
+
+/// Whether inp ends with the type name, possibly followed by whitespace and comments as skipped by skip_ws
+fn ends_with_name(mut inp: &[u8], name: &[u8]) -> bool {
+  if !inp.last().is_some_and(|c| c.is_ascii_whitespace()) { return inp.ends_with(name) }
+  loop {
+    inp = inp.trim_ascii_end();
+    let line_start = inp.iter().rposition(|&c| c == b'\n').map_or(0, |n| n + 1);
+    match inp[line_start..].iter().position(|&c| c == b'#') {
+      Some(n) => inp = &inp[..line_start + n],
+      None => return inp.ends_with(name),
+    }
+  }
+}
+
+#[test]
+fn test_skip_ws() {
+  assert_eq!(skip_ws(" \t# comment, {\r\n  @1".as_bytes()), "@1".as_bytes());
+  assert_eq!(skip_ws("@1 ".as_bytes()), "@1 ".as_bytes());
+  assert_eq!(skip_ws("# only a comment".as_bytes()), "".as_bytes());
+}
+
+/// Skip whitespace around the expected character
 fn read_expect<'a>(inp: &'a [u8], chr: u8) -> &'a [u8] {
+  let inp = skip_ws(inp);
   if inp.len() == 0 || inp[0] != chr {
-      panic!("Expecting {:?}, got {:?}.", chr as char, String::from_utf8(inp.to_vec()).unwrap());
+      panic!("Expecting {:?}, got {:?}.", chr as char, String::from_utf8_lossy(inp));
+  }
+  return skip_ws(&inp[1..]);
+}
+
+/// The number of '{' minus the number of '}' in a line, not counting those in quoted names and comments
+pub fn brace_depth(line: &[u8]) -> i64 {
+  // machine-generated lines have no quotes or comments, so first count the braces without branches, which is vectorized
+  let (mut depth, mut special) = (0i64, false);
+  for &c in line {
+    depth += (c == b'{') as i64 - (c == b'}') as i64;
+    special |= (c == b'"') | (c == b'#');
+  }
+  if !special { return depth }
+  let mut depth = 0;
+  let mut i = 0;
+  while i < line.len() {
+    match line[i] {
+      b'{' => depth += 1,
+      b'}' => depth -= 1,
+      b'#' => return depth,
+      b'"' => {
+        i += 1;
+        while i < line.len() && line[i] != b'"' {
+          if line[i] == b'\\' { i += 1 }
+          i += 1;
+        }
+      },
+      _ => {}
+    }
+    i += 1;
+  }
+  return depth;
+}
+
+#[test]
+fn test_brace_depth() {
+  assert_eq!(brace_depth("List[0]{@0,@1}".as_bytes()), 0);
+  assert_eq!(brace_depth("List[0]{ # }".as_bytes()), 1);
+  assert_eq!(brace_depth("List[\"{\\\"{\"]{@0, Set[0]{".as_bytes()), 2);
+  assert_eq!(brace_depth("}}".as_bytes()), -2);
+  assert_eq!(brace_depth("Add[0]{@0:1, # {\n".as_bytes()), 1);
+  assert_eq!(brace_depth("List[\"}\"]{\n".as_bytes()), 1);
+}
+
+/// Symbolic names in the text format.
+/// Tags can be written as List[send]{...} or List["send msg"]{...} instead of a number.
+/// Each new tag name gets the smallest tag that is neither used as a number in the input nor taken by another name.
+/// The labels of Tag nodes can be names too, as in Tag[0]{@1:send}, which get labels in the same way.
+/// States can be defined as `name: node` and referred to as @name.
+/// Shared nodes are defined as `$k = node` and referred to as $k. They are numbered in the order of their definitions.
+pub struct Names {
+  tags: HMap<Vec<u8>, u32>,
+  reserved: HSet<u32>,
+  next: u32,
+  labels: HMap<Vec<u8>, u64>,
+  reserved_labels: HSet<u64>,
+  next_label: u64,
+  states: HMap<Vec<u8>, u32>,
+  shared: HMap<u64, u32>,
+  numeric_only: bool,
+  seen_name: bool,
+  // elements of the nodes being read, reused between nodes
+  elems: Vec<(Node, [u64; MAX_ARITY])>,
+}
+
+impl Names {
+  pub fn new() -> Names {
+    Names { tags: HMap::default(), reserved: HSet::default(), next: 0,
+            labels: HMap::default(), reserved_labels: HSet::default(), next_label: 0,
+            states: HMap::default(), shared: HMap::default(), numeric_only: false, seen_name: false, elems: vec![] }
+  }
+
+  /// Names for reading an input without looking at all of it first, which is only correct if it has no names.
+  /// Names of states are not resolved then, and a reader has to start over with Names::new() once must_restart() holds.
+  pub fn numeric_only() -> Names {
+    Names { numeric_only: true, ..Names::new() }
+  }
+
+  pub fn is_numeric_only(self: &Self) -> bool {
+    self.numeric_only
+  }
+
+  /// Whether a name was read with numeric_only() names
+  pub fn must_restart(self: &Self) -> bool {
+    self.numeric_only && self.seen_name
+  }
+
+  /// Define the shared node $id, returning its index
+  pub fn define_shared(self: &mut Self, id: u64) -> u32 {
+    let k = self.shared.len() as u32;
+    if self.shared.insert(id, k).is_some() {
+      panic!("Shared node ${} is defined more than once.", id);
+    }
+    return k;
+  }
+
+  pub fn shared(self: &Self, id: u64) -> u32 {
+    *self.shared.get(&id).unwrap_or_else(|| panic!("Shared node ${} is used before it is defined.", id))
+  }
+
+  pub fn define_state(self: &mut Self, name: &[u8], state: u32) {
+    self.seen_name = true;
+    if self.states.insert(name.to_vec(), state).is_some() {
+      panic!("State {} is defined more than once.", String::from_utf8_lossy(name));
+    }
+  }
+
+  pub fn state(self: &mut Self, name: &[u8]) -> u32 {
+    self.seen_name = true;
+    if self.numeric_only { return 0 }
+    *self.states.get(name).unwrap_or_else(|| panic!("Unknown state @{}.", String::from_utf8_lossy(name)))
+  }
+
+  /// Reserve the tags and labels that occur as numbers in inp, so that names are not given the same tag or label.
+  /// If inp has a Tag node, any whole number after ':' is taken to be a label, which may reserve more labels than needed.
+  /// Must be called on the whole input before parsing it.
+  pub fn reserve_numeric(self: &mut Self, inp: &[u8]) {
+    let mut has_tag_node = false;
+    for (i, &c) in inp.iter().enumerate() {
+      if c == b'[' {
+        has_tag_node |= ends_with_name(&inp[..i], b"Tag");
+        let rest = skip_ws(&inp[i+1..]);
+        if let Ok((tag, n)) = lexical::parse_partial::<u32,_>(rest) {
+          if skip_ws(&rest[n..]).first() == Some(&b']') { self.reserved.insert(tag); }
+        }
+      }
+    }
+    if !has_tag_node { return }
+    for (i, &c) in inp.iter().enumerate() {
+      if c == b':' {
+        let rest = skip_ws(&inp[i+1..]);
+        if let Ok((label, n)) = lexical::parse_partial::<u64,_>(rest) {
+          if matches!(skip_ws(&rest[n..]).first(), Some(b',') | Some(b'}')) { self.reserved_labels.insert(label); }
+        }
+      }
+    }
+  }
+
+  pub fn tag(self: &mut Self, name: &[u8]) -> u32 {
+    self.seen_name = true;
+    if let Some(&tag) = self.tags.get(name) { return tag }
+    while self.reserved.contains(&self.next) { self.next += 1 }
+    let tag = self.next;
+    self.reserved.insert(tag);
+    self.tags.insert(name.to_vec(), tag);
+    return tag;
+  }
+
+  pub fn names(self: &Self) -> BTreeMap<u32, String> {
+    self.tags.iter().map(|(name, &tag)| (tag, String::from_utf8(name.clone()).expect("Tag name is not valid UTF-8."))).collect()
+  }
+
+  pub fn label(self: &mut Self, name: &[u8]) -> u64 {
+    self.seen_name = true;
+    if let Some(&label) = self.labels.get(name) { return label }
+    while self.reserved_labels.contains(&self.next_label) { self.next_label += 1 }
+    let label = self.next_label;
+    self.reserved_labels.insert(label);
+    self.labels.insert(name.to_vec(), label);
+    return label;
+  }
+
+  pub fn label_names(self: &Self) -> BTreeMap<u64, String> {
+    self.labels.iter().map(|(name, &label)| (label, String::from_utf8(name.clone()).expect("Label name is not valid UTF-8."))).collect()
+  }
+}
+
+fn is_name_start(c: u8) -> bool { c.is_ascii_alphabetic() || c == b'_' }
+fn is_name_char(c: u8) -> bool { c.is_ascii_alphanumeric() || c == b'_' }
+
+/// Write a name, quoting it unless it is an identifier
+pub fn write_name(name: &str, w: &mut Vec<u8>) {
+  let bytes = name.as_bytes();
+  if bytes.len() > 0 && is_name_start(bytes[0]) && bytes.iter().all(|&c| is_name_char(c)) {
+    w.extend(bytes);
+  } else {
+    w.push(b'"');
+    for &c in bytes {
+      if c == b'"' || c == b'\\' { w.push(b'\\') }
+      w.push(c);
+    }
+    w.push(b'"');
   }
-  return &inp[1..];
 }
 
-fn read_tag<'a>(inp: &'a [u8]) -> (u8, &'a [u8]) {
+/// Read an identifier or a quoted name, returning its bytes without quotes and escapes
+pub fn read_name<'a>(inp: &'a [u8]) -> (Vec<u8>, &'a [u8]) {
+  if inp.len() > 0 && inp[0] == b'"' {
+    let mut name = vec![];
+    let mut i = 1;
+    loop {
+      match inp.get(i) {
+        None => panic!("Unterminated quoted name."),
+        Some(b'"') => return (name, &inp[i+1..]),
+        Some(b'\\') => {
+          name.push(*inp.get(i+1).expect("Unterminated quoted name."));
+          i += 2;
+        },
+        Some(&c) => { name.push(c); i += 1; }
+      }
+    }
+  }
+  if inp.len() == 0 || !is_name_start(inp[0]) {
+    panic!("Expected a name, got {:?}.", String::from_utf8_lossy(inp));
+  }
+  let n = inp.iter().take_while(|&&c| is_name_char(c)).count();
+  (inp[..n].to_vec(), &inp[n..])
+}
+
+/// Read the name of a state definition `name: node` at the start of a line, if there is one
+pub fn read_definition<'a>(inp: &'a [u8]) -> (Option<Vec<u8>>, &'a [u8]) {
+  let inp = skip_ws(inp);
+  if inp.len() == 0 { return (None, inp) }
+  if inp[0] == b'"' {
+    let (name, rest) = read_name(inp);
+    return (Some(name), read_expect(rest, b':'));
+  }
+  // avoid allocating for the type name at the start of a node
+  let n = inp.iter().take_while(|&&c| is_name_char(c)).count();
+  if n == 0 || !is_name_start(inp[0]) { return (None, inp) }
+  let rest = skip_ws(&inp[n..]);
+  if rest.len() > 0 && rest[0] == b':' {
+    return (Some(inp[..n].to_vec()), skip_ws(&rest[1..]));
+  }
+  (None, inp)
+}
+
+/// Read the start `$k =` of a shared node definition, if there is one
+pub fn read_shared_definition<'a>(inp: &'a [u8]) -> Option<(u64, &'a [u8])> {
+  let inp = skip_ws(inp);
+  if inp.first() != Some(&b'$') { return None }
+  let (id,n) = lexical::parse_partial::<u64,_>(&inp[1..]).expect("Expected a number after '$'.");
+  let rest = skip_ws(&inp[1+n..]);
+  if rest.first() != Some(&b'=') { return None } // a state that is a reference $k
+  return Some((id, skip_ws(&rest[1..])));
+}
+
+#[test]
+fn test_read_definition() {
+  assert_eq!(read_definition("s0: List[0]{}".as_bytes()), (Some(b"s0".to_vec()), "List[0]{}".as_bytes()));
+  assert_eq!(read_definition("\"s 1\":@0".as_bytes()), (Some(b"s 1".to_vec()), "@0".as_bytes()));
+  assert_eq!(read_definition("Set: @0".as_bytes()), (Some(b"Set".to_vec()), "@0".as_bytes()));
+  assert_eq!(read_definition("List[0]{}".as_bytes()), (None, "List[0]{}".as_bytes()));
+  assert_eq!(read_definition("@0".as_bytes()), (None, "@0".as_bytes()));
+  assert_eq!(read_definition("  s0 :\r\n  @0".as_bytes()), (Some(b"s0".to_vec()), "@0".as_bytes()));
+  assert_eq!(read_definition("  # comment\n".as_bytes()), (None, "".as_bytes()));
+  assert_eq!(read_shared_definition(" $7 = Set[0]{}".as_bytes()), Some((7, "Set[0]{}".as_bytes())));
+  assert_eq!(read_shared_definition("List[0]{$7}".as_bytes()), None);
+  assert_eq!(read_shared_definition("$7".as_bytes()), None);
+}
+
+fn read_tag<'a>(inp: &'a [u8], names: &mut Names) -> (u32, &'a [u8]) {
   let inp = read_expect(inp, b'[');
-  let (tag,n) = lexical::parse_partial::<u8,_>(inp).expect("Expected a number in tag [_].");
+  if inp.len() > 0 && !inp[0].is_ascii_digit() && inp[0] != b']' {
+    let (name, inp) = read_name(inp);
+    return (names.tag(&name), read_expect(inp, b']'));
+  }
+  let (tag,n) = lexical::parse_partial::<u32,_>(inp).expect("Expected a number or name in tag [_].");
   (tag, read_expect(&inp[n..], b']'))
 }
 
 #[test]
 fn test_read_tag() {
-  assert_eq!(read_tag("[123]abc".as_bytes()), (123, "abc".as_bytes()));
+  let mut names = Names::new();
+  names.reserve_numeric("[0]{}[x][2][1x]".as_bytes());
+  assert_eq!(read_tag("[123]abc".as_bytes(), &mut names), (123, "abc".as_bytes()));
+  assert_eq!(read_tag("[70000]abc".as_bytes(), &mut names), (70000, "abc".as_bytes()));
+  assert_eq!(read_tag("[send]abc".as_bytes(), &mut names), (1, "abc".as_bytes()));
+  assert_eq!(read_tag("[\"a \\\"b\\\"\"]abc".as_bytes(), &mut names), (3, "abc".as_bytes()));
+  assert_eq!(read_tag("[send]".as_bytes(), &mut names), (1, "".as_bytes()));
+  // a Tag node with whitespace or a comment before its tag also reserves its numeric labels
+  for inp in ["Tag [0]{@1:a,@1:0}", "Tag # labels\n [0]{@1:a,@1:0}"] {
+    let mut names = Names::new();
+    names.reserve_numeric(inp.as_bytes());
+    assert_eq!(names.label(b"a"), 1);
+  }
+  let mut out = vec![];
+  write_name("a \"b\"", &mut out);
+  write_name("send_2", &mut out);
+  assert_eq!(String::from_utf8(out).unwrap(), "\"a \\\"b\\\"\"send_2");
 }
 
-fn read_coll<'a>(inp: &'a [u8], typ: u8) -> (Node, &'a [u8]) {
-  let (tag, inp) = read_tag(inp);
+/// Read the elements of a node of type t after its name, each followed by ':' and t.arity() values if the arity isn't 0
+fn read_elems<'a>(inp: &'a [u8], typ: u8, t: &dyn NodeType, names: &mut Names) -> (Node, &'a [u8]) {
+  let (tag, inp) = read_tag(inp, names);
   let mut inp = read_expect(inp, b'{');
-  let mut nodes = vec![];
-  if inp.len() == 0 { panic!("Unexpected end of input at start of collection.") }
-  if inp[0] == b'}' { return (Node::Coll(typ, tag, nodes), &inp[1..]) }
-  loop {
-      let (node,inp2) = read_node(inp);
-      inp = inp2;
-      nodes.push(node);
-      if inp.len() == 0 { panic!("Unexpected end of input in collection.") }
-      if inp[0] == b'}' { return (Node::Coll(typ, tag, nodes), &inp[1..]) }
+  let start = names.elems.len();
+  let arity = t.arity();
+  if inp.len() == 0 { panic!("Unexpected end of input at start of {} node.", t.name()) }
+  if inp[0] != b'}' {
+    loop {
+      let (node,inp2) = read_node(inp, names);
+      inp = skip_ws(inp2);
+      let mut values = [0; MAX_ARITY];
+      if arity > 0 {
+        inp = read_expect(inp, b':');
+        let n = t.parse_values(inp, names, &mut values[..arity]);
+        inp = skip_ws(&inp[n..]);
+      }
+      names.elems.push((node, values));
+      if inp.len() == 0 { panic!("Unexpected end of input in {} node.", t.name()) }
+      if inp[0] == b'}' { break }
       inp = read_expect(inp, b',');
+    }
   }
+  let node = t.make_node(typ, tag, &mut names.elems.drain(start..));
+  return (node, &inp[1..]);
 }
 
 #[test]
 fn test_read_coll() {
-  assert_eq!(read_coll("[123]{@12,@13,@14}abc".as_bytes(), LIST_TYP),
+  assert_eq!(read_elems("[123]{@12,@13,@14}abc".as_bytes(), LIST_TYP, node_type(LIST_TYP).unwrap(), &mut Names::new()),
           (Node::Coll(LIST_TYP, 123, vec![Node::State(12),Node::State(13),Node::State(14)]),"abc".as_bytes()));
 }
 
-fn read_mon<'a>(inp: &'a [u8], typ: u8) -> (Node, &'a [u8]) {
-  let (tag, inp) = read_tag(inp);
-  let mut inp = read_expect(inp, b'{');
-  let mut nodes = vec![];
-  if inp.len() == 0 { panic!("Unexpected end of input at start of monoid.") }
-  if inp[0] == b'}' { return (Node::Mon(typ, tag, nodes), &inp[1..]) }
-  loop {
-      let (node,inp2) = read_node(inp);
-      inp = read_expect(inp2, b':');
-      let (val,n) = lexical::parse_partial::<u64,_>(inp).expect("Expected a number after ':'.");
-      inp = &inp[n..];
-      nodes.push((node, val));
-      if inp.len() == 0 { panic!("Unexpected end of input in monoid.") }
-      if inp[0] == b'}' { return (Node::Mon(typ, tag, nodes), &inp[1..]) }
-      inp = read_expect(inp, b',');
+/// Read a real weight, returning the bits of the f64
+pub fn read_real(inp: &[u8]) -> (u64, usize) {
+  let (val,n) = lexical::parse_partial::<f64,_>(inp).expect("Expected a real number after ':'.");
+  if !(val.is_finite() && val >= 0.0) {
+    panic!("Real weights must be finite and non-negative, got {}.", val);
   }
+  // normalize -0.0 to 0.0
+  ((val + 0.0).to_bits(), n)
+}
+
+/// Read a signed integer weight, returning it zigzag encoded
+pub fn read_int(inp: &[u8]) -> (u64, usize) {
+  let (val,n) = lexical::parse_partial::<i64,_>(inp).expect("Expected a signed 64 bit number after ':'.");
+  (zigzag(val), n)
+}
+
+/// Read the label of an element of a Tag node, which is a number or a name
+pub fn read_label(inp: &[u8], names: &mut Names) -> (u64, usize) {
+  if inp.first().is_some_and(|&c| c == b'"' || is_name_start(c)) {
+    let (name, rest) = read_name(inp);
+    return (names.label(&name), inp.len() - rest.len());
+  }
+  lexical::parse_partial::<u64,_>(inp).expect("Expected a number after ':'.")
+}
+
+/// Read a rational weight written as n/d, n or a decimal like 0.25, returning it in lowest terms
+pub fn read_fraction(inp: &[u8]) -> (u64, u64, usize) {
+  let (int,mut n) = lexical::parse_partial::<u64,_>(inp).expect("Expected a number after ':'.");
+  let (mut num, mut den) = (int as u128, 1u128);
+  if n < inp.len() && inp[n] == b'/' {
+    let (d,m) = lexical::parse_partial::<u64,_>(&inp[n+1..]).expect("Expected a denominator after '/'.");
+    if d == 0 { panic!("Denominator of a rational weight must not be zero.") }
+    den = d as u128;
+    n += 1 + m;
+  } else if n < inp.len() && inp[n] == b'.' {
+    n += 1;
+    while n < inp.len() && inp[n].is_ascii_digit() {
+      num = num.checked_mul(10).and_then(|x| x.checked_add((inp[n] - b'0') as u128)).expect("Decimal weight has too many digits.");
+      den = den.checked_mul(10).expect("Decimal weight has too many digits.");
+      n += 1;
+    }
+  }
+  let g = num.gcd(&den);
+  let (num, den) = (num / g, den / g);
+  if num > u64::MAX as u128 || den > u64::MAX as u128 {
+    panic!("Rational weight does not fit in 64 bits after reduction.");
+  }
+  (num as u64, den as u64, n)
+}
+
+#[test]
+fn test_read_fraction() {
+  assert_eq!(read_fraction("2/6}".as_bytes()), (1, 3, 3));
+  assert_eq!(read_fraction("5,".as_bytes()), (5, 1, 1));
+  assert_eq!(read_fraction("0.250".as_bytes()), (1, 4, 5));
+  assert_eq!(read_fraction("0/7".as_bytes()), (0, 1, 3));
 }
 
 #[test]
 fn test_read_mon() {
-  assert_eq!(read_mon("[123]{@12:5,@13:6,@14:7}abc".as_bytes(), ADD_TYP),
+  assert_eq!(read_elems("[123]{@12:5,@13:6,@14:7}abc".as_bytes(), ADD_TYP, node_type(ADD_TYP).unwrap(), &mut Names::new()),
       (Node::Mon(ADD_TYP, 123, vec![(Node::State(12),5),(Node::State(13),6),(Node::State(14),7)]),"abc".as_bytes()));
 }
 
-pub fn read_node<'a>(inp: &'a [u8]) -> (Node, &'a [u8]) {
+pub fn read_node<'a>(inp: &'a [u8], names: &mut Names) -> (Node, &'a [u8]) {
+  let inp = skip_ws(inp);
   if inp.len() == 0 { panic!("Expected start of a node, but input is empty.") }
   let chr = inp[0];
   let orig = inp;
   let inp = &inp[1..];
   match chr {
       b'@' => {
-          let (state,n) = lexical::parse_partial::<u32,_>(inp).expect("Expected a number after '@'.");
+          if inp.len() > 0 && !inp[0].is_ascii_digit() {
+              let (name, inp) = read_name(inp);
+              return (Node::State(names.state(&name)), inp);
+          }
+          let (state,n) = lexical::parse_partial::<u32,_>(inp).expect("Expected a number or name after '@'.");
           assert!(state <= u32::MAX >> 2);
           (Node::State(state), &inp[n..])
       },
-      b'L' => {
-          if inp.len() < 3 || inp[0..3] != [b'i', b's', b't'] {
-              panic!("Expected \"List\", got {:?}", String::from_utf8(orig.to_vec()).unwrap());
-          }
-          return read_coll(&inp[3..], LIST_TYP);
+      b'$' => {
+          let (id,n) = lexical::parse_partial::<u64,_>(inp).expect("Expected a number after '$'.");
+          (Node::Shared(names.shared(id)), &inp[n..])
       },
-      b'S' => {
-          if inp.len() < 2 || inp[0..2] != [b'e', b't'] {
-              panic!("Expected \"Set\" got {:?}", String::from_utf8(orig.to_vec()).unwrap());
-          }
-          return read_coll(&inp[2..], SET_TYP);
-      },
-      b'A' => {
-          if inp.len() < 2 || inp[0..2] != [b'd', b'd'] {
-              panic!("Expected \"Add\" got {:?}", String::from_utf8(orig.to_vec()).unwrap());
-          }
-          return read_mon(&inp[2..], ADD_TYP);
-      },
-      b'O' => {
-          if inp.len() < 1 || inp[0..1] != [b'r'] {
-              panic!("Expected \"Or\" got {:?}", String::from_utf8(orig.to_vec()).unwrap());
-          }
-          return read_mon(&inp[1..], OR_TYP);
-      },
-      b'M' => {
-          if inp.len() < 2 || inp[0..2] != [b'a', b'x'] {
-              panic!("Expected \"Max\", got {:?}", String::from_utf8(orig.to_vec()).unwrap());
-          }
-          return read_mon(&inp[2..], MAX_TYP);
+      c if c.is_ascii_digit() => {
+          let (val,n) = lexical::parse_partial::<u64,_>(orig).expect("Expected a 64 bit constant.");
+          (Node::Const(val), &orig[n..])
       },
-      b'T' => {
-          if inp.len() < 2 || inp[0..2] != [b'a', b'g'] {
-              panic!("Expected \"Tag\", got {:?}", String::from_utf8(orig.to_vec()).unwrap());
-          }
-          return read_mon(&inp[2..], TAG_TYP);
+      c if c.is_ascii_alphabetic() => {
+          let n = orig.iter().take_while(|c| c.is_ascii_alphabetic()).count();
+          let typ = typ_from_name(&orig[..n]).unwrap_or_else(|| {
+              panic!("Unknown node type {:?}.", String::from_utf8(orig[..n].to_vec()).unwrap())
+          });
+          return read_elems(&orig[n..], typ, node_type(typ).unwrap(), names);
       },
       _ => { panic!("Expected start of a node, but got {:?}.", String::from_utf8(orig.to_vec()).unwrap()) }
   }
@@ -118,24 +468,59 @@ pub fn read_node<'a>(inp: &'a [u8]) -> (Node, &'a [u8]) {
 
 #[test]
 fn test_read_node() {
-  assert_eq!(read_node("List[123]{@12,@13,@14}abc".as_bytes()),
+  assert_eq!(read_node("List[123]{@12,@13,@14}abc".as_bytes(), &mut Names::new()),
       (Node::Coll(LIST_TYP, 123, vec![Node::State(12),Node::State(13),Node::State(14)]), "abc".as_bytes()));
 
-  assert_eq!(read_node("Set[123]{@12,@13,@14}abc".as_bytes()),
+  assert_eq!(read_node("Set[123]{@12,@13,@14}abc".as_bytes(), &mut Names::new()),
       (Node::Coll(SET_TYP, 123, vec![Node::State(12),Node::State(13),Node::State(14)]), "abc".as_bytes()));
 
-  assert_eq!(read_node("Set[123]{}abc".as_bytes()),
+  assert_eq!(read_node("Set[123]{}abc".as_bytes(), &mut Names::new()),
       (Node::Coll(SET_TYP, 123, vec![]), "abc".as_bytes()));
 
-  assert_eq!(read_node("Add[123]{@12:5,@13:6,@14:7}abc".as_bytes()),
+  assert_eq!(read_node("Add[123]{@12:5,@13:6,@14:7}abc".as_bytes(), &mut Names::new()),
       (Node::Mon(ADD_TYP, 123, vec![(Node::State(12),5),(Node::State(13),6),(Node::State(14),7)]),"abc".as_bytes()));
 
-  assert_eq!(read_node("Or[123]{@12:5,@13:6,@14:7}abc".as_bytes()),
+  assert_eq!(read_node("Or[123]{@12:5,@13:6,@14:7}abc".as_bytes(), &mut Names::new()),
       (Node::Mon(OR_TYP, 123, vec![(Node::State(12),5),(Node::State(13),6),(Node::State(14),7)]),"abc".as_bytes()));
 
-  assert_eq!(read_node("Max[123]{@12:5,@13:6,@14:7}abc".as_bytes()),
+  assert_eq!(read_node("Max[123]{@12:5,@13:6,@14:7}abc".as_bytes(), &mut Names::new()),
       (Node::Mon(MAX_TYP, 123, vec![(Node::State(12),5),(Node::State(13),6),(Node::State(14),7)]),"abc".as_bytes()));
 
-  assert_eq!(read_node("Max[123]{}abc".as_bytes()),
+  assert_eq!(read_node("Real[123]{@12:0.5,@13:2}abc".as_bytes(), &mut Names::new()),
+      (Node::Mon(REAL_TYP, 123, vec![(Node::State(12),0.5f64.to_bits()),(Node::State(13),2f64.to_bits())]),"abc".as_bytes()));
+
+  assert_eq!(read_node("Int[123]{@12:-5,@13:6}abc".as_bytes(), &mut Names::new()),
+      (Node::Mon(INT_TYP, 123, vec![(Node::State(12),zigzag(-5)),(Node::State(13),zigzag(6))]),"abc".as_bytes()));
+
+  assert_eq!(read_node("Min[123]{@12:5}abc".as_bytes(), &mut Names::new()),
+      (Node::Mon(MIN_TYP, 123, vec![(Node::State(12),5)]),"abc".as_bytes()));
+
+  assert_eq!(read_node("Mul[123]{@12:5}abc".as_bytes(), &mut Names::new()),
+      (Node::Mon(MUL_TYP, 123, vec![(Node::State(12),5)]),"abc".as_bytes()));
+
+  assert_eq!(read_node("List[1]{5,@3}abc".as_bytes(), &mut Names::new()),
+      (Node::Coll(LIST_TYP, 1, vec![Node::Const(5),Node::State(3)]),"abc".as_bytes()));
+
+  let mut names = Names::new();
+  names.define_state(b"s0", 0);
+  names.define_state(b"s 1", 1);
+  assert_eq!(read_node("Set[0]{@s0,@\"s 1\",@2}abc".as_bytes(), &mut names),
+      (Node::Coll(SET_TYP, 0, vec![Node::State(0),Node::State(1),Node::State(2)]),"abc".as_bytes()));
+
+  names.define_shared(7);
+  names.define_shared(3);
+  assert_eq!(read_node("List[0]{$3,$7}abc".as_bytes(), &mut names),
+      (Node::Coll(LIST_TYP, 0, vec![Node::Shared(1),Node::Shared(0)]),"abc".as_bytes()));
+
+  assert_eq!(read_node(" Add [ 1 ] {\r\n  @12 : 5 , # first\n  Set[0]{ } : 6 ,\n  @14:5 }abc".as_bytes(), &mut Names::new()).0,
+      read_node("Add[1]{@12:5,Set[0]{}:6,@14:5}".as_bytes(), &mut Names::new()).0);
+
+  assert_eq!(read_node("Max[123]{}abc".as_bytes(), &mut Names::new()),
       (Node::Mon(MAX_TYP, 123, vec![]),"abc".as_bytes()));
-}
\ No newline at end of file
+}
+
+#[test]
+#[should_panic(expected = "used before it is defined")]
+fn test_shared_before_definition() {
+  read_node("List[0]{$1}".as_bytes(), &mut Names::new());
+}
@@ -0,0 +1,427 @@
+//===============================//
+// PRISM explicit model files //
+//===============================//
+
+// PRISM exports a model as model.tra with the transitions, and optionally model.lab with the labels,
+// model.srew with the state rewards and model.sta with the values of the variables in each state.
+// The .tra file of a DTMC or CTMC starts with a line `n m` giving the number of states and transitions,
+// followed by a line `s t p` for each transition from s to t with probability or rate p.
+// The .tra file of an MDP starts with `n c m`, also giving the number of choices, followed by a line
+// `s i t p a` for each transition of the i-th choice of s, where the action a is optional.
+//
+// A state of a DTMC or CTMC becomes Real[o]{@t:p,...}, and a state of an MDP becomes Set[o]{Real[a]{@t:p,...},...}
+// with one Real node per choice. The tag o stands for the labels and the reward of the state, so that
+// they form the initial partition, and the tag a for the action. What each tag stands for is kept in
+// meta.observations, and the tags are also named after it, like {init,goal} r=2, for the text format.
+// The .sta file gives the names of the states.
+//
+// write_prism goes the other way, for instance to pass a quotient back to PRISM or Storm. It accepts states of the
+// form above, where the Real nodes may also be Add, Int or Q nodes. The labels and rewards are taken from
+// meta.observations, and written to .lab and .srew files if there are any. The initial state is the one
+// labelled init, which PRISM requires; the quotient keeps this label, as it is part of the initial partition.
+
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::{fs::File, path::Path};
+
+use itertools::Itertools;
+
+use crate::binrep::{read_nodes, unzigzag, CReader, CWriter, Meta, Node, Observation, ADD_TYP, INT_TYP, REAL_TYP, SET_TYP};
+use crate::io::create_file;
+use crate::hmap::HMap;
+
+/// Open a file and return its non-blank lines
+fn read_lines(filename: &Path) -> impl Iterator<Item = String> {
+    let file = File::open(filename).
+        expect(&format!("Couldn't open file {:?}", filename.display().to_string()));
+    BufReader::new(file).lines()
+        .map(|line| line.expect("Failure while reading file."))
+        .filter(|line| !line.trim().is_empty())
+}
+
+pub fn parse<T: std::str::FromStr>(s: Option<&str>, what: &str, line: &str) -> T {
+    s.and_then(|s| s.parse().ok()).unwrap_or_else(|| panic!("Expected {} in line {:?}.", what, line))
+}
+
+fn parse_weight(s: Option<&str>, line: &str) -> u64 {
+    let p: f64 = parse(s, "a probability or rate", line);
+    if p.is_nan() || p < 0.0 { panic!("Negative probability or rate in line {:?}.", line) }
+    p.to_bits()
+}
+
+/// The labels of each state from a .lab file, whose first line declares the labels as 0="init" 1="deadlock"
+fn read_lab(filename: &Path, num_states: usize) -> Vec<Vec<String>> {
+    let mut lines = read_lines(filename);
+    let header = lines.next().unwrap_or_default();
+    let mut names = HMap::default();
+    for decl in header.split_ascii_whitespace() {
+        let (i, name) = decl.split_once('=').unwrap_or_else(|| panic!("Expected i=\"name\" in {:?}.", decl));
+        let i: u32 = parse(Some(i), "a label number", &header);
+        names.insert(i, name.trim_matches('"').to_string());
+    }
+    let mut labels = vec![vec![]; num_states];
+    for line in lines {
+        let (s, ls) = line.split_once(':').unwrap_or_else(|| panic!("Expected s: labels in line {:?}.", line));
+        let s: usize = parse(Some(s.trim()), "a state", &line);
+        let state_labels = labels.get_mut(s).unwrap_or_else(|| panic!("State {} out of range in {:?}.", s, filename));
+        for l in ls.split_ascii_whitespace() {
+            let l: u32 = parse(Some(l), "a label number", &line);
+            state_labels.push(names.get(&l).unwrap_or_else(|| panic!("Undeclared label {} in line {:?}.", l, line)).clone());
+        }
+        state_labels.sort();
+    }
+    return labels;
+}
+
+/// The reward of each state from a .srew file, which has a header `n m` and a line `s r` for each state with a reward
+fn read_srew(filename: &Path, num_states: usize) -> Vec<Option<String>> {
+    let mut rewards = vec![None; num_states];
+    for line in read_lines(filename).skip(1) {
+        let mut parts = line.split_ascii_whitespace();
+        let s: usize = parse(parts.next(), "a state", &line);
+        let r: f64 = parse(parts.next(), "a reward", &line);
+        if r != 0.0 {
+            *rewards.get_mut(s).unwrap_or_else(|| panic!("State {} out of range in {:?}.", s, filename)) = Some(r.to_string());
+        }
+    }
+    return rewards;
+}
+
+/// The names of the states from a .sta file, which has a header with the variables and a line `s:(v1,v2)` for each state
+fn read_sta(filename: &Path, num_states: usize) -> Vec<Option<String>> {
+    let mut names = vec![None; num_states];
+    for line in read_lines(filename).skip(1) {
+        let (s, values) = line.split_once(':').unwrap_or_else(|| panic!("Expected s:(values) in line {:?}.", line));
+        let s: usize = parse(Some(s.trim()), "a state", &line);
+        *names.get_mut(s).unwrap_or_else(|| panic!("State {} out of range in {:?}.", s, filename)) = Some(values.trim().to_string());
+    }
+    return names;
+}
+
+/// Tags by name, where None is a tag without a name
+#[derive(Default)]
+pub struct Tags {
+    tags: HMap<Option<String>, u32>,
+    pub names: BTreeMap<u32, String>,
+}
+
+impl Tags {
+    pub fn tag(self: &mut Self, name: Option<String>) -> u32 {
+        if let Some(&tag) = self.tags.get(&name) { return tag }
+        let tag = self.tags.len() as u32;
+        if let Some(name) = &name { self.names.insert(tag, name.clone()); }
+        self.tags.insert(name, tag);
+        return tag;
+    }
+}
+
+/// Rewards as kept in an observation, which are empty if they are all 0
+pub fn normalize_rewards(rewards: &[f64]) -> Vec<String> {
+    if rewards.iter().all(|&r| r == 0.0) { return vec![] }
+    return rewards.iter().map(|r| r.to_string()).collect();
+}
+
+/// A label or action as shown in the name of a tag, quoted if it has characters that separate them
+fn quote(name: &str) -> String {
+    if name.chars().any(|c| c.is_whitespace() || ",{}=\"\\".contains(c)) { format!("{:?}", name) } else { name.to_string() }
+}
+
+/// The name of a tag that stands for the observation, like {init,goal} r=2 or `a r=1`
+fn observation_name(observation: &Observation) -> Option<String> {
+    match observation {
+        Observation::State { labels, rewards, action_rewards } => {
+            let mut name = format!("{{{}}}", labels.iter().map(|l| quote(l)).join(","));
+            if !rewards.is_empty() { name = format!("{} r={}", name, rewards.join(",")) }
+            if !action_rewards.is_empty() { name = format!("{} ar={}", name, action_rewards.join(",")) }
+            Some(name)
+        }
+        Observation::Action { name, rewards } => match (name, rewards.is_empty()) {
+            (None, true) => None,
+            (None, false) => Some(format!("r={}", rewards.join(","))),
+            (Some(name), true) => Some(quote(name)),
+            (Some(name), false) => Some(format!("{} r={}", quote(name), rewards.join(","))),
+        },
+    }
+}
+
+/// Tags by the observation they stand for
+#[derive(Default)]
+pub struct ObservationTags {
+    tags: HMap<Observation, u32>,
+    pub names: BTreeMap<u32, String>,
+    pub observations: BTreeMap<u32, Observation>,
+}
+
+impl ObservationTags {
+    pub fn tag(self: &mut Self, observation: Observation) -> u32 {
+        if let Some(&tag) = self.tags.get(&observation) { return tag }
+        let tag = self.tags.len() as u32;
+        if let Some(name) = observation_name(&observation) { self.names.insert(tag, name); }
+        self.observations.insert(tag, observation.clone());
+        self.tags.insert(observation, tag);
+        return tag;
+    }
+}
+
+/// The labels, rewards and action rewards of a state with the given tag, which are empty if it has no observation
+pub fn state_observation(meta: &Meta, tag: u32) -> (&[String], &[String], &[String]) {
+    match meta.observations.get(&tag) {
+        Some(Observation::State { labels, rewards, action_rewards }) => (labels, rewards, action_rewards),
+        _ => (&[], &[], &[]),
+    }
+}
+
+/// The name and rewards of the action of a choice with the given tag.
+/// If the tag has no observation, its name is used as the name of the action.
+pub fn action_observation(meta: &Meta, tag: u32) -> (Option<&str>, &[String]) {
+    match meta.observations.get(&tag) {
+        Some(Observation::Action { name, rewards }) => (name.as_deref(), rewards),
+        Some(Observation::State { .. }) => (None, &[]),
+        None => (meta.tag_names.get(&tag).map(String::as_str), &[]),
+    }
+}
+
+/// The action of a choice of an MDP and its transitions
+pub type Choice = (Option<String>, Vec<(Node, u64)>);
+
+pub fn read_prism<P>(filename: P) -> (Vec<u8>,CReader)
+where P: AsRef<Path>, {
+    let filename = filename.as_ref();
+    if filename.extension().is_none_or(|ext| ext != "tra") {
+        panic!("File must be *.tra, but is {}", filename.display());
+    }
+    let mut lines = read_lines(filename);
+    let header = lines.next().expect("Missing header in .tra file.");
+    let counts: Vec<&str> = header.split_ascii_whitespace().collect();
+    let is_mdp = match counts.len() {
+        2 => false,
+        3 => true,
+        _ => panic!("Expected the header `states transitions` or `states choices transitions`, got {:?}.", header),
+    };
+    let num_states: usize = parse(Some(counts[0]), "the number of states", &header);
+
+    // choices[s] has the choices of s; a DTMC or CTMC has a single choice
+    let mut choices: Vec<Vec<Choice>> = vec![vec![]; num_states];
+    for line in lines {
+        let mut parts = line.split_ascii_whitespace();
+        let s: usize = parse(parts.next(), "a state", &line);
+        let i: usize = if is_mdp { parse(parts.next(), "a choice", &line) } else { 0 };
+        let t: u32 = parse(parts.next(), "a state", &line);
+        let p = parse_weight(parts.next(), &line);
+        let action = parts.next().map(String::from);
+        if s >= num_states || t as usize >= num_states { panic!("State out of range in line {:?}.", line) }
+        let state_choices = &mut choices[s];
+        while state_choices.len() <= i { state_choices.push((None, vec![])) }
+        if action.is_some() { state_choices[i].0 = action }
+        state_choices[i].1.push((Node::State(t), p));
+    }
+
+    let labels = match filename.with_extension("lab") {
+        f if f.exists() => read_lab(&f, num_states),
+        _ => vec![vec![]; num_states],
+    };
+    let rewards = match filename.with_extension("srew") {
+        f if f.exists() => read_srew(&f, num_states),
+        _ => vec![None; num_states],
+    };
+
+    let mut tags = ObservationTags::default();
+    let mut w = CWriter::new();
+    for (s, state_choices) in choices.into_iter().enumerate() {
+        let observation = Observation::State { labels: labels[s].clone(), rewards: rewards[s].iter().cloned().collect(), action_rewards: vec![] };
+        let tag = tags.tag(observation);
+        let node = if is_mdp {
+            let dists = state_choices.into_iter()
+                .map(|(name, trans)| Node::Mon(REAL_TYP, tags.tag(Observation::Action { name, rewards: vec![] }), trans))
+                .collect();
+            Node::Coll(SET_TYP, tag, dists)
+        } else {
+            Node::Mon(REAL_TYP, tag, state_choices.into_iter().next().map_or(vec![], |(_, trans)| trans))
+        };
+        node.write(&mut w);
+    }
+    w.meta.tag_names = tags.names;
+    w.meta.observations = tags.observations;
+    // the .tra file of a CTMC looks like that of a DTMC, so only an MDP is known to be one
+    w.meta.model_type = is_mdp.then(|| "MDP".to_string());
+    if let Some(f) = Some(filename.with_extension("sta")).filter(|f| f.exists()) {
+        w.meta.state_names = read_sta(&f, num_states);
+    }
+    w.finish()
+}
+
+/// A probability or rate as written in a .tra file
+fn format_weight(typ: u8, v: u64) -> String {
+    match typ {
+        REAL_TYP => f64::from_bits(v).to_string(),
+        ADD_TYP => v.to_string(),
+        INT_TYP => unzigzag(v).to_string(),
+        _ => panic!("Unreachable")
+    }
+}
+
+/// The transitions of a weighted node as target and weight
+pub fn weighted_transitions(node: &Node) -> Vec<(u32, String)> {
+    let target = |node: &Node| match node {
+        Node::State(t) => *t,
+        _ => panic!("Export requires the elements of weighted nodes to be states.")
+    };
+    match node {
+        Node::Mon(typ @ (REAL_TYP|ADD_TYP|INT_TYP), _, trans) =>
+            trans.iter().map(|(node, v)| (target(node), format_weight(*typ, *v))).collect(),
+        Node::Rat(_, trans) =>
+            trans.iter().map(|(node, num, den)| (target(node), (*num as f64 / *den as f64).to_string())).collect(),
+        _ => panic!("Export requires Real, Add, Int or Q nodes."),
+    }
+}
+
+pub fn tag_of(node: &Node) -> u32 {
+    match node {
+        Node::Coll(_, tag, _) | Node::Mon(_, tag, _) | Node::Rat(tag, _) => *tag,
+        _ => panic!("Export requires Set, Real, Add, Int or Q nodes."),
+    }
+}
+
+/// Write a coalgebra of the form produced by read_prism as a .tra file, with .lab and .srew files if it has labels or rewards
+pub fn write_prism<P>(filename: P, data: &[u8], r: &CReader)
+where P: AsRef<Path>, {
+    let filename = filename.as_ref();
+    if filename.extension().is_none_or(|ext| ext != "tra") {
+        panic!("File must be *.tra, but is {}", filename.display());
+    }
+    let nodes = read_nodes(data, r);
+    let is_mdp = nodes.iter().any(|node| matches!(node, Node::Coll(SET_TYP, _, _)));
+
+    let mut lines = vec![];
+    let mut num_choices = 0;
+    for (s, node) in nodes.iter().enumerate() {
+        if is_mdp {
+            let Node::Coll(SET_TYP, _, dists) = node else { panic!("PRISM export of an MDP requires all states to be Set nodes.") };
+            for (i, dist) in dists.iter().enumerate() {
+                let a = action_observation(&r.meta, tag_of(dist)).0.map_or(String::new(), |a| format!(" {}", a));
+                for (t, p) in weighted_transitions(dist) { lines.push(format!("{} {} {} {}{}", s, i, t, p, a)) }
+            }
+            num_choices += dists.len();
+        } else {
+            for (t, p) in weighted_transitions(node) { lines.push(format!("{} {} {}", s, t, p)) }
+        }
+    }
+    let mut writer = BufWriter::new(create_file(filename));
+    if is_mdp {
+        writeln!(writer, "{} {} {}", nodes.len(), num_choices, lines.len()).expect("Writing error.");
+    } else {
+        writeln!(writer, "{} {}", nodes.len(), lines.len()).expect("Writing error.");
+    }
+    for line in lines { writeln!(writer, "{}", line).expect("Writing error.") }
+
+    write_lab_srew(filename, &nodes, &r.meta);
+}
+
+/// Write the labels and the rewards of the first reward model of the states, as PRISM has one per .srew file
+fn write_lab_srew(filename: &Path, nodes: &[Node], meta: &Meta) {
+    let observations: Vec<(&[String], &[String], &[String])> = nodes.iter().map(|node| state_observation(meta, tag_of(node))).collect();
+    let mut label_names: Vec<&String> = observations.iter().flat_map(|(labels, _, _)| labels.iter()).collect();
+    label_names.sort();
+    label_names.dedup();
+    // PRISM puts init first
+    if let Some(i) = label_names.iter().position(|&l| l == "init") { label_names[..=i].rotate_right(1) }
+    if !label_names.is_empty() {
+        let mut writer = BufWriter::new(create_file(filename.with_extension("lab")));
+        let decls: Vec<String> = label_names.iter().enumerate().map(|(i, l)| format!("{}=\"{}\"", i, l)).collect();
+        writeln!(writer, "{}", decls.join(" ")).expect("Writing error.");
+        for (s, (labels, _, _)) in observations.iter().enumerate() {
+            if labels.is_empty() { continue }
+            let ls: Vec<String> = labels.iter().map(|l| label_names.iter().position(|m| *m == l).unwrap().to_string()).collect();
+            writeln!(writer, "{}: {}", s, ls.join(" ")).expect("Writing error.");
+        }
+    }
+    let rewards: Vec<(usize, &String)> = observations.iter().enumerate()
+        .filter_map(|(s, (_, rewards, _))| rewards.first().filter(|&r| r != "0").map(|r| (s, r)))
+        .collect();
+    if !rewards.is_empty() {
+        let mut writer = BufWriter::new(create_file(filename.with_extension("srew")));
+        writeln!(writer, "{} {}", nodes.len(), rewards.len()).expect("Writing error.");
+        for (s, r) in rewards { writeln!(writer, "{} {}", s, r).expect("Writing error.") }
+    }
+}
+
+#[test]
+fn test_read_prism_dtmc() {
+    use crate::{naivealg::partref_naive, optalg::partref_nlogn, renumber, binrep::read_nodes};
+    // States 0 and 5 have label init and go to {1,2} with probability 0.3 and 0.7, up to rounding.
+    // States 1 and 2 go to 3 and 4, which have label goal.
+    let (data, r) = read_prism("tests/prism_dtmc.tra");
+    assert_eq!(r.meta.tag_names.values().collect::<Vec<_>>(), vec!["{init}", "{}", "{goal}"]);
+    assert_eq!(read_nodes(&data, &r)[3], Node::from_ascii("Real[2]{@3:1}".as_bytes()));
+    let ids1 = partref_naive(&data, &r);
+    let ids2 = partref_nlogn(data, r);
+    assert_eq!(&renumber(&ids1), &ids2);
+    assert_eq!(ids2, vec![0,1,1,2,2,0]);
+}
+
+#[test]
+fn test_read_prism_mdp() {
+    use crate::{naivealg::partref_naive, optalg::partref_nlogn, renumber, binrep::read_nodes};
+    // 0: a -> 0.5 @1 + 0.5 @2, b -> @3
+    // 1, 2: absorbing with reward 1
+    // 3: -> @3, b -> @1
+    let (data, r) = read_prism("tests/prism_mdp.tra");
+    assert_eq!(r.meta.state_name(3), Some("(3)"));
+    let names: Vec<&String> = r.meta.tag_names.values().collect();
+    assert_eq!(names, vec!["{init}", "a", "b", "{} r=1", "{}"]);
+    assert_eq!(read_nodes(&data, &r)[3], Node::from_ascii("Set[5]{Real[4]{@3:1},Real[2]{@1:1}}".as_bytes()));
+    let ids1 = partref_naive(&data, &r);
+    let ids2 = partref_nlogn(data, r);
+    assert_eq!(&renumber(&ids1), &ids2);
+    assert_eq!(ids2, vec![0,1,1,2]);
+}
+
+#[test]
+fn test_write_prism_quotient() {
+    use crate::{optalg::partref_nlogn, quotient::quotient};
+    for (model, num_blocks) in [("dtmc", 3), ("mdp", 3)] {
+        let (data, r) = read_prism(format!("tests/prism_{}.tra", model));
+        let ids = partref_nlogn(data.clone(), r.clone());
+        let (qdata, qr) = quotient(&data, &r, &ids);
+        let path = std::env::temp_dir().join(format!("boa_prism_test_{}_{}.tra", model, std::process::id()));
+        write_prism(&path, &qdata, &qr);
+        let (data2, r2) = read_prism(&path);
+        let lab = std::fs::read_to_string(path.with_extension("lab")).unwrap();
+        let srew = std::fs::read_to_string(path.with_extension("srew")).ok();
+        let tra = std::fs::read_to_string(&path).unwrap();
+        for ext in ["tra", "lab", "srew"] { std::fs::remove_file(path.with_extension(ext)).ok(); }
+        assert_eq!(read_nodes(&data2, &r2), read_nodes(&qdata, &qr));
+        assert_eq!(partref_nlogn(data2, r2).len(), num_blocks);
+        if model == "dtmc" {
+            assert_eq!(tra, "3 3\n0 1 1\n1 2 1\n2 2 1\n");
+            assert_eq!(lab, "0=\"init\" 1=\"goal\"\n0: 0\n2: 1\n");
+            assert_eq!(srew, None);
+        } else {
+            assert_eq!(tra, "3 5 5\n0 0 1 1 a\n0 1 2 1 b\n1 0 1 1\n2 0 2 1\n2 1 1 1 b\n");
+            assert_eq!(lab, "0=\"init\"\n0: 0\n");
+            assert_eq!(srew.as_deref(), Some("3 1\n1 1\n"));
+        }
+    }
+}
+
+#[test]
+fn test_prism_labels_roundtrip() {
+    // labels that look like the separators in the names of the tags
+    let dir = std::env::temp_dir().join(format!("boa_prism_labels_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("m.tra");
+    std::fs::write(&path, "3 3\n0 1 1\n1 2 1\n2 2 1\n").unwrap();
+    std::fs::write(path.with_extension("lab"), "0=\"init\" 1=\"a,b}\" 2=\"r=2\"\n0: 0\n1: 1\n2: 2\n").unwrap();
+    std::fs::write(path.with_extension("srew"), "3 1\n2 2\n").unwrap();
+    let (data, r) = read_prism(&path);
+    let labels: Vec<&[String]> = (0..3).map(|tag| state_observation(&r.meta, tag).0).collect();
+    assert_eq!(labels, vec![&["init".to_string()][..], &["a,b}".to_string()], &["r=2".to_string()]]);
+    assert_eq!(state_observation(&r.meta, 2).1, &["2".to_string()]);
+    assert_eq!(r.meta.tag_names.values().collect::<Vec<_>>(), vec!["{init}", "{\"a,b}\"}", "{\"r=2\"} r=2"]);
+    let out = dir.join("out.tra");
+    write_prism(&out, &data, &r);
+    let (data2, r2) = read_prism(&out);
+    assert_eq!(read_nodes(&data2, &r2), read_nodes(&data, &r));
+    assert_eq!(r2.meta.observations, r.meta.observations);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,204 @@
+//=====================//
+// Quotient coalgebra //
+//=====================//
+
+// Given a partition into bisimulation classes, the quotient has one state per block. The node of a block is the node
+// of a representative, the first state of the block, with every state @s replaced by @b where b is the block of s.
+// Elements of a node that now refer to the same block are merged: a Set keeps one of them, a Tag node keeps one per
+// label, and the other monoid-valued nodes combine their values with the operation of the monoid, so that
+// for instance the probabilities of going to the states of a block are summed.
+// Elements that are nested sub-nodes are merged in the same way if they are equal after merging their own elements,
+// up to the order of the elements of unordered nodes. For instance, two choices of an MDP that go to the same blocks
+// with the same probabilities become one choice.
+// Shared nodes are inlined, and the states of the quotient keep the names of their representatives.
+// Block b is state b of the quotient, so for the partitions computed by boa state 0 stays state 0,
+// and the initial state of the coalgebra, if it has another one, becomes its block.
+
+use std::cmp::{max, min};
+
+use num_rational::Ratio;
+
+use crate::binrep::{read_nodes, CReader, CWriter, Node, RealKeys, ADD_TYP, INT_TYP, MAX_TYP, MIN_TYP, MUL_TYP, OR_TYP, REAL_TYP, SET_TYP, TAG_TYP};
+use crate::coalg::{add_op, int_op, mul_op};
+use crate::hmap::HMap;
+
+/// The operation that combines the values of elements with the same state in a node of type typ,
+/// or None if the elements are kept apart
+fn merge_op(typ: u8) -> Option<fn(u64, u64) -> u64> {
+    match typ {
+        ADD_TYP => Some(add_op),
+        OR_TYP => Some(|a, b| a | b),
+        MAX_TYP => Some(max),
+        MIN_TYP => Some(min),
+        INT_TYP => Some(int_op),
+        MUL_TYP => Some(mul_op),
+        REAL_TYP => Some(|a, b| (f64::from_bits(a) + f64::from_bits(b)).to_bits()),
+        _ => None,
+    }
+}
+
+fn rat_add(a: (u64, u64), b: (u64, u64)) -> (u64, u64) {
+    let sum = Ratio::new(a.0 as u128, a.1 as u128) + Ratio::new(b.0 as u128, b.1 as u128);
+    match (u64::try_from(*sum.numer()), u64::try_from(*sum.denom())) {
+        (Ok(num), Ok(den)) => (num, den),
+        _ => panic!("Overflow while summing the values of a Q node."),
+    }
+}
+
+/// The node with the elements of its unordered sub-nodes sorted and its real weights replaced by their key in reals.
+/// Two nodes whose elements have been merged get the same canonical node if and only if refinement can't tell them apart,
+/// except for the nodes of user-defined types, whose elements are kept in order.
+pub fn canonical(node: &Node, reals: &RealKeys) -> Node {
+    match node {
+        Node::State(_) | Node::Const(_) | Node::Shared(_) => node.clone(),
+        Node::Coll(typ, tag, nodes) => {
+            let mut nodes: Vec<Node> = nodes.iter().map(|node| canonical(node, reals)).collect();
+            if *typ == SET_TYP { nodes.sort() }
+            Node::Coll(*typ, *tag, nodes)
+        }
+        Node::Mon(typ, tag, nodes) => {
+            let mut nodes: Vec<(Node, u64)> = nodes.iter().map(|(node, v)| {
+                (canonical(node, reals), if *typ == REAL_TYP { reals.key(f64::from_bits(*v)) } else { *v })
+            }).collect();
+            nodes.sort();
+            Node::Mon(*typ, *tag, nodes)
+        }
+        Node::Rat(tag, nodes) => {
+            let mut nodes: Vec<(Node, u64, u64)> = nodes.iter().map(|(node, num, den)| (canonical(node, reals), *num, *den)).collect();
+            nodes.sort();
+            Node::Rat(*tag, nodes)
+        }
+        Node::Ext(typ, tag, nodes) => Node::Ext(*typ, *tag, nodes.iter().map(|(node, vs)| (canonical(node, reals), vs.clone())).collect()),
+    }
+}
+
+/// Merge the elements of the node and its sub-nodes that are equal, keeping the first of them in place.
+/// Elements are equal if they have the same canonical node after merging their own elements.
+pub fn merge_elements(node: &mut Node, reals: &RealKeys) {
+    match node {
+        Node::State(_) | Node::Const(_) | Node::Shared(_) => {}
+        Node::Coll(typ, _, nodes) => {
+            for node in nodes.iter_mut() { merge_elements(node, reals) }
+            if *typ == SET_TYP {
+                let mut seen = HMap::default();
+                nodes.retain(|node| seen.insert(canonical(node, reals), ()).is_none());
+            }
+        }
+        Node::Mon(typ, _, nodes) => {
+            for (node, _) in nodes.iter_mut() { merge_elements(node, reals) }
+            if *typ == TAG_TYP {
+                let mut seen = HMap::default();
+                nodes.retain(|(node, label)| seen.insert((canonical(node, reals), *label), ()).is_none());
+            } else if let Some(op) = merge_op(*typ) {
+                let mut first: HMap<Node, usize> = HMap::default();
+                let mut merged: Vec<(Node, u64)> = Vec::with_capacity(nodes.len());
+                for (node, v) in nodes.drain(..) {
+                    let key = canonical(&node, reals);
+                    if let Some(&i) = first.get(&key) {
+                        merged[i].1 = op(merged[i].1, v);
+                        continue;
+                    }
+                    first.insert(key, merged.len());
+                    merged.push((node, v));
+                }
+                *nodes = merged;
+            }
+        }
+        Node::Rat(_, nodes) => {
+            for (node, _, _) in nodes.iter_mut() { merge_elements(node, reals) }
+            let mut first: HMap<Node, usize> = HMap::default();
+            let mut merged: Vec<(Node, u64, u64)> = Vec::with_capacity(nodes.len());
+            for (node, num, den) in nodes.drain(..) {
+                let key = canonical(&node, reals);
+                if let Some(&i) = first.get(&key) {
+                    let (num, den) = rat_add((merged[i].1, merged[i].2), (num, den));
+                    merged[i].1 = num;
+                    merged[i].2 = den;
+                    continue;
+                }
+                first.insert(key, merged.len());
+                merged.push((node, num, den));
+            }
+            *nodes = merged;
+        }
+        Node::Ext(_, _, nodes) => {
+            for (node, _) in nodes.iter_mut() { merge_elements(node, reals) }
+        }
+    }
+}
+
+/// Build the quotient of the coalgebra by the partition ids, which gives the block of each state.
+/// The blocks must be numbered 0..n without gaps.
+pub fn quotient(data: &[u8], r: &CReader, ids: &[u32]) -> (Vec<u8>, CReader) {
+    let nodes = read_nodes(data, r);
+    if nodes.len() != ids.len() { panic!("The partition has {} states, but the coalgebra has {}.", ids.len(), nodes.len()) }
+    let num_blocks = ids.iter().max().map_or(0, |&m| m as usize + 1);
+    let mut reps = vec![u32::MAX; num_blocks];
+    for (state, &block) in ids.iter().enumerate() {
+        if reps[block as usize] == u32::MAX { reps[block as usize] = state as u32 }
+    }
+    if reps.contains(&u32::MAX) { panic!("The blocks of the partition are not numbered without gaps.") }
+
+    let mut w = CWriter::new();
+    w.meta.tag_names = r.meta.tag_names.clone();
+    w.meta.label_names = r.meta.label_names.clone();
+    w.meta.model_type = r.meta.model_type.clone();
+    w.meta.reward_models = r.meta.reward_models.clone();
+    w.meta.observations = r.meta.observations.clone();
+    w.meta.initial = r.meta.initial.map(|s| ids[s as usize]).filter(|&b| b != 0);
+    let mut nodes: Vec<Option<Node>> = nodes.into_iter().map(Some).collect();
+    for &rep in &reps {
+        let mut node = nodes[rep as usize].take().unwrap();
+        node.map_states(&|s| ids[s as usize]);
+        merge_elements(&mut node, &r.reals);
+        node.write(&mut w);
+    }
+    if !r.meta.state_names.is_empty() {
+        w.meta.state_names = reps.iter().map(|&rep| r.meta.state_name(rep).map(String::from)).collect();
+    }
+    w.finish()
+}
+
+#[test]
+fn test_quotient() {
+    use crate::{io::read_boa_txt, optalg::partref_nlogn};
+    let (data, r) = read_boa_txt("tests/quotient_test.boa.txt");
+    let ids = partref_nlogn(data.clone(), r.clone());
+    assert_eq!(ids, vec![0,1,1,2,3,3]);
+    let (qdata, qr) = quotient(&data, &r, &ids);
+    assert_eq!(qr.meta.state_names, vec![Some("s".to_string()), Some("a".to_string()), None, Some("t".to_string())]);
+    let expected = ["Add[0]{@1:3,@2:1}", "Set[1]{@2,@3}", "List[2]{Tag[0]{@3:1,@3:2},Q[0]{@1:5/6}}", "Real[1]{@3:0.75,@0:0.25}"];
+    let expected: Vec<Node> = expected.iter().map(|s| Node::from_ascii(s.as_bytes())).collect();
+    assert_eq!(read_nodes(&qdata, &qr), expected);
+    // the quotient is minimal
+    let qids = partref_nlogn(qdata, qr);
+    assert_eq!(qids, vec![0,1,2,3]);
+}
+
+#[test]
+fn test_quotient_nested() {
+    use crate::optalg::partref_nlogn;
+    // An MDP whose first state has two choices that only differ in the order of the blocks they go to, and whose
+    // last state has two choices with probabilities that are only equal up to rounding
+    let nodes = [
+        "Set[0]{Real[0]{@1:0.5,@3:0.5},Real[0]{@3:0.5,@2:0.5}}",
+        "Set[0]{}",
+        "Set[0]{}",
+        "Set[0]{Real[1]{@1:1}}",
+        "Set[0]{Real[0]{@1:0.1,@1:0.2,@0:0.7},Real[0]{@0:0.7,@2:0.3}}",
+    ];
+    let mut w = CWriter::new();
+    for node in nodes { Node::from_ascii(node.as_bytes()).write(&mut w) }
+    let (data, r) = w.finish();
+    let ids = partref_nlogn(data.clone(), r.clone());
+    assert_eq!(ids, vec![0,1,1,2,3]);
+    let (qdata, qr) = quotient(&data, &r, &ids);
+    let nodes = read_nodes(&qdata, &qr);
+    assert_eq!(nodes[0], Node::from_ascii("Set[0]{Real[0]{@1:0.5,@2:0.5}}".as_bytes()));
+    match &nodes[3] {
+        Node::Coll(SET_TYP, 0, choices) => assert_eq!(choices.len(), 1),
+        node => panic!("Unexpected node {:?}", node),
+    }
+    let qids = partref_nlogn(qdata, qr);
+    assert_eq!(qids, vec![0,1,2,3]);
+}
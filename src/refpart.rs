@@ -41,7 +41,7 @@ fn counts_vec(xs: &[u32]) -> Vec<u32> {
 
 #[test]
 fn test_counts_vec() {
-  let counts = counts_vec(&vec![0,0,1,1,3,4,5,5,5]);
+  let counts = counts_vec(&[0,0,1,1,3,4,5,5,5]);
   assert_eq!(counts[0],2);
   assert_eq!(counts[1],2);
   assert_eq!(counts[3],1);
@@ -63,7 +63,7 @@ fn index_of_max(counts: &[u32]) -> usize {
 
 #[test]
 fn test_index_of_max() {
-  assert_eq!(index_of_max(&vec![0,3,1,2,3,4,3]), 5);
+  assert_eq!(index_of_max(&[0,3,1,2,3,4,3]), 5);
 }
 
 type State = u32;
@@ -149,7 +149,7 @@ impl RefinablePartition {
       // let signatures = renumber(signatures); // Renumber signatures to be 0..n. This makes the sig of the clean states 0 if there are any.
 
       // compute the occurrence counts of each of the signatures
-      let mut counts = counts_vec(&signatures);
+      let mut counts = counts_vec(signatures);
 
       let (start,mid,end) = self.partition[partition_id as usize];
       if start < mid { counts[0] += mid - start - 1 } // add count of clean part
@@ -0,0 +1,357 @@
+//=====================//
+// Simulation preorder //
+//=====================//
+
+// For coalgebras whose nodes are Set, Or or Tag nodes over states (i.e. labelled transition systems),
+// we compute the largest simulation relation.
+// State x is simulated by state y if their nodes have the same type and tag,
+// and for every transition x -l-> x' there is a transition y -l-> y' such that x' is simulated by y'.
+// Set nodes have a single unlabelled transition to each element, Tag nodes have a transition labelled
+// with the value to each element, and Or nodes have a transition labelled i to an element for every bit i
+// that is set in its value.
+//
+// Bisimilar states simulate each other, so we first compute the bisimulation quotient with the nlogn algorithm,
+// and then compute the preorder over its blocks with a partition-relation algorithm in the style of Ranzato & Tapparo.
+// The blocks are grouped into classes, and a relation over the classes says which classes may still simulate which,
+// so that block x is simulated by block y iff class(x) is related to class(y). The classes start as the blocks with
+// the same type, tag and set of labels, and class c is related to class d if they have the same type and tag
+// and d has a transition with every label that c has one with.
+// For each block w with an l-transition and each class c, a counter keeps the number of l-successors of w in the classes
+// related to c. When it drops to 0, w can't simulate any l-predecessor of c, so w is added to remove(c,l).
+// Processing remove(c,l) splits the classes so that the l-predecessors of c and the blocks in remove(c,l) are unions
+// of classes, and then removes the pairs between them from the relation, decrementing the counters of the
+// predecessors of the removed classes. When a class is split, both halves keep its row and column of the relation,
+// its counters and its pending removals.
+//
+// With n blocks, m transitions and k final classes, the relation takes k^2 bits and the counters k times the number
+// of (block, label) pairs with transitions, which is at most m. The counter of a class and a transition is decremented
+// at most once, so the updates take O(k*m) time. Blocks in the same class are always related both ways, so k is at least
+// the number of simulation equivalence classes, and at most n. A class is only split if its blocks differ in whether
+// they are l-predecessors of a class with pending removals, or in whether they are among these removals.
+// The predecessors are found using the backrefs of the coalgebra.
+
+use itertools::Itertools;
+
+use crate::binrep::{CReader, Node, SET_TYP, OR_TYP, TAG_TYP};
+use crate::coalg::Coalg;
+use crate::hmap::HMap;
+use crate::optalg::partref_nlogn;
+
+#[cfg(test)]
+use crate::{io::read_boa_txt, binrep::CWriter};
+
+/// Set or clear bit j of a row of bits, which grows as needed
+fn set_bit(row: &mut Vec<u64>, j: u32, value: bool) {
+    let (i, mask) = (j as usize / 64, 1 << (j % 64));
+    if row.len() <= i { row.resize(i + 1, 0) }
+    if value { row[i] |= mask } else { row[i] &= !mask }
+}
+
+fn get_bit(row: &[u64], j: u32) -> bool {
+    row.get(j as usize / 64).is_some_and(|w| w & (1 << (j % 64)) != 0)
+}
+
+/// A preorder over blocks, stored as a partition of the blocks into classes and a relation over the classes
+pub struct Preorder {
+    pub num_blocks: usize,
+    class_of: Vec<u32>,
+    members: Vec<Vec<u32>>,
+    rel: Vec<Vec<u64>>,
+}
+
+impl Preorder {
+    /// Is block a simulated by block b?
+    pub fn le(self: &Self, a: u32, b: u32) -> bool {
+        get_bit(&self.rel[self.class_of[a as usize] as usize], self.class_of[b as usize])
+    }
+
+    /// All pairs (a,b) with a != b such that a is simulated by b
+    pub fn pairs(self: &Self) -> Vec<(u32,u32)> {
+        let mut pairs = vec![];
+        for a in 0..self.num_blocks as u32 {
+            let row = &self.rel[self.class_of[a as usize] as usize];
+            for d in (0..self.members.len() as u32).filter(|&d| get_bit(row, d)) {
+                pairs.extend(self.members[d as usize].iter().filter(|&&b| b != a).map(|&b| (a, b)));
+            }
+        }
+        pairs.sort_unstable();
+        return pairs;
+    }
+}
+
+/// The classes of the partition-relation algorithm, with the state kept for each class
+struct Classes {
+    class_of: Vec<u32>,
+    members: Vec<Vec<u32>>,
+    rel: Vec<Vec<u64>>, // bit d of rel[c] is set if class d may simulate class c
+    count: Vec<Vec<u32>>, // count[c][slot(w,l)] is the number of l-successors of w in the classes related to c
+    remove: Vec<HMap<u64, Vec<u32>>>, // remove[c][l] are the blocks with an l-transition but none to the classes related to c
+}
+
+impl Classes {
+    /// Split the classes so that the blocks, which are sorted and distinct, are a union of classes.
+    /// The new classes copy the relation, the counters and the pending removals of the class they are split from.
+    fn split(self: &mut Self, blocks: &[u32], worklist: &mut Vec<(u32,u64)>) {
+        let mut by_class: Vec<(u32,u32)> = blocks.iter().map(|&b| (self.class_of[b as usize], b)).collect();
+        by_class.sort_unstable();
+        for group in by_class.chunk_by(|a, b| a.0 == b.0) {
+            let c = group[0].0;
+            let group: Vec<u32> = group.iter().map(|&(_, b)| b).collect();
+            if group.len() == self.members[c as usize].len() { continue }
+            let k = self.members.len() as u32;
+            for &b in &group { self.class_of[b as usize] = k }
+            let class_of = &self.class_of;
+            self.members[c as usize].retain(|&b| class_of[b as usize] == c);
+            self.members.push(group);
+            self.rel.push(self.rel[c as usize].clone());
+            for row in self.rel.iter_mut() {
+                if get_bit(row, c) { set_bit(row, k, true) }
+            }
+            self.count.push(self.count[c as usize].clone());
+            self.remove.push(self.remove[c as usize].clone());
+            worklist.extend(self.remove[k as usize].iter().filter(|(_, r)| !r.is_empty()).map(|(&l, _)| (k, l)));
+        }
+    }
+}
+
+/// The observation (typ, tag) and the sorted, deduplicated (label, successor) transitions of a state
+fn transitions(node: &Node) -> ((u8, u32), Vec<(u64, u32)>) {
+    let succ = |node: &Node| match node {
+        Node::State(state) => *state,
+        _ => panic!("Simulation requires the elements of nodes to be states.")
+    };
+    let mut trans: Vec<(u64,u32)> = match node {
+        Node::Coll(SET_TYP, _, nodes) => nodes.iter().map(|node| (0, succ(node))).collect(),
+        Node::Mon(TAG_TYP, _, nodes) => nodes.iter().map(|(node, label)| (*label, succ(node))).collect(),
+        Node::Mon(OR_TYP, _, nodes) => nodes.iter()
+            .flat_map(|(node, bits)| (0..64).filter(move |i| bits & (1 << i) != 0).map(move |i| (i, succ(node))))
+            .collect(),
+        _ => panic!("Simulation requires Set, Or or Tag nodes.")
+    };
+    let obs = match node {
+        Node::Coll(typ, tag, _) | Node::Mon(typ, tag, _) => (*typ, *tag),
+        _ => panic!("Simulation requires Set, Or or Tag nodes.")
+    };
+    trans.sort_unstable();
+    trans.dedup();
+    return (obs, trans);
+}
+
+/// Compute the bisimulation blocks of the states, and the simulation preorder over these blocks
+pub fn simulation_preorder(data: Vec<u8>, r: CReader) -> (Vec<u32>, Preorder) {
+    let ids = partref_nlogn(data.clone(), r.clone());
+    let coa = Coalg::new(data, r);
+    let num_blocks = ids.iter().max().map_or(0, |m| *m as usize + 1);
+
+    // Transitions of the quotient, taken from a representative of each block
+    let mut reps = vec![u32::MAX; num_blocks];
+    for (state, &block) in ids.iter().enumerate() {
+        if reps[block as usize] == u32::MAX { reps[block as usize] = state as u32 }
+    }
+    let mut obs = vec![];
+    let mut trans = vec![];
+    for &rep in &reps {
+        let mut p = coa.locs[rep as usize];
+        let node = unsafe { Node::read(&coa.reader, &mut p) };
+        let (o, mut t) = transitions(&node);
+        for lt in t.iter_mut() { lt.1 = ids[lt.1 as usize] }
+        t.sort_unstable();
+        t.dedup();
+        obs.push(o);
+        trans.push(t);
+    }
+
+    // Labelled predecessors of each block, found through the backrefs of its states.
+    // Bisimilar states have the same transitions up to blocks, so the transitions of the representatives suffice.
+    let mut preds: Vec<Vec<u32>> = vec![vec![]; num_blocks];
+    for state in 0..coa.num_states() {
+        let block = ids[state as usize] as usize;
+        preds[block].extend(coa.state_backrefs(state).iter().map(|&s| ids[s as usize]));
+    }
+    let mut pre: Vec<Vec<(u64,u32)>> = vec![vec![]; num_blocks];
+    for (v, p) in preds.iter_mut().enumerate() {
+        p.sort_unstable();
+        p.dedup();
+        for &u in p.iter() {
+            pre[v].extend(trans[u as usize].iter().filter(|&&(_, v2)| v2 as usize == v).map(|&(l, _)| (l, u)));
+        }
+        pre[v].sort_unstable();
+    }
+
+    // The distinct labels of the transitions of each block, each with a row of counters
+    let mut slot_start = vec![0];
+    let mut labels = vec![];
+    for t in &trans {
+        labels.extend(t.iter().map(|&(l, _)| l).dedup());
+        slot_start.push(labels.len());
+    }
+    let slot = |w: u32, l: u64| {
+        let (start, end) = (slot_start[w as usize], slot_start[w as usize + 1]);
+        start + labels[start..end].binary_search(&l).expect("Block has no transition with this label.")
+    };
+    let has_label = |w: usize, l: u64| labels[slot_start[w]..slot_start[w + 1]].binary_search(&l).is_ok();
+
+    let labels_of = |v: usize| &labels[slot_start[v]..slot_start[v + 1]];
+
+    // The initial classes, by observation and labels
+    let mut initial: HMap<((u8, u32), &[u64]), u32> = HMap::default();
+    let mut class_of = vec![0; num_blocks];
+    let mut members: Vec<Vec<u32>> = vec![];
+    for v in 0..num_blocks {
+        let c = *initial.entry((obs[v], labels_of(v))).or_insert_with(|| { members.push(vec![]); members.len() as u32 - 1 });
+        class_of[v] = c;
+        members[c as usize].push(v as u32);
+    }
+    let num_classes = members.len();
+    let reps: Vec<usize> = members.iter().map(|m| m[0] as usize).collect();
+    let rel: Vec<Vec<u64>> = reps.iter().map(|&v| {
+        let mut row = vec![0; num_classes.div_ceil(64)];
+        for (d, &u) in reps.iter().enumerate() {
+            if obs[u] == obs[v] && labels_of(v).iter().all(|&l| has_label(u, l)) { set_bit(&mut row, d as u32, true) }
+        }
+        row
+    }).collect();
+    let count: Vec<Vec<u32>> = rel.iter().map(|row| {
+        let mut count = vec![0; labels.len()];
+        for w in 0..num_blocks as u32 {
+            for &(l, w2) in &trans[w as usize] {
+                if get_bit(row, class_of[w2 as usize]) { count[slot(w, l)] += 1 }
+            }
+        }
+        count
+    }).collect();
+    let mut classes = Classes { class_of, members, rel, count, remove: vec![HMap::default(); num_classes] };
+
+    // remove(c,l) is only needed if c has l-predecessors
+    let mut worklist = vec![];
+    for c in 0..num_classes {
+        let pre_labels: Vec<u64> = classes.members[c].iter().flat_map(|&v| pre[v as usize].iter().map(|&(l, _)| l)).sorted().dedup().collect();
+        for w in 0..num_blocks {
+            for (s, &l) in labels_of(w).iter().enumerate() {
+                if classes.count[c][slot_start[w] + s] == 0 && pre_labels.binary_search(&l).is_ok() {
+                    classes.remove[c].entry(l).or_default().push(w as u32);
+                }
+            }
+        }
+        worklist.extend(classes.remove[c].keys().map(|&l| (c as u32, l)));
+    }
+
+    while let Some((c, l)) = worklist.pop() {
+        let Some(removed) = classes.remove[c as usize].remove(&l) else { continue };
+        let preds: Vec<u32> = classes.members[c as usize].iter()
+            .flat_map(|&v| {
+                let pre_v = &pre[v as usize];
+                let start = pre_v.partition_point(|&(l2, _)| l2 < l);
+                pre_v[start..].iter().take_while(|&&(l2, _)| l2 == l).map(|&(_, u)| u)
+            })
+            .sorted().dedup().collect();
+        let removed: Vec<u32> = removed.into_iter().sorted().dedup().collect();
+        classes.split(&preds, &mut worklist);
+        classes.split(&removed, &mut worklist);
+        let pred_classes: Vec<u32> = preds.iter().map(|&u| classes.class_of[u as usize]).sorted().dedup().collect();
+        let mut removed_classes = vec![];
+        for &w in &removed { set_bit(&mut removed_classes, classes.class_of[w as usize], true) }
+        for &u in &pred_classes {
+            // the removed classes that are still related to u, found a word of bits at a time
+            let row = &mut classes.rel[u as usize];
+            let mut related = vec![];
+            for (i, (bits, removed_bits)) in row.iter_mut().zip(&removed_classes).enumerate() {
+                let mut both = *bits & removed_bits;
+                *bits &= !removed_bits;
+                while both != 0 {
+                    related.push((i * 64) as u32 + both.trailing_zeros());
+                    both &= both - 1;
+                }
+            }
+            for w in related {
+                for &v in &classes.members[w as usize] {
+                    for &(l2, w2) in &pre[v as usize] {
+                        let c = &mut classes.count[u as usize][slot(w2, l2)];
+                        *c -= 1;
+                        if *c == 0 {
+                            let r = classes.remove[u as usize].entry(l2).or_default();
+                            if r.is_empty() { worklist.push((u, l2)) }
+                            r.push(w2);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    let Classes { class_of, members, rel, .. } = classes;
+    return (ids, Preorder { num_blocks, class_of, members, rel });
+}
+
+#[test]
+fn test_simulation_preorder() {
+    // 0: Tag[0]{@1:0,@2:0}   a.b + a.c
+    // 1: Tag[0]{@3:1}        b
+    // 2: Tag[0]{@3:2}        c
+    // 3: Tag[0]{}            nil
+    // 4: Tag[0]{@5:0}        a.(b + c)
+    // 5: Tag[0]{@3:1,@3:2}   b + c
+    // 6: Tag[0]{@6:0}        a.a.a...
+    let (data,r) = read_boa_txt("tests/simulation_test.boa.txt");
+    let (ids, rel) = simulation_preorder(data, r);
+    assert_eq!(rel.num_blocks, 7);
+    let le = |x: u32, y: u32| rel.le(ids[x as usize], ids[y as usize]);
+    assert!(le(0, 4));
+    assert!(!le(4, 0));
+    assert!(le(1, 5) && le(2, 5) && !le(5, 1));
+    assert!(!le(1, 2) && !le(2, 1));
+    for x in 0..7 { assert!(le(3, x)); assert!(le(x, x)); }
+    assert!(!le(0, 6) && !le(6, 0) && !le(4, 6));
+    assert!(!le(5, 6) && !le(6, 5));
+}
+
+#[test]
+fn test_simulation_classes() {
+    // a.b + a.(b + c) and a.(b + c) simulate each other without being bisimilar, so they stay in one class
+    let mut w = CWriter::new();
+    for node in ["Tag[0]{@2:0,@3:0}", "Tag[0]{@3:0}", "Tag[0]{@4:1}", "Tag[0]{@4:1,@4:2}", "Tag[0]{}"] {
+        Node::from_ascii(node.as_bytes()).write(&mut w);
+    }
+    let (data, r) = w.finish();
+    let (ids, rel) = simulation_preorder(data, r);
+    assert_eq!(rel.num_blocks, 5);
+    assert_eq!(rel.members.len(), 4);
+    assert!(rel.le(ids[0], ids[1]) && rel.le(ids[1], ids[0]));
+    assert!(rel.le(ids[2], ids[3]) && !rel.le(ids[3], ids[2]));
+    assert_eq!(rel.pairs().len(), 2 + 1 + 4);
+}
+
+#[test]
+fn test_simulation_preorder_agrees_with_fixpoint() {
+    // Random LTSs with 3 labels, compared with the greatest fixpoint of the transfer condition on the quotient
+    let mut seed = 12345u64;
+    let mut rand = |n: u64| { seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407); (seed >> 33) % n };
+    for _ in 0..20 {
+        let n = 2 + rand(12) as u32;
+        let mut w = CWriter::new();
+        for _ in 0..n {
+            let nodes = (0..rand(4)).map(|_| (Node::State(rand(n as u64) as u32), rand(3))).collect();
+            Node::Mon(TAG_TYP, rand(2) as u32, nodes).write(&mut w);
+        }
+        let (data, r) = w.finish();
+        let nodes: Vec<Node> = crate::binrep::read_nodes(&data, &r);
+        let (ids, rel) = simulation_preorder(data, r);
+        let mut le = vec![vec![true; n as usize]; n as usize];
+        loop {
+            let mut changed = false;
+            for x in 0..n as usize {
+                for y in 0..n as usize {
+                    if !le[x][y] { continue }
+                    let ((ox, tx), (oy, ty)) = (transitions(&nodes[x]), transitions(&nodes[y]));
+                    let ok = ox == oy && tx.iter().all(|&(l, x2)| ty.iter().any(|&(l2, y2)| l == l2 && le[x2 as usize][y2 as usize]));
+                    if !ok { le[x][y] = false; changed = true }
+                }
+            }
+            if !changed { break }
+        }
+        for x in 0..n as usize {
+            for y in 0..n as usize {
+                assert_eq!(rel.le(ids[x], ids[y]), le[x][y], "@{} @{}", x, y);
+            }
+        }
+    }
+}
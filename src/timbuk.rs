@@ -0,0 +1,209 @@
+//========================================//
+// Timbuk-style weighted tree automata //
+//========================================//
+
+// A tree automaton in the format of Timbuk has sections that start with a keyword:
+//   Ops a:0 f:2
+//   Automaton A
+//   States q0 q1
+//   Final States q0
+//   Transitions
+//   a -> q1
+//   f(q1,q1) -> q0 : 2
+// Ops gives the symbols and their arities, and each transition f(q1,...,qn) -> q can have a weight after a colon,
+// which is 1 if it is left out. Final states can also have a weight, like q0:2. The weights are natural numbers, unless
+// a line `Semiring s` before the transitions chooses another semiring s, which is one of nat, int, real, max or min.
+// Lines starting with # are comments.
+//
+// State q becomes Add[o]{List[f]{@q1,...,@qn}:w,...} with one element per transition into q, where Add is replaced by
+// Int, Real, Max or Min for the other semirings. The tag o is unnamed if q is not final, and named `final` or like
+// `final=2` after its final weight otherwise, and the tag f of a List node is named after its symbol.
+// Forward refinement of this coalgebra computes the backward bisimulation of the tree automaton in the sense of
+// Högberg, Maletti and May, where states are equivalent if they have the same final weight, and for every symbol
+// and blocks B1, ..., Bn, the total weight of the transitions f(q1,...,qn) -> q with each qi in Bi is the same.
+// Backward refinement (--direction backward) computes their forward bisimulation instead, where states are equivalent
+// if they have the same final weight, and occur with the same total weight at the same position of a symbol whose
+// other arguments and target are in the same blocks.
+
+use std::io::{BufRead, BufReader};
+use std::{fs::File, path::Path};
+
+use crate::binrep::{unzigzag, zigzag, CReader, CWriter, Node, ADD_TYP, INT_TYP, LIST_TYP, MAX_TYP, MIN_TYP, REAL_TYP};
+use crate::hmap::HMap;
+use crate::prism::{parse, Tags};
+
+#[derive(Clone, Copy, PartialEq)]
+enum Section { None, Ops, States, Final, Transitions }
+
+/// The node type of the semiring with this name
+fn semiring_typ(name: &str) -> u8 {
+    match name {
+        "nat" => ADD_TYP,
+        "int" => INT_TYP,
+        "real" => REAL_TYP,
+        "max" => MAX_TYP,
+        "min" => MIN_TYP,
+        _ => panic!("Unknown semiring {}, expected nat, int, real, max or min.", name),
+    }
+}
+
+/// Parse a weight in the semiring of node type typ
+fn parse_weight(typ: u8, s: &str, line: &str) -> u64 {
+    match typ {
+        REAL_TYP => parse::<f64>(Some(s), "a weight", line).to_bits(),
+        INT_TYP => zigzag(parse(Some(s), "a weight", line)),
+        _ => parse(Some(s), "a weight", line),
+    }
+}
+
+pub fn read_timbuk<P>(filename: P) -> (Vec<u8>,CReader)
+where P: AsRef<Path>, {
+    let filename = filename.as_ref();
+    if filename.extension().is_none_or(|ext| ext != "timbuk") {
+        panic!("File must be *.timbuk, but is {}", filename.display());
+    }
+    let file = File::open(filename).
+        expect(&format!("Couldn't open file {:?}", filename.display().to_string()));
+    let mut typ = ADD_TYP;
+    let mut tags = Tags::default();
+    tags.tag(None);
+    let mut arities: HMap<String, usize> = HMap::default();
+    let mut ids: HMap<String, u32> = HMap::default();
+    let mut names: Vec<String> = vec![];
+    let mut finals: HMap<u32, String> = HMap::default();
+    let mut trans: Vec<Vec<(Node, u64)>> = vec![];
+    let mut automata = 0;
+    let mut section = Section::None;
+
+    for line in BufReader::new(file).lines() {
+        let line = line.expect("Failure while reading file.");
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') { continue }
+        let mut rest = line;
+        for (keyword, s) in [("Ops", Section::Ops), ("States", Section::States), ("Final States", Section::Final), ("Transitions", Section::Transitions)] {
+            if let Some(r) = line.strip_prefix(keyword).filter(|r| r.is_empty() || r.starts_with(char::is_whitespace)) {
+                section = s;
+                rest = r;
+            }
+        }
+        if let Some(r) = line.strip_prefix("Automaton").filter(|r| r.is_empty() || r.starts_with(char::is_whitespace)) {
+            automata += 1;
+            if automata > 1 { panic!("Only one automaton per file is supported, in line {:?}.", line) }
+            section = Section::None;
+            rest = r;
+            // the name of the automaton isn't needed
+            rest = rest.trim_start().split_once(char::is_whitespace).map_or("", |(_, r)| r);
+        }
+        if let Some(r) = line.strip_prefix("Semiring ") {
+            if !trans.is_empty() { panic!("The semiring must be given before the transitions, in line {:?}.", line) }
+            typ = semiring_typ(r.trim());
+            continue;
+        }
+        match section {
+            Section::None => if !rest.trim().is_empty() { panic!("Unexpected line {:?}.", line) },
+            Section::Ops => for op in rest.split_whitespace() {
+                let (name, arity) = op.split_once(':').unwrap_or_else(|| panic!("Expected symbol:arity in line {:?}.", line));
+                arities.insert(name.to_string(), parse(Some(arity), "an arity", line));
+            },
+            Section::States => for state in rest.split_whitespace() {
+                // states can have a sort, like q0:0
+                let name = state.split(':').next().unwrap();
+                ids.entry(name.to_string()).or_insert_with(|| {
+                    names.push(name.to_string());
+                    trans.push(vec![]);
+                    names.len() as u32 - 1
+                });
+            },
+            Section::Final => for state in rest.split_whitespace() {
+                let (name, weight) = state.split_once(':').unwrap_or((state, "1"));
+                let q = *ids.get(name).unwrap_or_else(|| panic!("Unknown state {} in line {:?}.", name, line));
+                let weight = parse_weight(typ, weight, line);
+                let name = if weight == parse_weight(typ, "1", line) { "final".to_string() } else {
+                    format!("final={}", weight_name(typ, weight))
+                };
+                finals.insert(q, name);
+            },
+            Section::Transitions => {
+                if rest.trim().is_empty() { continue }
+                let (lhs, rhs) = rest.split_once("->").unwrap_or_else(|| panic!("Expected -> in line {:?}.", line));
+                let (target, weight) = rhs.split_once(':').unwrap_or((rhs, "1"));
+                let state = |name: &str| *ids.get(name.trim()).unwrap_or_else(|| panic!("Unknown state {} in line {:?}.", name.trim(), line));
+                let (symbol, args) = match lhs.split_once('(') {
+                    Some((symbol, args)) => {
+                        let args = args.trim_end().strip_suffix(')').unwrap_or_else(|| panic!("Expected ) in line {:?}.", line));
+                        let args: Vec<Node> = if args.trim().is_empty() { vec![] } else { args.split(',').map(|a| Node::State(state(a))).collect() };
+                        (symbol.trim(), args)
+                    }
+                    None => (lhs.trim(), vec![]),
+                };
+                match arities.get(symbol) {
+                    Some(&arity) if arity == args.len() => {}
+                    Some(&arity) => panic!("Symbol {} has arity {}, but {} arguments in line {:?}.", symbol, arity, args.len(), line),
+                    None => panic!("Unknown symbol {} in line {:?}.", symbol, line),
+                }
+                let tag = tags.tag(Some(symbol.to_string()));
+                let weight = parse_weight(typ, weight.trim(), line);
+                trans[state(target) as usize].push((Node::Coll(LIST_TYP, tag, args), weight));
+            }
+        }
+    }
+
+    let mut w = CWriter::new();
+    for (q, succs) in trans.into_iter().enumerate() {
+        let tag = tags.tag(finals.remove(&(q as u32)));
+        Node::Mon(typ, tag, succs).write(&mut w);
+    }
+    w.meta.tag_names = tags.names;
+    w.meta.state_names = names.into_iter().map(Some).collect();
+    w.finish()
+}
+
+fn weight_name(typ: u8, weight: u64) -> String {
+    match typ {
+        REAL_TYP => f64::from_bits(weight).to_string(),
+        INT_TYP => unzigzag(weight).to_string(),
+        _ => weight.to_string(),
+    }
+}
+
+#[test]
+fn test_read_timbuk() {
+    use crate::{binrep::read_nodes, naivealg::partref_naive, optalg::partref_nlogn, renumber};
+    // name, partition
+    let cases: [(&str, Vec<u32>); 2] = [
+        ("tests/wta_test.timbuk", vec![0,1,1,2,0]),
+        ("tests/wta_max.timbuk", vec![0,0,1,1]),
+    ];
+    for (filename, expected) in cases {
+        let (data, r) = read_timbuk(filename);
+        let ids1 = partref_naive(&data, &r);
+        let ids2 = partref_nlogn(data, r);
+        assert_eq!(&renumber(&ids1), &ids2);
+        assert_eq!(ids2, expected, "{}", filename);
+    }
+
+    let (data, r) = read_timbuk("tests/wta_test.timbuk");
+    assert_eq!(r.meta.state_name(4), Some("q4"));
+    let names: Vec<&String> = r.meta.tag_names.values().collect();
+    assert_eq!(names, vec!["a", "b", "f", "final", "final=2"]);
+    let expected = ["Add[4]{List[3]{@1,@2}:1,List[3]{@2,@1}:1,List[3]{@0,@0}:3}", "Add[0]{List[1]{}:2,List[2]{}:1}"];
+    let expected: Vec<Node> = expected.iter().map(|s| Node::from_ascii(s.as_bytes())).collect();
+    assert_eq!(read_nodes(&data, &r)[0..2], expected[..]);
+
+    let (data, r) = read_timbuk("tests/wta_max.timbuk");
+    assert!(matches!(&read_nodes(&data, &r)[2], Node::Mon(MAX_TYP, _, _)));
+}
+
+#[test]
+fn test_timbuk_backward() {
+    use crate::backward::partref_backward;
+    // name, partition
+    let cases: [(&str, Vec<u32>); 2] = [
+        ("tests/wta_test.timbuk", vec![0,1,2,3,4]),
+        ("tests/wta_backward.timbuk", vec![0,0,1,2]),
+    ];
+    for (filename, expected) in cases {
+        let (data, r) = read_timbuk(filename);
+        assert_eq!(partref_backward(&data, &r).unwrap(), expected, "{}", filename);
+    }
+}
@@ -1,5 +1,5 @@
 pub fn mb(num_bytes: usize) -> String {
-  let bytes_in_mb = ((1 as usize) << 20) as f64;
+  let bytes_in_mb = (1_usize << 20) as f64;
   let num_mb = num_bytes as f64 / bytes_in_mb;
   format!("{:.2}", num_mb)
 }
\ No newline at end of file